@@ -0,0 +1,330 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const NONCE_LEN: usize = 32;
+pub const PROOF_LEN: usize = 32;
+pub const SALT_LEN: usize = 16;
+
+/// PBKDF2 rounds for newly set passwords
+pub const PASSWORD_ITERATIONS: u32 = 100_000;
+/// Range of rounds a client agrees to compute for a server's challenge
+pub const MIN_ITERATIONS: u32 = 10_000;
+pub const MAX_ITERATIONS: u32 = 10_000_000;
+
+/// Generate a fresh random nonce for one side of the handshake
+pub fn generate_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Salted password derived with PBKDF2. Only the client ever holds it; the
+/// keys below are derived from it.
+pub fn password_key(password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut key);
+    key
+}
+
+/// Key the client proves possession of at every login
+pub fn client_key(password_key: &[u8; 32]) -> [u8; 32] {
+    hmac(password_key, b"netbackup-client-key", &[])
+}
+
+/// Key the server proves possession of back to the client
+pub fn server_key(password_key: &[u8; 32]) -> [u8; 32] {
+    hmac(password_key, b"netbackup-server-key", &[])
+}
+
+/// What the server keeps to check a client key: its hash, which is useless
+/// for logging in
+pub fn stored_key(client_key: &[u8; 32]) -> [u8; 32] {
+    Sha256::digest(client_key).into()
+}
+
+/// What the server keeps to check one password, SCRAM-style: neither key
+/// can be turned into a proof, so reading the user database does not let
+/// anyone log in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub salt: [u8; SALT_LEN],
+    pub iterations: u32,
+    pub stored_key: [u8; 32],
+    pub server_key: [u8; 32],
+}
+
+impl Credentials {
+    /// Derive credentials for `password` under a fresh random salt
+    pub fn new(password: &str, iterations: u32) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self::derive(salt, iterations, &password_key(password, &salt, iterations))
+    }
+
+    /// Credentials for an already salted password
+    pub fn derive(salt: [u8; SALT_LEN], iterations: u32, password_key: &[u8; 32]) -> Self {
+        Self {
+            salt,
+            iterations,
+            stored_key: stored_key(&client_key(password_key)),
+            server_key: server_key(password_key),
+        }
+    }
+}
+
+/// Salt handed out for a username that does not exist, so a challenge does
+/// not reveal which accounts do. Stable per server run and username.
+pub fn decoy_salt(secret: &[u8; 32], username: &str) -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    salt.copy_from_slice(
+        &hmac(secret, b"netbackup-decoy-salt", &[username.as_bytes()])[..SALT_LEN],
+    );
+    salt
+}
+
+/// Digest of the hello exchange: the client's offer and the server's answer.
+/// It is bound into both proofs and the session key, so a version or
+/// capability set altered in transit makes the handshake fail.
+pub fn hello_transcript(request: &[u8], response: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"netbackup-hello");
+    for part in [request, response] {
+        hasher.update((part.len() as u32).to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn hmac(key: &[u8], label: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(label);
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn hmac_verify(key: &[u8], label: &[u8], parts: &[&[u8]], expected: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(label);
+    for part in parts {
+        mac.update(part);
+    }
+    mac.verify_slice(expected).is_ok()
+}
+
+/// Everything one login is bound to: the user, both nonces and the hello
+/// exchange. Proofs and the session key are all computed over it.
+pub fn auth_message(
+    username: &str,
+    server_nonce: &[u8],
+    client_nonce: &[u8],
+    transcript: &[u8; 32],
+) -> Vec<u8> {
+    let mut message = Vec::with_capacity(4 + username.len() + 2 * NONCE_LEN + 32);
+    message.extend_from_slice(&(username.len() as u32).to_be_bytes());
+    message.extend_from_slice(username.as_bytes());
+    message.extend_from_slice(server_nonce);
+    message.extend_from_slice(client_nonce);
+    message.extend_from_slice(transcript);
+    message
+}
+
+fn xor(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// Proof sent by the client: its client key masked with a signature only
+/// the holder of the stored key can compute for this login
+pub fn client_proof(client_key: &[u8; 32], auth_message: &[u8]) -> [u8; 32] {
+    let signature = hmac(
+        &stored_key(client_key),
+        b"netbackup-client-proof",
+        &[auth_message],
+    );
+    xor(client_key, &signature)
+}
+
+/// Unmask the client key from a proof and check it against the stored key.
+/// Returns the client key, which the session key is derived from.
+pub fn verify_client_proof(
+    stored: &[u8; 32],
+    auth_message: &[u8],
+    proof: &[u8],
+) -> Option<[u8; 32]> {
+    let proof: &[u8; 32] = proof.try_into().ok()?;
+    let signature = hmac(stored, b"netbackup-client-proof", &[auth_message]);
+    let client_key = xor(proof, &signature);
+    // Compare every byte so the time taken does not depend on how many match
+    let mismatch = stored_key(&client_key)
+        .iter()
+        .zip(stored)
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    (mismatch == 0).then_some(client_key)
+}
+
+/// Proof sent back by the server so the client knows it is talking to a
+/// server that holds the credentials for this password
+pub fn server_proof(server_key: &[u8; 32], auth_message: &[u8]) -> [u8; 32] {
+    hmac(server_key, b"netbackup-server-proof", &[auth_message])
+}
+
+pub fn verify_server_proof(server_key: &[u8; 32], auth_message: &[u8], proof: &[u8]) -> bool {
+    hmac_verify(
+        server_key,
+        b"netbackup-server-proof",
+        &[auth_message],
+        proof,
+    )
+}
+
+/// Per-connection key used to MAC every message after the handshake. It
+/// comes from the client key, which the server only learns from the proof,
+/// so the stored credentials alone cannot produce it.
+pub fn derive_session_key(client_key: &[u8; 32], auth_message: &[u8]) -> [u8; 32] {
+    hmac(client_key, b"netbackup-session", &[auth_message])
+}
+
+/// Which way a message travels. Requests and responses are MAC'd under
+/// different labels, so a signed request cannot be reflected back to the
+/// client as a response.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+impl Direction {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Direction::Request => b"netbackup-request",
+            Direction::Response => b"netbackup-response",
+        }
+    }
+}
+
+/// MAC over the message header fields. The payload is covered through its checksum.
+pub fn message_mac(
+    session_key: &[u8; 32],
+    direction: Direction,
+    request_id: u32,
+    operation: u8,
+    status: u8,
+    checksum: &[u8; 32],
+) -> [u8; 32] {
+    hmac(
+        session_key,
+        direction.label(),
+        &[&request_id.to_be_bytes(), &[operation, status], checksum],
+    )
+}
+
+pub fn verify_message_mac(
+    session_key: &[u8; 32],
+    direction: Direction,
+    request_id: u32,
+    operation: u8,
+    status: u8,
+    checksum: &[u8; 32],
+    mac: &[u8],
+) -> bool {
+    hmac_verify(
+        session_key,
+        direction.label(),
+        &[&request_id.to_be_bytes(), &[operation, status], checksum],
+        mac,
+    )
+}
+
+/// Encode the client's second handshake message
+/// Format: [client_nonce: 32][proof: 32]
+pub fn encode_auth_response(client_nonce: &[u8; NONCE_LEN], proof: &[u8; PROOF_LEN]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(NONCE_LEN + PROOF_LEN);
+    payload.extend_from_slice(client_nonce);
+    payload.extend_from_slice(proof);
+    payload
+}
+
+pub fn decode_auth_response(payload: &[u8]) -> Option<([u8; NONCE_LEN], [u8; PROOF_LEN])> {
+    if payload.len() != NONCE_LEN + PROOF_LEN {
+        return None;
+    }
+    let mut client_nonce = [0u8; NONCE_LEN];
+    let mut proof = [0u8; PROOF_LEN];
+    client_nonce.copy_from_slice(&payload[..NONCE_LEN]);
+    proof.copy_from_slice(&payload[NONCE_LEN..]);
+    Some((client_nonce, proof))
+}
+
+/// Server's answer to a challenge request: its nonce plus what the client
+/// needs to derive the password key
+/// Format: [server_nonce: 32][salt: 16][iterations: u32]
+#[derive(Debug, PartialEq)]
+pub struct Challenge {
+    pub server_nonce: [u8; NONCE_LEN],
+    pub salt: [u8; SALT_LEN],
+    pub iterations: u32,
+}
+
+impl Challenge {
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(NONCE_LEN + SALT_LEN + 4);
+        payload.extend_from_slice(&self.server_nonce);
+        payload.extend_from_slice(&self.salt);
+        payload.extend_from_slice(&self.iterations.to_be_bytes());
+        payload
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        if payload.len() != NONCE_LEN + SALT_LEN + 4 {
+            return None;
+        }
+        let mut server_nonce = [0u8; NONCE_LEN];
+        let mut salt = [0u8; SALT_LEN];
+        server_nonce.copy_from_slice(&payload[..NONCE_LEN]);
+        salt.copy_from_slice(&payload[NONCE_LEN..NONCE_LEN + SALT_LEN]);
+        let iterations = u32::from_be_bytes(payload[NONCE_LEN + SALT_LEN..].try_into().ok()?);
+        Some(Self {
+            server_nonce,
+            salt,
+            iterations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stored_credentials_cannot_log_in() {
+        let salt = [3u8; SALT_LEN];
+        let key = password_key("hunter2", &salt, MIN_ITERATIONS);
+        let credentials = Credentials::derive(salt, MIN_ITERATIONS, &key);
+        let message = auth_message("alice", &generate_nonce(), &generate_nonce(), &[0; 32]);
+
+        // The real client's proof checks out and both sides agree on the session key
+        let client_key = client_key(&key);
+        let proof = client_proof(&client_key, &message);
+        let recovered = verify_client_proof(&credentials.stored_key, &message, &proof).unwrap();
+        assert_eq!(
+            derive_session_key(&recovered, &message),
+            derive_session_key(&client_key, &message)
+        );
+        let server = server_proof(&credentials.server_key, &message);
+        assert!(verify_server_proof(&server_key(&key), &message, &server));
+
+        // Someone holding only what the server stores cannot produce a proof
+        for stolen in [credentials.stored_key, credentials.server_key] {
+            let forged = client_proof(&stolen, &message);
+            assert!(verify_client_proof(&credentials.stored_key, &message, &forged).is_none());
+        }
+        // A proof is tied to its login
+        let other = auth_message("alice", &generate_nonce(), &generate_nonce(), &[0; 32]);
+        assert!(verify_client_proof(&credentials.stored_key, &other, &proof).is_none());
+        assert!(verify_client_proof(&credentials.stored_key, &message, &proof[..31]).is_none());
+    }
+}
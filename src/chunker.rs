@@ -0,0 +1,215 @@
+use std::io::{self, Read};
+
+/// Largest chunk a content-defined layout may use; bounds the size of a
+/// single `StoreChunk` message
+pub const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Smallest `min_size` accepted, so a layout cannot degenerate into
+/// millions of tiny chunks
+pub const MIN_CHUNK_SIZE: usize = 256;
+
+/// Size bounds for content-defined chunking. Cut points are chosen by a
+/// rolling hash over the data (FastCDC), so an insertion only changes the
+/// chunks around it instead of shifting every later boundary.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkerConfig {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 16 * 1024,
+            avg_size: 64 * 1024,
+            max_size: 256 * 1024,
+        }
+    }
+}
+
+impl ChunkerConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_size < MIN_CHUNK_SIZE || self.max_size > MAX_CHUNK_SIZE {
+            return Err(format!(
+                "Chunk sizes must be between {} and {} bytes",
+                MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+            ));
+        }
+        if !(self.min_size <= self.avg_size && self.avg_size <= self.max_size) {
+            return Err("Chunk sizes must satisfy min <= avg <= max".to_string());
+        }
+        Ok(())
+    }
+
+    /// Length of the first chunk of `data`. `data` must hold at least
+    /// `max_size` bytes unless it is the end of the stream.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+        let limit = data.len().min(self.max_size);
+        let normal = self.avg_size.min(limit);
+
+        // Normalised chunking: a stricter mask before the average size and a
+        // looser one after it pulls chunk sizes towards the average
+        let bits = self.avg_size.max(2).ilog2();
+        let strict = top_bits(bits + 1);
+        let loose = top_bits(bits.saturating_sub(1).max(1));
+
+        let mut hash = 0u64;
+        for (i, &byte) in data.iter().enumerate().take(limit).skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            let mask = if i < normal { strict } else { loose };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        limit
+    }
+
+    /// Split a stream into content-defined chunks
+    pub fn chunks<R: Read>(&self, reader: R) -> Chunks<R> {
+        Chunks {
+            config: *self,
+            reader,
+            buf: Vec::with_capacity(self.max_size),
+            eof: false,
+        }
+    }
+}
+
+/// Iterator over the chunks of a stream, see `ChunkerConfig::chunks`
+pub struct Chunks<R> {
+    config: ChunkerConfig,
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Iterator for Chunks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Keep a full window buffered so the cut point does not depend on
+        // how the reader happens to split its reads
+        while !self.eof && self.buf.len() < self.config.max_size {
+            let wanted = (self.config.max_size - self.buf.len()) as u64;
+            match (&mut self.reader).take(wanted).read_to_end(&mut self.buf) {
+                Ok(0) => self.eof = true,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        if self.buf.is_empty() {
+            return None;
+        }
+        let len = self.config.cut(&self.buf);
+        let rest = self.buf.split_off(len);
+        Some(Ok(std::mem::replace(&mut self.buf, rest)))
+    }
+}
+
+/// Mask of the `n` most significant bits; those depend on the most recent
+/// bytes fed into the gear hash
+fn top_bits(n: u32) -> u64 {
+    !0u64 << (64 - n.min(63))
+}
+
+/// Random values for the gear hash, fixed so every client cuts the same
+/// data at the same places
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x6e65_7462_6163_6b75u64; // splitmix64
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use sha2::{Digest, Sha256};
+    use std::collections::HashSet;
+
+    fn pseudo_random(len: usize) -> Vec<u8> {
+        test_util::pseudo_random(len, 0x2545_f491_4f6c_dd1d)
+    }
+
+    fn hashes(config: &ChunkerConfig, data: &[u8]) -> Vec<[u8; 32]> {
+        config
+            .chunks(data)
+            .map(|chunk| Sha256::digest(chunk.unwrap()).into())
+            .collect()
+    }
+
+    #[test]
+    fn test_chunks_respect_bounds_and_reassemble() {
+        let config = ChunkerConfig::default();
+        let data = pseudo_random(2 * 1024 * 1024 + 123);
+        let chunks: Vec<Vec<u8>> = config.chunks(&data[..]).map(|c| c.unwrap()).collect();
+
+        assert_eq!(chunks.concat(), data);
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(last.len() <= config.max_size);
+        for chunk in rest {
+            assert!(chunk.len() >= config.min_size && chunk.len() <= config.max_size);
+        }
+        // Sizes cluster around the average rather than the bounds
+        let mean = data.len() / chunks.len();
+        assert!(mean > config.avg_size / 2 && mean < config.avg_size * 2);
+    }
+
+    #[test]
+    fn test_insertion_near_start_reuses_chunks() {
+        let config = ChunkerConfig::default();
+        let original = pseudo_random(4 * 1024 * 1024);
+        let mut edited = original.clone();
+        edited.insert(100, 0x42);
+
+        let before: HashSet<_> = hashes(&config, &original).into_iter().collect();
+        let after = hashes(&config, &edited);
+        let reused = after.iter().filter(|h| before.contains(*h)).count();
+        assert!(
+            reused + 2 >= after.len(),
+            "only {} of {} chunks reused",
+            reused,
+            after.len()
+        );
+
+        // Fixed-size chunks all shift and nothing is reused
+        let fixed = |data: &[u8]| -> HashSet<[u8; 32]> {
+            data.chunks(64 * 1024)
+                .map(|c| Sha256::digest(c).into())
+                .collect()
+        };
+        assert_eq!(fixed(&original).intersection(&fixed(&edited)).count(), 0);
+    }
+
+    #[test]
+    fn test_config_validation() {
+        assert!(ChunkerConfig::default().validate().is_ok());
+        let bad = ChunkerConfig {
+            min_size: 64 * 1024,
+            avg_size: 32 * 1024,
+            max_size: 128 * 1024,
+        };
+        assert!(bad.validate().is_err());
+        let too_big = ChunkerConfig {
+            max_size: MAX_CHUNK_SIZE + 1,
+            ..ChunkerConfig::default()
+        };
+        assert!(too_big.validate().is_err());
+    }
+}
@@ -1,286 +1,1904 @@
+use crate::auth;
+use crate::chunker::ChunkerConfig;
+use crate::crypto::{self, Crypto};
+use crate::delta::{self, SignatureIndex};
+use crate::failure::{classify, Failure, FailureKind};
+use crate::hex;
+use crate::ignore::{filter_matches, IgnoreRules};
 use crate::protocol::{
-    generate_auth_token, ChunkDownloadRequest, ChunkDownloadResponse, ChunkMetadata, Message,
-    Operation, StatusCode, CHUNK_SIZE,
+    capability, frame_length, ChunkDownloadRequest, ChunkDownloadResponse, ChunkHashesRequest,
+    ChunkMetadata, DeltaChunk, HaveChunksRequest, Hello, ListRequest, Message, MoveRequest,
+    Operation, SignaturesRequest, SignaturesResponse, StatusCode, StoreCompleteRequest,
+    UploadBeginRequest, UploadBeginResponse, CHUNK_SIZE, MAX_FRAME_LEN, MAX_LIST_PAGE,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
 };
-use indicatif::ProgressBar;
+use crate::storage::{
+    normalize_path, EntryType, FileMetadata, FileStat, Listing, SortKey, VersionInfo,
+};
+use crate::tls::{ClientTls, Transport};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::error::Error;
 use std::fs;
 use std::fs::OpenOptions;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+/// Which entries `list` shows and how
+#[derive(Debug, Clone, Default)]
+pub struct ListView {
+    /// Glob or prefix the entries' paths must match
+    pub filter: Option<String>,
+    pub sort: SortKey,
+    /// Show size, modification time and checksum columns with totals
+    pub long: bool,
+    /// Show at most this many entries
+    pub limit: Option<usize>,
+}
+
+/// How commands report what they did
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Output {
+    /// Progress bars and human-readable messages
+    #[default]
+    Text,
+    /// One JSON document on stdout per command, and no progress output
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            _ => Err(format!(
+                "Unknown output format '{}': expected text or json",
+                s
+            )),
+        }
+    }
+}
+
+/// Chunk hashes offered to the server per `HaveChunks` request
+const HASH_BATCH: usize = 1024;
+
+/// Encoded size of one `BlockSignature`: a weak and a strong hash
+const BLOCK_SIGNATURE_LEN: u64 = 4 + 32;
+
+/// Where and how to reach the server
+pub struct ConnectOptions {
+    pub server_addr: String,
+    /// Account to log in as; ignored by servers without a user database
+    pub username: String,
+    pub password: String,
+    pub tls: Option<ClientTls>,
+    /// Cut uploads at content-defined boundaries instead of every `CHUNK_SIZE` bytes
+    pub chunking: Option<ChunkerConfig>,
+    /// Encrypt contents and names before they leave this host
+    pub crypto: Option<Crypto>,
+    pub output: Output,
+}
+
 pub struct Client {
-    stream: TcpStream,
-    auth_token: [u8; 32],
+    stream: Box<dyn Transport>,
+    session_key: Option<[u8; 32]>,
     request_id: u32,
+    /// Capabilities agreed with the server in the hello exchange
+    capabilities: u32,
+    /// Digest of that exchange, bound into the auth handshake
+    hello_transcript: [u8; 32],
+    /// Set once a send or receive failed; the stream may be dead or out of
+    /// step with the server, so no further requests are made on it
+    broken: bool,
+    chunking: Option<ChunkerConfig>,
+    crypto: Option<Crypto>,
+    output: Output,
 }
 
 impl Client {
-    async fn connect(server_addr: &str, password: &str) -> Result<Self, Box<dyn Error>> {
-        let stream = TcpStream::connect(server_addr).await?;
-        let auth_token = generate_auth_token(password); // Use parameter
+    async fn connect(options: &ConnectOptions) -> Result<Self, Box<dyn Error>> {
+        let network = |e: std::io::Error| {
+            Failure::new(
+                FailureKind::Network,
+                format!("Cannot connect to {}: {}", options.server_addr, e),
+            )
+        };
+        let tcp = TcpStream::connect(&options.server_addr)
+            .await
+            .map_err(network)?;
+        let stream: Box<dyn Transport> = match &options.tls {
+            Some(tls) => Box::new(
+                tls.connector
+                    .connect(tls.server_name.clone(), tcp)
+                    .await
+                    .map_err(network)?,
+            ),
+            None => Box::new(tcp),
+        };
 
         let mut client = Self {
             stream,
-            auth_token,
+            session_key: None,
             request_id: 1,
+            capabilities: 0,
+            hello_transcript: [0; 32],
+            broken: false,
+            chunking: options.chunking,
+            crypto: options.crypto.clone(),
+            output: options.output,
         };
 
-        client.authenticate().await?;
+        client.hello().await?;
+        client
+            .authenticate(&options.username, &options.password)
+            .await?;
         Ok(client)
     }
 
+    /// Build the next request, signing it once a session key is established
+    fn new_request(&mut self, operation: Operation, payload: Vec<u8>) -> Message {
+        let mut msg = Message::new(operation, payload);
+        msg.set_request_id(self.request_id);
+        self.request_id += 1;
+        if let Some(key) = &self.session_key {
+            msg.sign(key, auth::Direction::Request);
+        }
+        msg
+    }
+
+    async fn request(
+        &mut self,
+        operation: Operation,
+        payload: Vec<u8>,
+    ) -> Result<Message, Box<dyn Error>> {
+        let msg = self.new_request(operation, payload);
+        self.send_message(&msg).await?;
+        let response = self.receive_message().await?;
+        // A validly signed answer to some other request is a replay
+        if response.request_id != msg.request_id || response.operation != operation {
+            self.broken = true;
+            return Err(Failure::new(
+                FailureKind::Integrity,
+                format!(
+                    "Response to {:?} #{} does not answer {:?} #{}",
+                    response.operation, response.request_id, operation, msg.request_id
+                ),
+            )
+            .into());
+        }
+        Ok(response)
+    }
+
     async fn send_message(&mut self, message: &Message) -> Result<(), Box<dyn Error>> {
+        if self.broken {
+            return Err(
+                Failure::new(FailureKind::Network, "Connection to the server was lost").into(),
+            );
+        }
         let bytes = message.to_bytes();
-        self.stream.write_all(&bytes).await?;
+        if bytes.len() - 4 > MAX_FRAME_LEN as usize {
+            return Err(format!(
+                "{:?} request of {} bytes exceeds the {} byte frame limit",
+                message.operation,
+                bytes.len() - 4,
+                MAX_FRAME_LEN
+            )
+            .into());
+        }
+        if let Err(e) = self.stream.write_all(&bytes).await {
+            self.broken = true;
+            return Err(e.into());
+        }
         Ok(())
     }
 
     async fn receive_message(&mut self) -> Result<Message, Box<dyn Error>> {
+        let result = self.read_message().await;
+        if result.is_err() {
+            self.broken = true;
+        }
+        result
+    }
+
+    async fn read_message(&mut self) -> Result<Message, Box<dyn Error>> {
         let mut len_bytes = [0u8; 4];
         self.stream.read_exact(&mut len_bytes).await?;
-        let length = u32::from_be_bytes(len_bytes);
+        let length = frame_length(len_bytes)
+            .map_err(|e| Failure::new(FailureKind::Integrity, e.to_string()))?;
 
         let mut data = vec![0u8; length as usize];
         self.stream.read_exact(&mut data).await?;
 
-        Ok(Message::from_bytes(length, &data)?)
+        let message = Message::from_bytes(length, &data)?;
+        if let Some(key) = &self.session_key {
+            if !message.verify_mac(key, auth::Direction::Response) {
+                return Err(
+                    Failure::new(FailureKind::Integrity, "Response failed authentication").into(),
+                );
+            }
+        }
+        Ok(message)
     }
 
-    async fn authenticate(&mut self) -> Result<(), Box<dyn Error>> {
-        let mut auth_msg = Message::new_with_auth(Operation::Auth, Vec::new(), self.auth_token);
-        auth_msg.set_request_id(self.request_id);
-        self.request_id += 1;
+    /// Announce our protocol version and capabilities; the server answers with
+    /// the subset both sides support
+    async fn hello(&mut self) -> Result<(), Box<dyn Error>> {
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: capability::SUPPORTED,
+        };
+        let offer = hello.to_payload();
+        let response = self.request(Operation::Hello, offer.clone()).await?;
+        if response.status != StatusCode::Success {
+            return Err(refused("Server rejected protocol handshake", &response));
+        }
 
-        self.send_message(&auth_msg).await?;
-        let response = self.receive_message().await?;
+        let server = Hello::from_payload(&response.payload)?;
+        if server.version < MIN_PROTOCOL_VERSION {
+            return Err(format!(
+                "Server speaks protocol version {}, this client needs at least {}",
+                server.version, MIN_PROTOCOL_VERSION
+            )
+            .into());
+        }
+        self.capabilities = server.capabilities & capability::SUPPORTED;
+        self.hello_transcript = auth::hello_transcript(&offer, &response.payload);
+        Ok(())
+    }
+
+    /// Fail early when the server did not agree to a capability an operation needs
+    fn require(&self, capability_bit: u32) -> Result<(), Box<dyn Error>> {
+        if self.capabilities & capability_bit == capability_bit {
+            Ok(())
+        } else {
+            Err(format!(
+                "Server does not support {}",
+                capability::names(capability_bit).join(", ")
+            )
+            .into())
+        }
+    }
+
+    /// Name of a file or directory as the server sees it: encrypted
+    /// component by component when client-side encryption is on
+    fn remote_path(&self, path: &str) -> Result<String, Box<dyn Error>> {
+        match &self.crypto {
+            Some(crypto) => Ok(crypto.encrypt_path(&normalize_path(path)?)?),
+            None => Ok(path.to_string()),
+        }
+    }
+
+    /// Challenge-response handshake: name the user and get a server nonce
+    /// with that user's salt, answer with an HMAC proof over both nonces,
+    /// then check the server's proof in return.
+    async fn authenticate(&mut self, username: &str, password: &str) -> Result<(), Box<dyn Error>> {
+        let response = self
+            .request(Operation::Auth, username.as_bytes().to_vec())
+            .await?;
+        let challenge = match auth::Challenge::from_payload(&response.payload) {
+            Some(challenge) if response.status == StatusCode::Success => challenge,
+            _ => return Err(Failure::new(FailureKind::Auth, "Authentication failed").into()),
+        };
+        if !(auth::MIN_ITERATIONS..=auth::MAX_ITERATIONS).contains(&challenge.iterations) {
+            return Err(Failure::new(
+                FailureKind::Auth,
+                format!(
+                    "Server asked for {} password rounds, outside the accepted range",
+                    challenge.iterations
+                ),
+            )
+            .into());
+        }
+        let password_key = &auth::password_key(password, &challenge.salt, challenge.iterations);
+        let client_key = auth::client_key(password_key);
+        let client_nonce = auth::generate_nonce();
+        let auth_message = auth::auth_message(
+            username,
+            &challenge.server_nonce,
+            &client_nonce,
+            &self.hello_transcript,
+        );
+        let proof = auth::client_proof(&client_key, &auth_message);
+        let response = self
+            .request(
+                Operation::Auth,
+                auth::encode_auth_response(&client_nonce, &proof),
+            )
+            .await?;
 
         if response.status != StatusCode::Success {
-            return Err("Authentication failed".into());
+            return Err(Failure::new(
+                FailureKind::Auth,
+                format!(
+                    "Authentication failed: {}",
+                    String::from_utf8_lossy(&response.payload)
+                ),
+            )
+            .into());
+        }
+        if !auth::verify_server_proof(
+            &auth::server_key(password_key),
+            &auth_message,
+            &response.payload,
+        ) {
+            return Err(Failure::new(
+                FailureKind::Auth,
+                "Server failed to prove knowledge of the password",
+            )
+            .into());
         }
+
+        self.session_key = Some(auth::derive_session_key(&client_key, &auth_message));
         Ok(())
     }
 
+    /// Byte-based progress bar shared by single-file and tree transfers;
+    /// hidden when the output is JSON
+    fn transfer_bar(&self, total_bytes: u64, message: &str) -> ProgressBar {
+        if self.output == Output::Json {
+            return ProgressBar::hidden();
+        }
+        let pb = ProgressBar::new(total_bytes);
+        pb.set_style(
+            ProgressStyle::with_template("{msg:30!} [{bar:40}] {bytes}/{total_bytes} ({eta})")
+                .expect("progress template is valid")
+                .progress_chars("=> "),
+        );
+        pb.set_message(message.to_string());
+        pb
+    }
+
+    /// Print the blank line that follows a finished progress bar
+    fn end_bar(&self) {
+        if self.output == Output::Text {
+            println!();
+        }
+    }
+
+    /// Upload a single file with its own progress bar, returning its size
     async fn upload_file(
         &mut self,
         local_path: &str,
         remote_name: &str,
+    ) -> Result<u64, Box<dyn Error>> {
+        let size = fs::metadata(local_path)?.len();
+        let pb = self.transfer_bar(size, "Uploading");
+        match self.send_file(local_path, remote_name, &pb).await {
+            Ok(()) => {
+                pb.finish_with_message("Upload complete!");
+                self.end_bar();
+                Ok(size)
+            }
+            Err(e) => {
+                pb.abandon();
+                Err(e)
+            }
+        }
+    }
+
+    /// Upload a file through an upload session, advancing `pb` by bytes sent.
+    /// If the server still holds a partial session for the same contents, or
+    /// already stores some of the chunks, only the missing chunks are sent.
+    async fn send_file(
+        &mut self,
+        local_path: &str,
+        remote_name: &str,
+        pb: &ProgressBar,
     ) -> Result<(), Box<dyn Error>> {
-        let data = fs::read(local_path)?;
-        let total_size = data.len();
-        let total_chunks = (total_size + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        self.require(capability::RESUMABLE_UPLOADS)?;
+
+        let mut file = fs::File::open(local_path)?;
+        let file_size = file.metadata()?.len();
+        let (mut layout, file_checksum) = chunk_layout(&mut file, self.chunking.as_ref())?;
+        let remote = self.remote_path(remote_name)?;
+
+        // With encryption the server gets sealed chunks, so sizes and hashes
+        // below are those of the ciphertext
+        let sealer = match self.crypto.clone() {
+            Some(crypto) => Some(Sealer {
+                crypto,
+                path: normalize_path(remote_name)?,
+                size: file_size,
+                checksum: file_checksum,
+            }),
+            None => None,
+        };
+        let (total_size, checksum, chunk_sizes) = match &sealer {
+            Some(sealer) => sealer.seal_layout(&mut file, &mut layout)?,
+            None => (
+                file_size,
+                file_checksum,
+                match self.chunking {
+                    Some(_) => layout.iter().map(|chunk| chunk.len).collect(),
+                    None => Vec::new(),
+                },
+            ),
+        };
+        let total_chunks = layout.len() as u32;
+
+        let begin = UploadBeginRequest {
+            filename: remote.clone(),
+            total_size,
+            total_chunks,
+            checksum,
+            chunk_sizes,
+        };
+        let response = self
+            .request(Operation::UploadBegin, begin.to_payload())
+            .await?;
+        if response.status != StatusCode::Success {
+            return Err(refused("Upload failed", &response));
+        }
+        let session = UploadBeginResponse::from_payload(&response.payload)?;
+        let mut missing = missing_chunk_numbers(session.missing_chunks, total_chunks)?;
+
+        let already_sent = total_chunks as usize - missing.len();
+        if already_sent > 0 {
+            pb.println(format!(
+                "Resuming upload of '{}': {} of {} chunks already on server",
+                remote_name, already_sent, total_chunks
+            ));
+        }
+        if !missing.is_empty() && self.capabilities & capability::DEDUP != 0 {
+            let before = missing.len();
+            let hashes: Vec<[u8; 32]> = layout.iter().map(|chunk| chunk.hash).collect();
+            missing = self.offer_chunks(&session.upload_id, &hashes).await?;
+            let reused = before - missing.len();
+            if reused > 0 {
+                pb.println(format!(
+                    "'{}': {} of {} chunks already stored on server",
+                    remote_name, reused, total_chunks
+                ));
+            }
+        }
+        let missing_bytes: u64 = missing.iter().map(|&n| layout[n as usize].len as u64).sum();
+        pb.inc(file_size - missing_bytes);
 
-        let pb = ProgressBar::new(total_chunks as u64);
-        pb.set_message("Uploading");
+        // Chunks of a file that already exists go over as deltas against it.
+        // Sealed chunks share nothing with their previous version but what
+        // dedup already found, so encrypted uploads skip this.
+        let mut base = None;
+        if !missing.is_empty() && sealer.is_none() && self.capabilities & capability::DELTA != 0 {
+            base = self.fetch_signatures(&remote, total_size).await?;
+        }
+        let mut delta_chunks = 0;
+        let mut delta_bytes = 0;
 
-        for chunk_num in 0..total_chunks {
-            let start = chunk_num * CHUNK_SIZE;
-            let end = std::cmp::min(start + CHUNK_SIZE, total_size);
-            let chunk_data = data[start..end].to_vec();
+        // Send what is missing, then ask the server what it still lacks. A
+        // well-behaved server reports nothing on the second pass.
+        for _ in 0..3 {
+            if missing.is_empty() {
+                break;
+            }
+            for chunk_num in missing {
+                let chunk = layout
+                    .get(chunk_num as usize)
+                    .ok_or("Server asked for a chunk beyond the end of the file")?;
+                let mut chunk_data = vec![0u8; chunk.len as usize];
+                file.seek(SeekFrom::Start(chunk.offset))?;
+                file.read_exact(&mut chunk_data)?;
+                if let Some(sealer) = &sealer {
+                    chunk_data = sealer.seal(&chunk_data, chunk_num as usize, layout.len());
+                }
+
+                if let Some(literal) = self
+                    .send_delta(
+                        &session.upload_id,
+                        chunk_num,
+                        total_chunks,
+                        &chunk_data,
+                        &mut base,
+                    )
+                    .await?
+                {
+                    delta_chunks += 1;
+                    delta_bytes += literal;
+                    pb.inc(chunk.len as u64);
+                    continue;
+                }
 
-            let chunk_meta = ChunkMetadata {
+                let chunk_meta = ChunkMetadata {
+                    upload_id: session.upload_id.clone(),
+                    chunk_number: chunk_num,
+                    total_chunks,
+                    data: chunk_data,
+                };
+
+                let response = self
+                    .request(
+                        Operation::StoreChunk,
+                        chunk_meta.to_payload(self.capabilities & capability::COMPRESSION != 0),
+                    )
+                    .await?;
+
+                if response.status != StatusCode::Success {
+                    return Err(refused(
+                        &format!("Chunk {} upload failed", chunk_num),
+                        &response,
+                    ));
+                }
+                pb.inc(chunk.len as u64);
+            }
+            missing = self.upload_status(&session.upload_id, total_chunks).await?;
+        }
+        if !missing.is_empty() {
+            return Err(format!("Server is still missing {} chunks", missing.len()).into());
+        }
+        if delta_chunks > 0 {
+            pb.println(format!(
+                "'{}': {} chunk(s) sent as deltas, {} literal",
+                remote_name,
+                delta_chunks,
+                HumanBytes(delta_bytes as u64)
+            ));
+        }
+
+        // The server re-hashes the reassembled file and refuses to commit on mismatch
+        let complete = StoreCompleteRequest {
+            upload_id: session.upload_id,
+            total_size,
+            checksum,
+        };
+        let response = self
+            .request(Operation::StoreComplete, complete.to_payload())
+            .await?;
+
+        if response.status == StatusCode::Success {
+            Ok(())
+        } else {
+            Err(corrupted("Upload finalization failed", &response))
+        }
+    }
+
+    /// Signatures of the server's current copy of a file, or None if there is
+    /// no copy to diff against
+    async fn fetch_signatures(
+        &mut self,
+        remote_name: &str,
+        total_size: u64,
+    ) -> Result<Option<DeltaBase>, Box<dyn Error>> {
+        // Aim for about 16K blocks, between 1 KiB and 16 KiB each, unless the
+        // file is so large that their signatures would not fit in one frame
+        let fitting = total_size / (MAX_FRAME_LEN as u64 / 2 / BLOCK_SIGNATURE_LEN);
+        let block_size = (total_size / 16384)
+            .next_power_of_two()
+            .clamp(1024, 16384)
+            .max(fitting.next_power_of_two())
+            .min(delta::MAX_BLOCK_SIZE as u64) as u32;
+        let req = SignaturesRequest {
+            filename: remote_name.to_string(),
+            block_size,
+        };
+        let response = self
+            .request(Operation::Signatures, req.to_payload())
+            .await?;
+        match response.status {
+            StatusCode::Success => {
+                let sigs = SignaturesResponse::from_payload(&response.payload)?;
+                Ok(Some(DeltaBase {
+                    checksum: sigs.checksum,
+                    index: SignatureIndex::new(block_size as usize, &sigs.signatures),
+                }))
+            }
+            // Append-only accounts may not read stored data; they upload whole chunks
+            StatusCode::ErrorNotFound | StatusCode::ErrorPermissionDenied => Ok(None),
+            _ => Err(refused("Signature request failed", &response)),
+        }
+    }
+
+    /// Send a chunk as a delta against `base` if that saves anything. Returns
+    /// the literal bytes sent, or None if the chunk still has to be sent whole.
+    /// A base the server rejects (it changed meanwhile) is not tried again.
+    async fn send_delta(
+        &mut self,
+        upload_id: &str,
+        chunk_number: u32,
+        total_chunks: u32,
+        data: &[u8],
+        base: &mut Option<DeltaBase>,
+    ) -> Result<Option<usize>, Box<dyn Error>> {
+        let Some(current) = base.as_ref() else {
+            return Ok(None);
+        };
+        let instructions = current.index.delta(data);
+        let literal = delta::literal_len(&instructions);
+        if literal >= data.len() {
+            return Ok(None);
+        }
+
+        let chunk = DeltaChunk {
+            upload_id: upload_id.to_string(),
+            chunk_number,
+            total_chunks,
+            base_checksum: current.checksum,
+            instructions,
+        };
+        let response = self
+            .request(Operation::StoreDelta, chunk.to_payload())
+            .await?;
+        if response.status == StatusCode::Success {
+            Ok(Some(literal))
+        } else {
+            *base = None;
+            Ok(None)
+        }
+    }
+
+    /// Send the hash of every chunk of the upload so the server can fill the
+    /// session from chunks it already stores. Returns the chunks still missing.
+    async fn offer_chunks(
+        &mut self,
+        upload_id: &str,
+        hashes: &[[u8; 32]],
+    ) -> Result<Vec<u32>, Box<dyn Error>> {
+        let total_chunks = hashes.len() as u32;
+        let mut missing = Vec::new();
+        for (batch, hashes) in hashes.chunks(HASH_BATCH).enumerate() {
+            let req = HaveChunksRequest {
+                upload_id: upload_id.to_string(),
+                first_chunk: (batch * HASH_BATCH) as u32,
+                hashes: hashes.to_vec(),
+            };
+            let response = self
+                .request(Operation::HaveChunks, req.to_payload())
+                .await?;
+            if response.status != StatusCode::Success {
+                return Err(refused("Chunk offer failed", &response));
+            }
+            missing =
+                missing_chunk_numbers(bincode::deserialize(&response.payload)?, total_chunks)?;
+        }
+        Ok(missing)
+    }
+
+    /// Ask the server which chunks of an upload session it has not received
+    async fn upload_status(
+        &mut self,
+        upload_id: &str,
+        total_chunks: u32,
+    ) -> Result<Vec<u32>, Box<dyn Error>> {
+        let response = self
+            .request(Operation::UploadStatus, upload_id.as_bytes().to_vec())
+            .await?;
+        if response.status != StatusCode::Success {
+            return Err(refused("Upload status failed", &response));
+        }
+        missing_chunk_numbers(bincode::deserialize(&response.payload)?, total_chunks)
+    }
+
+    async fn get_file_metadata(
+        &mut self,
+        remote_name: &str,
+    ) -> Result<FileMetadata, Box<dyn Error>> {
+        if self.capabilities & capability::STAT != 0 {
+            let stat = self.stat_and_return(remote_name).await?;
+            if stat.metadata.entry_type != EntryType::File {
+                return Err(format!("{} is a directory", stat.metadata.filename).into());
+            }
+            return Ok(stat.metadata);
+        }
+        // Servers without Stat: find the file in its directory's listing
+        let remote_name = normalize_path(remote_name)?;
+        let parent = remote_name
+            .rsplit_once('/')
+            .map(|(dir, _)| dir)
+            .unwrap_or("");
+        let listing = self
+            .list_files_and_return(parent, false, &ListView::default())
+            .await?;
+        listing
+            .entries
+            .into_iter()
+            .find(|f| f.filename == remote_name && f.entry_type == EntryType::File)
+            .ok_or_else(|| {
+                Failure::new(
+                    FailureKind::NotFound,
+                    format!("File {} not found on server", remote_name),
+                )
+                .into()
+            })
+    }
+
+    async fn stat_and_return(&mut self, remote_name: &str) -> Result<FileStat, Box<dyn Error>> {
+        self.require(capability::STAT)?;
+        let response = self
+            .request(Operation::Stat, self.remote_path(remote_name)?.into_bytes())
+            .await?;
+        if response.status != StatusCode::Success {
+            return Err(refused("Stat failed", &response));
+        }
+        let mut stat: FileStat = bincode::deserialize(&response.payload)?;
+        // The server only knows the name we sent, which may be encrypted
+        stat.metadata.filename = normalize_path(remote_name)?;
+        Ok(stat)
+    }
+
+    async fn stat_file(&mut self, remote_name: &str) -> Result<(), Box<dyn Error>> {
+        let stat = self.stat_and_return(remote_name).await?;
+        if self.output == Output::Json {
+            return print_json(&stat);
+        }
+        let meta = &stat.metadata;
+        println!("Name:          {}", meta.filename);
+        match meta.entry_type {
+            EntryType::Directory => {
+                println!("Type:          directory");
+                println!("Last modified: {}", meta.last_modified);
+            }
+            EntryType::File => {
+                println!("Type:          file");
+                println!("Size:          {} ({})", meta.size, HumanBytes(meta.size));
+                println!("Last modified: {}", meta.last_modified);
+                println!("Checksum:      {}", meta.checksum);
+                println!("Versions:      {} older kept", stat.versions);
+                println!(
+                    "Chunks:        {} ({} shared with other files or versions)",
+                    stat.chunks, stat.shared_chunks
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetch a listing page by page until `view.limit` entries are in hand,
+    /// or all of them. The server filters and sorts, except with encryption:
+    /// it only sees encrypted names then, so that happens here after decrypting.
+    async fn list_files_and_return(
+        &mut self,
+        path: &str,
+        recursive: bool,
+        view: &ListView,
+    ) -> Result<Listing, Box<dyn Error>> {
+        if !path.is_empty() || recursive {
+            self.require(capability::DIRECTORIES)?;
+        }
+        let local_view = self.crypto.is_some();
+        let mut request = ListRequest {
+            path: self.remote_path(path)?,
+            recursive,
+            ..ListRequest::default()
+        };
+        if !local_view {
+            request.filter = view.filter.clone();
+            request.sort = view.sort;
+        }
+
+        let mut listing = Listing::default();
+        loop {
+            if !local_view {
+                let remaining = view
+                    .limit
+                    .map(|limit| limit.saturating_sub(listing.entries.len()));
+                if remaining == Some(0) {
+                    break;
+                }
+                request.limit = remaining.map_or(0, |n| n.min(MAX_LIST_PAGE as usize) as u32);
+            }
+            let response = self.request(Operation::List, request.to_payload()).await?;
+            if response.status != StatusCode::Success {
+                return Err(refused("List failed", &response));
+            }
+            let page: Listing = bincode::deserialize(&response.payload)?;
+            listing.entries.extend(page.entries);
+            // Only the first page carries totals
+            if request.cursor.is_none() {
+                listing.total_entries = page.total_entries;
+                listing.logical_size = page.logical_size;
+            }
+            listing.physical_size = page.physical_size;
+            request.cursor = page.next_cursor;
+            if request.cursor.is_none() {
+                break;
+            }
+        }
+
+        let Some(crypto) = &self.crypto else {
+            return Ok(listing);
+        };
+        // Entries not encrypted under our key are someone else's; leave them out
+        listing
+            .entries
+            .retain_mut(|entry| match crypto.decrypt_path(&entry.filename) {
+                Some(name) => {
+                    entry.filename = name;
+                    true
+                }
+                None => false,
+            });
+        if let Some(filter) = &view.filter {
+            listing
+                .entries
+                .retain(|entry| filter_matches(filter, &entry.filename));
+        }
+        listing.entries.sort_by(|a, b| view.sort.compare(a, b));
+        listing.total_entries = listing.entries.len() as u64;
+        listing.logical_size = listing.entries.iter().map(|m| m.size).sum();
+        if let Some(limit) = view.limit {
+            listing.entries.truncate(limit);
+        }
+        Ok(listing)
+    }
+
+    async fn download_file_chunked(
+        &mut self,
+        remote_name: &str,
+        local_path: &str,
+        resume: bool,
+        selector: VersionSelector,
+    ) -> Result<u64, Box<dyn Error>> {
+        let (file_meta, version) = match selector {
+            VersionSelector::Current => (self.get_file_metadata(remote_name).await?, 0),
+            _ => self.resolve_version(remote_name, selector).await?,
+        };
+        if version != 0 && self.output == Output::Text {
+            println!(
+                "Fetching version {} stored at {}",
+                version, file_meta.last_modified
+            );
+        }
+        let pb = self.transfer_bar(file_meta.size, "Downloading");
+        match self
+            .fetch_file(&file_meta, version, local_path, resume, &pb)
+            .await
+        {
+            Ok(()) => {
+                pb.finish_with_message("Downloaded successfully!");
+                self.end_bar();
+                Ok(file_meta.size)
+            }
+            Err(e) => {
+                pb.abandon();
+                Err(e)
+            }
+        }
+    }
+
+    /// Download one file, decrypting it if client-side encryption is on. The
+    /// ciphertext is fetched next to `local_path` first, so `resume` works
+    /// for encrypted files too.
+    async fn fetch_file(
+        &mut self,
+        file_meta: &FileMetadata,
+        version: u64,
+        local_path: &str,
+        resume: bool,
+        pb: &ProgressBar,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(crypto) = self.crypto.clone() else {
+            return self
+                .fetch_raw(file_meta, version, local_path, resume, pb)
+                .await;
+        };
+        let sealed_path = format!("{}.netbackup-part", local_path);
+        self.fetch_raw(file_meta, version, &sealed_path, resume, pb)
+            .await?;
+
+        let decrypted = fs::File::open(&sealed_path)
+            .and_then(|input| Ok((input, fs::File::create(local_path)?)))
+            .map_err(|e| e.into())
+            .and_then(|(input, output)| {
+                crypto.decrypt_file(
+                    std::io::BufReader::new(input),
+                    std::io::BufWriter::new(output),
+                    &file_meta.filename,
+                )
+            });
+        match decrypted {
+            Ok(_) => {
+                fs::remove_file(&sealed_path)?;
+                Ok(())
+            }
+            Err(e) => {
+                let _ = fs::remove_file(local_path);
+                Err(Failure::new(
+                    FailureKind::Integrity,
+                    format!("Cannot decrypt '{}': {}", file_meta.filename, e),
+                )
+                .into())
+            }
+        }
+    }
+
+    /// Download one file chunk by chunk as stored on the server, advancing
+    /// `pb` by bytes received, and verify the result against the server's checksum
+    async fn fetch_raw(
+        &mut self,
+        file_meta: &FileMetadata,
+        version: u64,
+        local_path: &str,
+        resume: bool,
+        pb: &ProgressBar,
+    ) -> Result<(), Box<dyn Error>> {
+        let remote_name = &self.remote_path(&file_meta.filename)?;
+        let total_size = file_meta.size;
+        let total_chunks = total_size.div_ceil(CHUNK_SIZE as u64) as u32;
+
+        let resuming = resume && std::path::Path::new(local_path).exists();
+        let mut output = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(!resuming)
+            .open(local_path)?;
+
+        let missing = if resuming {
+            let missing = self
+                .missing_local_chunks(remote_name, version, &mut output, total_size)
+                .await?;
+            pb.println(format!(
+                "Resuming download of '{}': {} of {} chunks already present",
+                remote_name,
+                total_chunks as usize - missing.len(),
+                total_chunks
+            ));
+            missing
+        } else {
+            (0..total_chunks).collect()
+        };
+        let missing_bytes: u64 = missing.iter().map(|&n| chunk_len(total_size, n)).sum();
+        pb.inc(total_size - missing_bytes);
+
+        for chunk_num in missing {
+            let chunk_req = ChunkDownloadRequest {
                 filename: remote_name.to_string(),
-                chunk_number: chunk_num as u32,
-                total_chunks: total_chunks as u32,
-                data: chunk_data,
+                chunk_number: chunk_num,
+                chunk_size: CHUNK_SIZE as u32,
+                version,
             };
+            let response = self
+                .request(Operation::RetrieveChunk, chunk_req.to_payload())
+                .await?;
+
+            if response.status != StatusCode::Success {
+                return Err(corrupted(
+                    &format!("Chunk {} download failed", chunk_num),
+                    &response,
+                ));
+            }
+
+            let chunk_resp = ChunkDownloadResponse::from_payload(&response.payload)?;
+            output.seek(SeekFrom::Start(chunk_num as u64 * CHUNK_SIZE as u64))?;
+            output.write_all(&chunk_resp.data)?;
+            pb.inc(chunk_resp.data.len() as u64);
+        }
+        // A partial file from an earlier attempt may be longer than the real one
+        output.set_len(total_size)?;
+        output.sync_all()?;
+
+        let checksum = hex::encode(&hash_file(&mut output)?);
+        if checksum != file_meta.checksum {
+            return Err(Failure::new(
+                FailureKind::Integrity,
+                format!(
+                    "Checksum mismatch for '{}': expected {}, got {}",
+                    local_path, file_meta.checksum, checksum
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Compare a partial local copy against the server's per-chunk hashes and
+    /// return the chunks that still have to be fetched
+    async fn missing_local_chunks(
+        &mut self,
+        remote_name: &str,
+        version: u64,
+        local: &mut fs::File,
+        total_size: u64,
+    ) -> Result<Vec<u32>, Box<dyn Error>> {
+        self.require(capability::RESUMABLE_DOWNLOADS)?;
+
+        let req = ChunkHashesRequest {
+            filename: remote_name.to_string(),
+            chunk_size: CHUNK_SIZE as u32,
+            version,
+        };
+        let response = self
+            .request(Operation::ChunkHashes, req.to_payload())
+            .await?;
+        if response.status != StatusCode::Success {
+            return Err(refused("Chunk hash request failed", &response));
+        }
+        let server_hashes: Vec<[u8; 32]> = bincode::deserialize(&response.payload)?;
+        let expected = total_size.div_ceil(CHUNK_SIZE as u64);
+        if server_hashes.len() as u64 != expected {
+            return Err(Failure::new(
+                FailureKind::Integrity,
+                format!(
+                    "Server sent hashes for {} chunks, expected {}",
+                    server_hashes.len(),
+                    expected
+                ),
+            )
+            .into());
+        }
+
+        let local_size = local.metadata()?.len();
+        let mut missing = Vec::new();
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        for (chunk_num, server_hash) in server_hashes.iter().enumerate() {
+            let start = chunk_num as u64 * CHUNK_SIZE as u64;
+            let len = (total_size - start).min(CHUNK_SIZE as u64) as usize;
+            let present = if start + len as u64 <= local_size {
+                local.seek(SeekFrom::Start(start))?;
+                local.read_exact(&mut buf[..len])?;
+                let local_hash: [u8; 32] = Sha256::digest(&buf[..len]).into();
+                &local_hash == server_hash
+            } else {
+                false
+            };
+            if !present {
+                missing.push(chunk_num as u32);
+            }
+        }
+        Ok(missing)
+    }
+
+    async fn list_files(
+        &mut self,
+        path: &str,
+        recursive: bool,
+        view: &ListView,
+    ) -> Result<(), Box<dyn Error>> {
+        let listing = self.list_files_and_return(path, recursive, view).await?;
+        if self.output == Output::Json {
+            return print_json(&ListOutput {
+                path,
+                entries: &listing.entries,
+                total_entries: listing.total_entries,
+                logical_size: listing.logical_size,
+                physical_size: listing.physical_size,
+            });
+        }
+        if listing.entries.is_empty() {
+            if view.filter.is_some() {
+                println!("No matching files on server");
+            } else {
+                println!("No files on server");
+            }
+        } else if !view.long {
+            for file in &listing.entries {
+                match file.entry_type {
+                    EntryType::Directory => println!("{}/", file.filename),
+                    EntryType::File => println!("{}", file.filename),
+                }
+            }
+        } else {
+            println!(
+                "{:<35} {:>10} {:<26} {:<16}",
+                "FILENAME", "SIZE", "LAST MODIFIED", "CHECKSUM"
+            );
+            for file in &listing.entries {
+                match file.entry_type {
+                    EntryType::Directory => println!(
+                        "{:<35} {:>10} {:<26} {:<16}",
+                        format!("{}/", file.filename),
+                        "-",
+                        file.last_modified,
+                        "-"
+                    ),
+                    EntryType::File => println!(
+                        "{:<35} {:>10} {:<26} {:<16}",
+                        file.filename,
+                        file.size,
+                        file.last_modified,
+                        &file.checksum[..16] // Short checksum for readability
+                    ),
+                }
+            }
+            println!(
+                "Total: {} listed; chunk store holds {} after deduplication",
+                HumanBytes(listing.logical_size),
+                HumanBytes(listing.physical_size)
+            );
+        }
+        if (listing.entries.len() as u64) < listing.total_entries {
+            println!(
+                "Showing {} of {} entries",
+                listing.entries.len(),
+                listing.total_entries
+            );
+        }
+        Ok(())
+    }
+
+    async fn list_versions_and_return(
+        &mut self,
+        remote_name: &str,
+    ) -> Result<Vec<VersionInfo>, Box<dyn Error>> {
+        self.require(capability::VERSIONS)?;
+        let response = self
+            .request(
+                Operation::ListVersions,
+                self.remote_path(remote_name)?.into_bytes(),
+            )
+            .await?;
+        if response.status != StatusCode::Success {
+            return Err(refused("Listing versions failed", &response));
+        }
+        Ok(bincode::deserialize(&response.payload)?)
+    }
+
+    async fn list_versions(&mut self, remote_name: &str) -> Result<(), Box<dyn Error>> {
+        let versions = self.list_versions_and_return(remote_name).await?;
+        if self.output == Output::Json {
+            return print_json(&versions);
+        }
+        println!(
+            "{:<20} {:>10} {:<26} {:<16}",
+            "VERSION", "SIZE", "STORED AT", "CHECKSUM"
+        );
+        for v in versions {
+            let id = if v.version == 0 {
+                "current".to_string()
+            } else {
+                v.version.to_string()
+            };
+            println!(
+                "{:<20} {:>10} {:<26} {:<16}",
+                id,
+                v.size,
+                format_micros(v.stored_at),
+                &v.checksum[..16] // Short checksum for readability
+            );
+        }
+        Ok(())
+    }
+
+    /// Pick the version a selector refers to, returning metadata to download
+    /// it with and the version ID to request
+    async fn resolve_version(
+        &mut self,
+        remote_name: &str,
+        selector: VersionSelector,
+    ) -> Result<(FileMetadata, u64), Box<dyn Error>> {
+        let versions = self.list_versions_and_return(remote_name).await?;
+        // Versions come newest first
+        let chosen = match selector {
+            VersionSelector::Current => versions.into_iter().find(|v| v.version == 0),
+            VersionSelector::Id(id) => versions.into_iter().find(|v| v.version == id),
+            VersionSelector::At(at) => versions.into_iter().find(|v| v.stored_at <= at),
+        }
+        .ok_or_else(|| format!("No matching version of '{}'", remote_name))?;
+
+        let meta = FileMetadata {
+            filename: normalize_path(remote_name)?,
+            size: chosen.size,
+            last_modified: format_micros(chosen.stored_at),
+            checksum: chosen.checksum,
+            entry_type: EntryType::File,
+        };
+        Ok((meta, chosen.version))
+    }
+
+    /// Rename or copy a remote file or directory on the server. A destination
+    /// that is an existing directory, or ends in '/', receives it under its
+    /// current name.
+    async fn move_path(&mut self, from: &str, to: &str, copy: bool) -> Result<(), Box<dyn Error>> {
+        self.require(capability::RENAME)?;
+        if self.crypto.is_some() {
+            // The trailer of an encrypted file is sealed to its path
+            return Err("Renaming and copying on the server are not possible with \
+                 client-side encryption; download and upload under the new name instead"
+                .into());
+        }
+        let from = normalize_path(from)?;
+        let mut dest = normalize_path(to)?;
+        let into_dir = to.ends_with('/')
+            || dest.is_empty()
+            || (self.capabilities & capability::STAT != 0
+                && matches!(
+                    self.stat_and_return(&dest).await,
+                    Ok(stat) if stat.metadata.entry_type == EntryType::Directory
+                ));
+        if into_dir {
+            let name = from.rsplit('/').next().unwrap_or(&from);
+            dest = join_remote(&dest, name);
+        }
+
+        let (operation, verb) = if copy {
+            (Operation::Copy, "Copied")
+        } else {
+            (Operation::Rename, "Moved")
+        };
+        let req = MoveRequest {
+            from: self.remote_path(&from)?,
+            to: self.remote_path(&dest)?,
+        };
+        let response = self.request(operation, req.to_payload()).await?;
+
+        if response.status != StatusCode::Success {
+            Err(refused(&format!("{:?} failed", operation), &response))
+        } else if self.output == Output::Json {
+            print_json(&serde_json::json!({ "from": from, "to": dest, "copy": copy }))
+        } else {
+            println!("✓ {} '{}' to '{}'", verb, from, dest);
+            Ok(())
+        }
+    }
+
+    async fn make_directory(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        self.require(capability::DIRECTORIES)?;
+        let response = self
+            .request(Operation::Mkdir, self.remote_path(path)?.into_bytes())
+            .await?;
+
+        if response.status != StatusCode::Success {
+            Err(refused("Mkdir failed", &response))
+        } else if self.output == Output::Json {
+            print_json(&serde_json::json!({ "created": path }))
+        } else {
+            println!("✓ Created directory '{}'", path);
+            Ok(())
+        }
+    }
+
+    async fn remove_directory(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        self.require(capability::DIRECTORIES)?;
+        let response = self
+            .request(Operation::Rmdir, self.remote_path(path)?.into_bytes())
+            .await?;
+
+        if response.status != StatusCode::Success {
+            Err(refused("Rmdir failed", &response))
+        } else if self.output == Output::Json {
+            print_json(&serde_json::json!({ "removed": path }))
+        } else {
+            println!("✓ Removed directory '{}'", path);
+            Ok(())
+        }
+    }
+
+    /// Upload every file below `local_dir` into `remote_dir`, keeping relative
+    /// paths. One failed file does not stop the rest, a lost connection does.
+    async fn upload_tree(
+        &mut self,
+        local_dir: &str,
+        remote_dir: &str,
+        exclude: &[String],
+    ) -> Result<TransferSummary, Box<dyn Error>> {
+        self.require(capability::DIRECTORIES)?;
+        let remote_dir = normalize_path(remote_dir)?;
+        let rules = IgnoreRules::load(Path::new(local_dir), exclude)?;
+        let tree = walk_local(Path::new(local_dir), &rules)?;
+
+        // Uploads create parent directories; this keeps empty ones too
+        for dir in std::iter::once(String::new()).chain(tree.dirs) {
+            let remote = join_remote(&remote_dir, &dir);
+            if !remote.is_empty() {
+                let response = self
+                    .request(Operation::Mkdir, self.remote_path(&remote)?.into_bytes())
+                    .await?;
+                if response.status != StatusCode::Success {
+                    return Err(refused(&format!("Mkdir '{}' failed", remote), &response));
+                }
+            }
+        }
+
+        let total_bytes = tree.files.iter().map(|f| f.size).sum();
+        let pb = self.transfer_bar(total_bytes, "Uploading");
+        let mut summary = TransferSummary::new(local_dir, &remote_dir);
+
+        for file in tree.files {
+            let remote = join_remote(&remote_dir, &file.relative);
+            pb.set_message(file.relative.clone());
+            let local = file.path.to_string_lossy().to_string();
+            match self.send_file(&local, &remote, &pb).await {
+                Ok(()) => summary.succeeded(file.size),
+                Err(e) if self.broken => {
+                    pb.abandon();
+                    self.end_bar();
+                    return Err(e);
+                }
+                Err(e) => {
+                    pb.println(format!("✗ {}: {}", file.relative, e));
+                    summary.failed(file.relative, e.as_ref());
+                }
+            }
+        }
+        pb.finish_with_message("done");
+        self.end_bar();
+        Ok(summary)
+    }
+
+    /// Download every file below `remote_dir` into `local_dir`, keeping
+    /// relative paths. As with uploads, only a lost connection stops the rest.
+    async fn download_tree(
+        &mut self,
+        remote_dir: &str,
+        local_dir: &str,
+        resume: bool,
+    ) -> Result<TransferSummary, Box<dyn Error>> {
+        let remote_dir = normalize_path(remote_dir)?;
+        let listing = self
+            .list_files_and_return(&remote_dir, true, &ListView::default())
+            .await?;
+        fs::create_dir_all(local_dir)?;
+
+        let mut files = Vec::new();
+        for entry in listing.entries {
+            // Never trust the server with where things land locally
+            let relative = match entry.filename.strip_prefix(&remote_dir) {
+                Some(rest) => normalize_path(rest)?,
+                None => normalize_path(&entry.filename)?,
+            };
+            if relative.is_empty() {
+                continue;
+            }
+            let local = Path::new(local_dir).join(&relative);
+            match entry.entry_type {
+                EntryType::Directory => fs::create_dir_all(&local)?,
+                EntryType::File => files.push((relative, local, entry)),
+            }
+        }
+
+        let total_bytes = files.iter().map(|(_, _, meta)| meta.size).sum();
+        let pb = self.transfer_bar(total_bytes, "Downloading");
+        let mut summary = TransferSummary::new(&remote_dir, local_dir);
+
+        for (relative, local, meta) in files {
+            pb.set_message(relative.clone());
+            let local = local.to_string_lossy().to_string();
+            match self.fetch_file(&meta, 0, &local, resume, &pb).await {
+                Ok(()) => summary.succeeded(meta.size),
+                Err(e) if self.broken => {
+                    pb.abandon();
+                    self.end_bar();
+                    return Err(e);
+                }
+                Err(e) => {
+                    pb.println(format!("✗ {}: {}", relative, e));
+                    summary.failed(relative, e.as_ref());
+                }
+            }
+        }
+        pb.finish_with_message("done");
+        self.end_bar();
+        Ok(summary)
+    }
+
+    async fn delete_file(&mut self, remote_name: &str) -> Result<(), Box<dyn Error>> {
+        let response = self
+            .request(
+                Operation::Delete,
+                self.remote_path(remote_name)?.into_bytes(),
+            )
+            .await?;
+
+        if response.status != StatusCode::Success {
+            Err(refused("Delete failed", &response))
+        } else if self.output == Output::Json {
+            print_json(&serde_json::json!({ "deleted": remote_name }))
+        } else {
+            println!("✓ Deleted '{}'", remote_name);
+            Ok(())
+        }
+    }
+}
+
+/// SHA-256 of a whole file, read in chunks so large files are never held in memory
+fn hash_file(file: &mut fs::File) -> std::io::Result<[u8; 32]> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// The server's copy of a file being re-uploaded, for delta transfers
+struct DeltaBase {
+    checksum: [u8; 32],
+    index: SignatureIndex,
+}
+
+/// Encrypts the chunks of one file on their way to the server
+struct Sealer {
+    crypto: Crypto,
+    /// Normalised remote path, bound into the file's trailer
+    path: String,
+    /// Size and SHA-256 of the plaintext
+    size: u64,
+    checksum: [u8; 32],
+}
+
+impl Sealer {
+    fn seal(&self, plaintext: &[u8], index: usize, count: usize) -> Vec<u8> {
+        self.crypto.encrypt_chunk(
+            plaintext,
+            index,
+            count,
+            &self.path,
+            self.size,
+            &self.checksum,
+        )
+    }
+
+    /// Replace the chunk hashes in `layout` with those of the sealed chunks,
+    /// returning the sealed file's size, SHA-256 and chunk sizes. Even an
+    /// empty file gets a chunk, to hold its header and trailer.
+    fn seal_layout(
+        &self,
+        file: &mut fs::File,
+        layout: &mut Vec<LocalChunk>,
+    ) -> std::io::Result<(u64, [u8; 32], Vec<u32>)> {
+        if layout.is_empty() {
+            layout.push(LocalChunk {
+                offset: 0,
+                len: 0,
+                hash: [0; 32],
+            });
+        }
+        let count = layout.len();
+        let mut hasher = Sha256::new();
+        let mut total = 0u64;
+        let mut sizes = Vec::with_capacity(count);
+        for (index, chunk) in layout.iter_mut().enumerate() {
+            let mut data = vec![0u8; chunk.len as usize];
+            file.seek(SeekFrom::Start(chunk.offset))?;
+            file.read_exact(&mut data)?;
+            let sealed = self.seal(&data, index, count);
+            debug_assert_eq!(
+                sealed.len(),
+                crypto::encrypted_len(data.len(), index, count)
+            );
+            hasher.update(&sealed);
+            chunk.hash = Sha256::digest(&sealed).into();
+            total += sealed.len() as u64;
+            sizes.push(sealed.len() as u32);
+        }
+        Ok((total, hasher.finalize().into(), sizes))
+    }
+}
 
-            let mut msg = Message::new_with_auth(
-                Operation::StoreChunk,
-                chunk_meta.to_payload(),
-                self.auth_token,
-            );
-            msg.set_request_id(self.request_id);
-            self.request_id += 1;
+/// One chunk of a local file being uploaded
+struct LocalChunk {
+    offset: u64,
+    len: u32,
+    hash: [u8; 32],
+}
 
-            self.send_message(&msg).await?;
-            let response = self.receive_message().await?;
+/// Cut a file into chunks, at content-defined boundaries if `chunking` is
+/// set and every `CHUNK_SIZE` bytes otherwise. Also returns the whole-file
+/// SHA-256.
+fn chunk_layout(
+    file: &mut fs::File,
+    chunking: Option<&ChunkerConfig>,
+) -> std::io::Result<(Vec<LocalChunk>, [u8; 32])> {
+    // With all three sizes equal the chunker cuts at fixed offsets
+    let config = chunking.copied().unwrap_or(ChunkerConfig {
+        min_size: CHUNK_SIZE,
+        avg_size: CHUNK_SIZE,
+        max_size: CHUNK_SIZE,
+    });
 
-            if response.status != StatusCode::Success {
-                pb.abandon();
-                return Err(format!("Chunk {} upload failed", chunk_num).into());
-            }
-            pb.inc(1);
-        }
+    file.seek(SeekFrom::Start(0))?;
+    let mut layout = Vec::new();
+    let mut hasher = Sha256::new();
+    let mut offset = 0u64;
+    for chunk in config.chunks(&mut *file) {
+        let chunk = chunk?;
+        hasher.update(&chunk);
+        layout.push(LocalChunk {
+            offset,
+            len: chunk.len() as u32,
+            hash: Sha256::digest(&chunk).into(),
+        });
+        offset += chunk.len() as u64;
+    }
+    Ok((layout, hasher.finalize().into()))
+}
 
-        pb.finish_with_message("Upload complete!");
-        println!();
+/// Which version of a file to download
+#[derive(Debug, Clone, Copy)]
+pub enum VersionSelector {
+    Current,
+    /// A version ID as shown by `netbackup versions`
+    Id(u64),
+    /// Whatever version was current at this time, in microseconds since the epoch
+    At(u64),
+}
 
-        let mut complete_msg = Message::new_with_auth(
-            Operation::StoreComplete,
-            remote_name.as_bytes().to_vec(),
-            self.auth_token,
-        );
-        complete_msg.set_request_id(self.request_id);
-        self.request_id += 1;
+/// Parse a UTC timestamp as accepted by `download --at`
+pub fn parse_timestamp(value: &str) -> Result<u64, Box<dyn Error>> {
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(dt.timestamp_micros() as u64);
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(value, format) {
+            return Ok(dt.and_utc().timestamp_micros() as u64);
+        }
+    }
+    Err(format!(
+        "Invalid timestamp '{}', expected e.g. \"2024-05-01 13:00:00\" (UTC)",
+        value
+    )
+    .into())
+}
 
-        self.send_message(&complete_msg).await?;
-        let response = self.receive_message().await?;
+fn format_micros(micros: u64) -> String {
+    chrono::DateTime::from_timestamp_micros(micros as i64)
+        .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
 
-        if response.status == StatusCode::Success {
-            Ok(())
-        } else {
-            Err(format!(
-                "Upload finalization failed: {}",
-                String::from_utf8_lossy(&response.payload)
+/// Expand the missing-chunk ranges a server sent. They must be sorted, not
+/// overlap and stay within the file, so they never list more than
+/// `total_chunks` chunks.
+fn missing_chunk_numbers(
+    ranges: Vec<std::ops::Range<u32>>,
+    total_chunks: u32,
+) -> Result<Vec<u32>, Box<dyn Error>> {
+    let mut next = 0;
+    for range in &ranges {
+        if range.start < next || range.end <= range.start || range.end > total_chunks {
+            return Err(Failure::new(
+                FailureKind::Integrity,
+                "Server asked for chunks beyond the end of the file",
             )
-            .into())
+            .into());
         }
+        next = range.end;
     }
+    Ok(ranges.into_iter().flatten().collect())
+}
 
-    async fn get_file_metadata(
-        &mut self,
-        remote_name: &str,
-    ) -> Result<crate::storage::FileMetadata, Box<dyn Error>> {
-        let files = self.list_files_and_return().await?;
-        files
-            .into_iter()
-            .find(|f| f.filename == remote_name)
-            .ok_or_else(|| format!("File {} not found on server", remote_name).into())
-    }
+/// Number of bytes in a given chunk of a file of `total_size` bytes
+fn chunk_len(total_size: u64, chunk_number: u32) -> u64 {
+    let start = chunk_number as u64 * CHUNK_SIZE as u64;
+    total_size.saturating_sub(start).min(CHUNK_SIZE as u64)
+}
 
-    async fn list_files_and_return(
-        &mut self,
-    ) -> Result<Vec<crate::storage::FileMetadata>, Box<dyn Error>> {
-        let mut list_msg = Message::new_with_auth(Operation::List, Vec::new(), self.auth_token);
-        list_msg.set_request_id(self.request_id);
-        self.request_id += 1;
+/// Error for a request the server refused, keeping its reason and the kind
+/// of failure its status stands for
+fn refused(what: &str, response: &Message) -> Box<dyn Error> {
+    let kind = match response.status {
+        StatusCode::ErrorNotFound => FailureKind::NotFound,
+        StatusCode::ErrorPermissionDenied => FailureKind::PermissionDenied,
+        // The server could not trust the request as sent
+        StatusCode::ErrorMessageRejected => FailureKind::Integrity,
+        StatusCode::Success
+        | StatusCode::ErrorInvalidData
+        | StatusCode::ErrorServerError
+        | StatusCode::ErrorIncompatibleVersion
+        | StatusCode::ErrorUnsupported
+        | StatusCode::ErrorInvalidRequest
+        | StatusCode::ErrorAlreadyExists => FailureKind::Other,
+    };
+    Failure::new(
+        kind,
+        format!("{}: {}", what, String::from_utf8_lossy(&response.payload)),
+    )
+    .into()
+}
 
-        self.send_message(&list_msg).await?;
-        let response = self.receive_message().await?;
-        if response.status != StatusCode::Success {
-            return Err("List failed".into());
-        }
-        let files: Vec<crate::storage::FileMetadata> = bincode::deserialize(&response.payload)?;
-        Ok(files)
+/// Like `refused`, for transfer steps where invalid data means a file or
+/// chunk failed its checksum on the server
+fn corrupted(what: &str, response: &Message) -> Box<dyn Error> {
+    if response.status == StatusCode::ErrorInvalidData {
+        Failure::new(
+            FailureKind::Integrity,
+            format!("{}: {}", what, String::from_utf8_lossy(&response.payload)),
+        )
+        .into()
+    } else {
+        refused(what, response)
     }
+}
 
-    async fn download_file_chunked(
-        &mut self,
-        remote_name: &str,
-        local_path: &str,
-    ) -> Result<(), Box<dyn Error>> {
-        let file_meta = self.get_file_metadata(remote_name).await?;
-        let total_size = file_meta.size as usize;
-        let total_chunks = (total_size + CHUNK_SIZE - 1) / CHUNK_SIZE;
-
-        let pb = ProgressBar::new(total_chunks as u64);
-        pb.set_message("Downloading");
+fn join_remote(dir: &str, relative: &str) -> String {
+    match (dir.is_empty(), relative.is_empty()) {
+        (true, _) => relative.to_string(),
+        (_, true) => dir.to_string(),
+        _ => format!("{}/{}", dir, relative),
+    }
+}
 
-        let mut output = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(local_path)?;
+struct LocalFile {
+    path: PathBuf,
+    /// Path below the walked root, '/'-separated
+    relative: String,
+    size: u64,
+}
 
-        for chunk_num in 0..total_chunks {
-            let chunk_req = ChunkDownloadRequest {
-                filename: remote_name.to_string(),
-                chunk_number: chunk_num as u32,
-                chunk_size: CHUNK_SIZE as u32,
-            };
-            let mut msg = Message::new_with_auth(
-                Operation::RetrieveChunk,
-                chunk_req.to_payload(),
-                self.auth_token,
-            );
-            msg.set_request_id(self.request_id);
-            self.request_id += 1;
+struct LocalTree {
+    dirs: Vec<String>,
+    files: Vec<LocalFile>,
+    /// Paths skipped by ignore rules; an excluded directory is listed once
+    excluded: Vec<String>,
+}
 
-            self.send_message(&msg).await?;
-            let response = self.receive_message().await?;
+/// Collect every regular file and directory below `root` that the ignore
+/// rules let through, sorted by path. Symlinks are skipped rather than followed.
+fn walk_local(root: &Path, rules: &IgnoreRules) -> std::io::Result<LocalTree> {
+    if !root.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("'{}' is not a directory", root.display()),
+        ));
+    }
 
-            if response.status != StatusCode::Success {
-                pb.abandon();
-                return Err(format!(
-                    "Chunk {} download failed: {}",
-                    chunk_num,
-                    String::from_utf8_lossy(&response.payload)
-                )
-                .into());
+    let mut tree = LocalTree {
+        dirs: Vec::new(),
+        files: Vec::new(),
+        excluded: Vec::new(),
+    };
+    // The root's ignore file is already part of `rules`
+    let mut rules = rules.clone();
+    let mut pending = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        if !prefix.is_empty() {
+            rules.load_dir(&dir, &prefix)?;
+        }
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let relative = join_remote(&prefix, &name);
+            let file_type = entry.file_type()?;
+            if rules.is_ignored(&relative, file_type.is_dir()) {
+                tree.excluded.push(relative);
+                continue;
             }
+            if file_type.is_dir() {
+                tree.dirs.push(relative.clone());
+                pending.push((entry.path(), relative));
+            } else if file_type.is_file() {
+                tree.files.push(LocalFile {
+                    path: entry.path(),
+                    relative,
+                    size: entry.metadata()?.len(),
+                });
+            }
+        }
+    }
+    tree.dirs.sort();
+    tree.files.sort_by(|a, b| a.relative.cmp(&b.relative));
+    tree.excluded.sort();
+    Ok(tree)
+}
 
-            let chunk_resp = ChunkDownloadResponse::from_payload(&response.payload)?;
-            output.seek(SeekFrom::Start(chunk_num as u64 * CHUNK_SIZE as u64))?;
-            output.write_all(&chunk_resp.data)?;
-            pb.inc(1);
+/// Print what an upload would send without connecting to the server
+pub fn upload_dry_run(
+    local_path: &str,
+    remote_name: Option<&str>,
+    recursive: bool,
+    exclude: &[String],
+    output: Output,
+) -> Result<(), Box<dyn Error>> {
+    if !recursive {
+        let size = fs::metadata(local_path)?.len();
+        let remote = normalize_path(remote_name.unwrap_or_else(|| default_local_path(local_path)))?;
+        if output == Output::Json {
+            return print_json(&serde_json::json!({
+                "files": [{ "path": remote, "size": size }],
+                "bytes": size,
+                "excluded": [],
+            }));
         }
-        pb.finish_with_message("Downloaded successfully!");
-        println!();
-        Ok(())
+        println!("Would upload 1 file ({} bytes):", size);
+        println!("  {:<50} {:>12}", remote, size);
+        return Ok(());
     }
 
-    async fn list_files(&mut self) -> Result<(), Box<dyn Error>> {
-        let mut list_msg = Message::new_with_auth(Operation::List, Vec::new(), self.auth_token);
-        list_msg.set_request_id(self.request_id);
-        self.request_id += 1;
+    let remote_dir = match remote_name {
+        Some(name) => normalize_path(name)?,
+        None => default_remote_dir(local_path)?,
+    };
+    let rules = IgnoreRules::load(Path::new(local_path), exclude)?;
+    let tree = walk_local(Path::new(local_path), &rules)?;
+    let total_bytes: u64 = tree.files.iter().map(|f| f.size).sum();
 
-        self.send_message(&list_msg).await?;
-        let response = self.receive_message().await?;
+    if output == Output::Json {
+        let files: Vec<_> = tree
+            .files
+            .iter()
+            .map(|file| {
+                serde_json::json!({
+                    "path": join_remote(&remote_dir, &file.relative),
+                    "size": file.size,
+                })
+            })
+            .collect();
+        return print_json(&serde_json::json!({
+            "files": files,
+            "bytes": total_bytes,
+            "excluded": tree.excluded,
+        }));
+    }
+    println!(
+        "Would upload {} file(s) ({} bytes):",
+        tree.files.len(),
+        total_bytes
+    );
+    for file in &tree.files {
+        println!(
+            "  {:<50} {:>12}",
+            join_remote(&remote_dir, &file.relative),
+            file.size
+        );
+    }
+    if !tree.excluded.is_empty() {
+        println!("Excluded by ignore rules:");
+        for path in &tree.excluded {
+            println!("  {}", path);
+        }
+    }
+    Ok(())
+}
 
-        if response.status != StatusCode::Success {
-            return Err("List failed".into());
+/// Outcome of a transfer
+#[derive(Serialize)]
+struct TransferSummary {
+    source: String,
+    destination: String,
+    transferred: usize,
+    bytes: u64,
+    failed: Vec<TransferFailure>,
+}
+
+#[derive(Serialize)]
+struct TransferFailure {
+    path: String,
+    kind: &'static str,
+    error: String,
+    #[serde(skip)]
+    failure_kind: FailureKind,
+}
+
+impl TransferSummary {
+    fn new(source: &str, destination: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            destination: destination.to_string(),
+            transferred: 0,
+            bytes: 0,
+            failed: Vec::new(),
         }
+    }
 
-        let files: Vec<crate::storage::FileMetadata> = bincode::deserialize(&response.payload)?;
-        if files.is_empty() {
-            println!("No files on server");
-        } else {
-            println!(
-                "{:<35} {:>10} {:<26} {:<16}",
-                "FILENAME", "SIZE", "LAST MODIFIED", "CHECKSUM"
-            );
-            for file in files {
+    fn succeeded(&mut self, size: u64) {
+        self.transferred += 1;
+        self.bytes += size;
+    }
+
+    fn failed(&mut self, path: String, error: &(dyn Error + 'static)) {
+        let failure_kind = classify(error);
+        self.failed.push(TransferFailure {
+            path,
+            kind: failure_kind.name(),
+            error: error.to_string(),
+            failure_kind,
+        });
+    }
+
+    /// Print the summary, turning any failures into an error. The error
+    /// takes the kind of the failures if they all share one.
+    fn report(self, verb: &str, output: Output) -> Result<(), Box<dyn Error>> {
+        match output {
+            Output::Json => print_json(&self)?,
+            Output::Text => {
                 println!(
-                    "{:<35} {:>10} {:<26} {:<16}",
-                    file.filename,
-                    file.size,
-                    file.last_modified,
-                    &file.checksum[..16] // Short checksum for readability
+                    "✓ {} {} file(s) ({} bytes)",
+                    verb, self.transferred, self.bytes
                 );
+                if !self.failed.is_empty() {
+                    println!("✗ {} file(s) failed:", self.failed.len());
+                    for failure in &self.failed {
+                        println!("  {}: {}", failure.path, failure.error);
+                    }
+                }
             }
         }
-        Ok(())
+        let Some(first) = self.failed.first() else {
+            return Ok(());
+        };
+        let kind = if self
+            .failed
+            .iter()
+            .all(|f| f.failure_kind == first.failure_kind)
+        {
+            first.failure_kind
+        } else {
+            FailureKind::Other
+        };
+        Err(Failure::new(
+            kind,
+            format!(
+                "{} of {} files failed",
+                self.failed.len(),
+                self.failed.len() + self.transferred
+            ),
+        )
+        .into())
     }
+}
 
-    async fn delete_file(&mut self, remote_name: &str) -> Result<(), Box<dyn Error>> {
-        let mut delete_msg = Message::new_with_auth(
-            Operation::Delete,
-            remote_name.as_bytes().to_vec(),
-            self.auth_token,
-        );
-        delete_msg.set_request_id(self.request_id);
-        self.request_id += 1;
+/// A listing as written by `--output json`
+#[derive(Serialize)]
+struct ListOutput<'a> {
+    path: &'a str,
+    entries: &'a [FileMetadata],
+    total_entries: u64,
+    logical_size: u64,
+    physical_size: u64,
+}
 
-        self.send_message(&delete_msg).await?;
-        let response = self.receive_message().await?;
+/// Write one JSON document to stdout
+fn print_json(value: &impl Serialize) -> Result<(), Box<dyn Error>> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
 
-        if response.status == StatusCode::Success {
-            println!("✓ Deleted '{}'", remote_name);
-            Ok(())
-        } else {
-            Err(format!(
-                "Delete failed: {}",
-                String::from_utf8_lossy(&response.payload)
-            )
-            .into())
-        }
+/// Remote directory for `upload -r` when none is given: the local directory's name
+fn default_remote_dir(local_dir: &str) -> Result<String, Box<dyn Error>> {
+    let canonical = fs::canonicalize(local_dir)?;
+    Ok(canonical
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default())
+}
+
+/// Local directory for `download -r` when none is given
+fn default_local_dir(remote_dir: &str) -> &str {
+    match default_local_path(remote_dir) {
+        "" => ".",
+        name => name,
     }
 }
 
+/// Local name for a download when none is given: the last path component
+fn default_local_path(remote_name: &str) -> &str {
+    remote_name
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or(remote_name)
+}
+
 // PUBLIC API
 
+/// Connect for a one-shot command, announcing it unless the output is JSON
+async fn connect(options: &ConnectOptions) -> Result<Client, Box<dyn Error>> {
+    if options.output == Output::Json {
+        return Client::connect(options).await;
+    }
+    print!("Connecting to {}... ", options.server_addr);
+    std::io::Write::flush(&mut std::io::stdout())?;
+    let client = Client::connect(options).await?;
+    println!("✓\n");
+    Ok(client)
+}
+
 pub async fn upload(
-    server_addr: &str,
+    options: &ConnectOptions,
     local_path: &str,
     remote_name: Option<&str>,
-    password: &str, // NEW PARAMETER
+    recursive: bool,
+    exclude: &[String],
 ) -> Result<(), Box<dyn Error>> {
-    print!("Connecting to {}...  ", server_addr);
-    std::io::Write::flush(&mut std::io::stdout())?;
-    let mut client = Client::connect(server_addr, password).await?; // Pass password
-    println!("✓\n");
+    let mut client = connect(options).await?;
+
+    if recursive {
+        let remote_dir = match remote_name {
+            Some(name) => name.to_string(),
+            None => default_remote_dir(local_path)?,
+        };
+        let summary = client.upload_tree(local_path, &remote_dir, exclude).await?;
+        return summary.report("Uploaded", options.output);
+    }
 
     let filename = remote_name.unwrap_or_else(|| {
         std::path::Path::new(local_path)
@@ -289,56 +1907,99 @@ pub async fn upload(
             .unwrap_or("uploaded_file")
     });
 
-    client.upload_file(local_path, filename).await
+    let bytes = client.upload_file(local_path, filename).await?;
+    if options.output == Output::Json {
+        let mut summary = TransferSummary::new(local_path, filename);
+        summary.succeeded(bytes);
+        summary.report("Uploaded", options.output)?;
+    }
+    Ok(())
 }
 
 pub async fn download(
-    server_addr: &str,
+    options: &ConnectOptions,
     remote_name: &str,
     local_path: Option<&str>,
-    password: &str, // NEW PARAMETER
+    resume: bool,
+    recursive: bool,
+    selector: VersionSelector,
 ) -> Result<(), Box<dyn Error>> {
-    print!("Connecting to {}... ", server_addr);
-    std::io::Write::flush(&mut std::io::stdout())?;
-    let mut client = Client::connect(server_addr, password).await?;
-    println!("✓\n");
+    let mut client = connect(options).await?;
+
+    if recursive {
+        let local_dir = local_path.unwrap_or_else(|| default_local_dir(remote_name));
+        let summary = client.download_tree(remote_name, local_dir, resume).await?;
+        return summary.report("Downloaded", options.output);
+    }
+
+    let output_path = local_path.unwrap_or_else(|| default_local_path(remote_name));
+    let bytes = client
+        .download_file_chunked(remote_name, output_path, resume, selector)
+        .await?;
+    if options.output == Output::Json {
+        let mut summary = TransferSummary::new(remote_name, output_path);
+        summary.succeeded(bytes);
+        summary.report("Downloaded", options.output)?;
+    }
+    Ok(())
+}
 
-    let output_path = local_path.unwrap_or(remote_name);
-    client.download_file_chunked(remote_name, output_path).await
+pub async fn list(
+    options: &ConnectOptions,
+    path: &str,
+    recursive: bool,
+    view: &ListView,
+) -> Result<(), Box<dyn Error>> {
+    let mut client = connect(options).await?;
+    client.list_files(path, recursive, view).await
 }
 
-// List and delete leave unchanged
-pub async fn list(server_addr: &str, password: &str) -> Result<(), Box<dyn Error>> {
-    print!("Connecting to {}... ", server_addr);
-    std::io::Write::flush(&mut std::io::stdout())?;
-    let mut client = Client::connect(server_addr, password).await?;
-    println!("✓\n");
+pub async fn versions(options: &ConnectOptions, remote_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut client = connect(options).await?;
+    client.list_versions(remote_name).await
+}
 
-    client.list_files().await
+pub async fn stat(options: &ConnectOptions, remote_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut client = connect(options).await?;
+    client.stat_file(remote_name).await
 }
 
-pub async fn delete(
-    server_addr: &str,
-    remote_name: &str,
-    password: &str,
+pub async fn move_path(
+    options: &ConnectOptions,
+    from: &str,
+    to: &str,
+    copy: bool,
 ) -> Result<(), Box<dyn Error>> {
-    print!("Connecting to {}... ", server_addr);
-    std::io::Write::flush(&mut std::io::stdout())?;
-    let mut client = Client::connect(server_addr, password).await?;
-    println!("✓\n");
+    let mut client = connect(options).await?;
+    client.move_path(from, to, copy).await
+}
+
+pub async fn mkdir(options: &ConnectOptions, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut client = connect(options).await?;
+    client.make_directory(path).await
+}
 
+pub async fn rmdir(options: &ConnectOptions, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut client = connect(options).await?;
+    client.remove_directory(path).await
+}
+
+pub async fn delete(options: &ConnectOptions, remote_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut client = connect(options).await?;
     client.delete_file(remote_name).await
 }
 
 pub async fn interactive_session(
-    server_addr: &str,
-    password: &str,
+    options: &ConnectOptions,
+    exclude: &[String],
 ) -> Result<(), Box<dyn Error>> {
-    print!("Connecting to {}... ", server_addr);
-    std::io::Write::flush(&mut std::io::stdout())?;
-    let mut client = Client::connect(server_addr, password).await?;
-    println!("✓\n");
-    println!("Connected to {}. Type 'help' for commands or 'exit' to quit.\n", server_addr);
+    let mut client = connect(options).await?;
+    // The session is for a person at a terminal; it always talks in text
+    client.output = Output::Text;
+    println!(
+        "Connected to {}. Type 'help' for commands or 'exit' to quit.\n",
+        options.server_addr
+    );
     loop {
         print!("netbackup> ");
         std::io::Write::flush(&mut std::io::stdout())?;
@@ -357,11 +2018,21 @@ pub async fn interactive_session(
             }
             "help" => {
                 println!("Available commands:");
-                println!("  upload <local_file> [remote_name]   - Upload a file to server");
-                println!("  download <remote_file> [local_path] - Download a file from server");
-                println!("  list                                - List files on remote server");
+                println!("  upload <local_file> [remote_name]   - Upload a file to server (-r uploads a directory tree)");
+                println!("  download <remote_file> [local_path] - Download a file from server (-r for a directory tree, --resume to continue a partial one)");
+                println!("  list [path] [-r] [-l]               - List files on remote server (-r descends into subdirectories, -l shows details)");
                 println!("  llist [directory]                   - List local files (defaults to current directory)");
                 println!("  delete <remote_file>                - Delete a file from server");
+                println!("  versions <remote_file>              - List stored versions of a file");
+                println!("  stat <remote_path>                  - Show details of a remote file or directory");
+                println!("  mv <remote_path> <new_path>         - Rename or move a file or directory on server");
+                println!(
+                    "  cp <remote_path> <new_path>         - Copy a file or directory on server"
+                );
+                println!("  mkdir <remote_dir>                  - Create a directory on server");
+                println!(
+                    "  rmdir <remote_dir>                  - Remove an empty directory from server"
+                );
                 println!("  help                                - Show this help message");
                 println!("  exit | quit                         - Disconnect and quit session");
                 println!();
@@ -370,7 +2041,17 @@ pub async fn interactive_session(
                 println!("  [argument]  - Optional argument");
             }
             "list" => {
-                if let Err(e) = client.list_files().await {
+                let recursive = parts.contains(&"-r");
+                let view = ListView {
+                    long: parts.contains(&"-l"),
+                    ..ListView::default()
+                };
+                let path = parts[1..]
+                    .iter()
+                    .copied()
+                    .find(|p| !p.starts_with('-'))
+                    .unwrap_or("");
+                if let Err(e) = client.list_files(path, recursive, &view).await {
                     eprintln!("Error: {}", e);
                 }
             }
@@ -378,24 +2059,27 @@ pub async fn interactive_session(
                 let dir_path = parts.get(1).copied().unwrap_or(".");
                 match std::fs::read_dir(dir_path) {
                     Ok(entries) => {
-                        let mut files: Vec<_> = entries
-                            .filter_map(|e| e.ok())
-                            .collect();
+                        let mut files: Vec<_> = entries.filter_map(|e| e.ok()).collect();
                         files.sort_by_key(|e| e.file_name());
 
-                        println!("{:<40} {:>12} {}", "NAME", "SIZE", "TYPE");
+                        println!("{:<40} {:>12} TYPE", "NAME", "SIZE");
                         for entry in files {
                             let metadata = entry.metadata().ok();
                             let size = metadata.as_ref().map(|m| m.len()).unwrap_or(0);
-                            let file_type = if metadata.as_ref().map(|m| m.is_dir()).unwrap_or(false) {
-                                "DIR"
-                            } else {
-                                "FILE"
-                            };
+                            let file_type =
+                                if metadata.as_ref().map(|m| m.is_dir()).unwrap_or(false) {
+                                    "DIR"
+                                } else {
+                                    "FILE"
+                                };
                             println!(
                                 "{:<40} {:>12} {}",
                                 entry.file_name().to_string_lossy(),
-                                if file_type == "DIR" { "-".to_string() } else { size.to_string() },
+                                if file_type == "DIR" {
+                                    "-".to_string()
+                                } else {
+                                    size.to_string()
+                                },
                                 file_type
                             );
                         }
@@ -406,13 +2090,33 @@ pub async fn interactive_session(
                 }
             }
             "upload" => {
-                if parts.len() < 2 {
-                    eprintln!("Usage: upload <local_file> [remote_name]");
+                let recursive = parts.contains(&"-r");
+                let args: Vec<&str> = parts[1..].iter().copied().filter(|p| *p != "-r").collect();
+                if args.is_empty() {
+                    eprintln!("Usage: upload [-r] <local_file> [remote_name]");
+                    continue;
+                }
+                let local_file = args[0];
+                let remote_name = args.get(1).copied();
+
+                if recursive {
+                    let remote_dir = match remote_name {
+                        Some(name) => Ok(name.to_string()),
+                        None => default_remote_dir(local_file),
+                    };
+                    let result = match remote_dir {
+                        Ok(dir) => client.upload_tree(local_file, &dir, exclude).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) =
+                        result.and_then(|summary| summary.report("Uploaded", Output::Text))
+                    {
+                        eprintln!("Error: {}", e);
+                    }
+                    println!();
                     continue;
                 }
-                let local_file = parts[1];
-                let remote_name = parts.get(2).copied();
-                
+
                 let filename = remote_name.unwrap_or_else(|| {
                     std::path::Path::new(local_file)
                         .file_name()
@@ -430,9 +2134,46 @@ pub async fn interactive_session(
                     eprintln!("Usage: download <remote_file> [local_path]");
                     continue;
                 }
-                let remote_file = parts[1];
-                let local_path = parts.get(2).copied().unwrap_or(remote_file);
-                if let Err(e) = client.download_file_chunked(remote_file, local_path).await {
+                let resume = parts.contains(&"--resume");
+                let recursive = parts.contains(&"-r");
+                let args: Vec<&str> = parts[1..]
+                    .iter()
+                    .copied()
+                    .filter(|p| *p != "--resume" && *p != "-r")
+                    .collect();
+                if args.is_empty() {
+                    eprintln!("Usage: download [-r] <remote_file> [local_path] [--resume]");
+                    continue;
+                }
+                let remote_file = args[0];
+
+                if recursive {
+                    let local_dir = args
+                        .get(1)
+                        .copied()
+                        .unwrap_or_else(|| default_local_dir(remote_file));
+                    let result = client.download_tree(remote_file, local_dir, resume).await;
+                    if let Err(e) =
+                        result.and_then(|summary| summary.report("Downloaded", Output::Text))
+                    {
+                        eprintln!("Error: {}", e);
+                    }
+                    println!();
+                    continue;
+                }
+                let local_path = args
+                    .get(1)
+                    .copied()
+                    .unwrap_or_else(|| default_local_path(remote_file));
+                if let Err(e) = client
+                    .download_file_chunked(
+                        remote_file,
+                        local_path,
+                        resume,
+                        VersionSelector::Current,
+                    )
+                    .await
+                {
                     eprintln!("Error: {}", e);
                 } else {
                     println!("✓ Downloaded '{}' to '{}'", remote_file, local_path);
@@ -448,11 +2189,320 @@ pub async fn interactive_session(
                     eprintln!("Error: {}", e);
                 }
             }
+            "versions" => {
+                if parts.len() < 2 {
+                    eprintln!("Usage: versions <remote_file>");
+                    continue;
+                }
+                if let Err(e) = client.list_versions(parts[1]).await {
+                    eprintln!("Error: {}", e);
+                }
+            }
+            "stat" => {
+                if parts.len() < 2 {
+                    eprintln!("Usage: stat <remote_path>");
+                    continue;
+                }
+                if let Err(e) = client.stat_file(parts[1]).await {
+                    eprintln!("Error: {}", e);
+                }
+            }
+            "mv" | "cp" => {
+                if parts.len() < 3 {
+                    eprintln!("Usage: {} <remote_path> <new_path>", command);
+                    continue;
+                }
+                if let Err(e) = client.move_path(parts[1], parts[2], command == "cp").await {
+                    eprintln!("Error: {}", e);
+                }
+            }
+            "mkdir" => {
+                if parts.len() < 2 {
+                    eprintln!("Usage: mkdir <remote_dir>");
+                    continue;
+                }
+                if let Err(e) = client.make_directory(parts[1]).await {
+                    eprintln!("Error: {}", e);
+                }
+            }
+            "rmdir" => {
+                if parts.len() < 2 {
+                    eprintln!("Usage: rmdir <remote_dir>");
+                    continue;
+                }
+                if let Err(e) = client.remove_directory(parts[1]).await {
+                    eprintln!("Error: {}", e);
+                }
+            }
             _ => {
-                eprintln!("Unknown command: '{}'. Type 'help' for available commands.", command);
+                eprintln!(
+                    "Unknown command: '{}'. Type 'help' for available commands.",
+                    command
+                );
             }
         }
         println!(); // Add spacing between commands
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ignore::IGNORE_FILE;
+    use crate::test_util::TestDir;
+
+    #[test]
+    fn test_walk_local_keeps_relative_paths() {
+        let root = TestDir::new();
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("README"), b"hi").unwrap();
+        fs::write(root.join("src/nested/lib.rs"), b"fn main() {}").unwrap();
+
+        let rules = IgnoreRules::new();
+        let tree = walk_local(&root, &rules).unwrap();
+        assert_eq!(tree.dirs, vec!["empty", "src", "src/nested"]);
+        let files: Vec<_> = tree.files.iter().map(|f| f.relative.as_str()).collect();
+        assert_eq!(files, vec!["README", "src/nested/lib.rs"]);
+        assert_eq!(tree.files[1].size, 12);
+
+        assert!(walk_local(&root.join("README"), &rules).is_err());
+    }
+
+    #[test]
+    fn test_walk_local_applies_ignore_rules() {
+        let root = TestDir::new();
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("README"), b"hi").unwrap();
+        fs::write(root.join("src/nested/lib.rs"), b"fn main() {}").unwrap();
+        fs::write(root.join("src/.lib.rs.swp"), b"junk").unwrap();
+        fs::write(root.join(IGNORE_FILE), "*.swp\nempty/\n").unwrap();
+
+        // Patterns from the config and from the tree's ignore file both apply
+        let rules = IgnoreRules::load(&root, &["nested/".to_string()]).unwrap();
+        let tree = walk_local(&root, &rules).unwrap();
+        assert_eq!(tree.dirs, vec!["src"]);
+        assert_eq!(
+            tree.excluded,
+            vec!["empty", "src/.lib.rs.swp", "src/nested"]
+        );
+        let files: Vec<_> = tree.files.iter().map(|f| f.relative.as_str()).collect();
+        assert_eq!(files, vec![IGNORE_FILE, "README"]);
+    }
+
+    #[test]
+    fn test_walk_local_reads_nested_ignore_files() {
+        let root = TestDir::new();
+        fs::create_dir_all(root.join("web/build")).unwrap();
+        fs::create_dir_all(root.join("api/build")).unwrap();
+        fs::write(root.join(IGNORE_FILE), "*.log\n").unwrap();
+        fs::write(root.join("web").join(IGNORE_FILE), "build/\n!keep.log\n").unwrap();
+        fs::write(root.join("web/keep.log"), b"kept").unwrap();
+        fs::write(root.join("web/debug.log"), b"dropped").unwrap();
+        fs::write(root.join("web/build/app.js"), b"dropped").unwrap();
+        fs::write(root.join("api/build/app.js"), b"kept").unwrap();
+
+        let rules = IgnoreRules::load(&root, &[]).unwrap();
+        let tree = walk_local(&root, &rules).unwrap();
+        assert_eq!(tree.excluded, vec!["web/build", "web/debug.log"]);
+        let files: Vec<_> = tree.files.iter().map(|f| f.relative.as_str()).collect();
+        assert_eq!(
+            files,
+            vec![
+                IGNORE_FILE,
+                "api/build/app.js",
+                "web/.netbackupignore",
+                "web/keep.log"
+            ]
+        );
+    }
+
+    /// A client past the handshake, talking over `stream`
+    fn test_client(stream: tokio::io::DuplexStream, session_key: Option<[u8; 32]>) -> Client {
+        Client {
+            stream: Box::new(stream),
+            session_key,
+            request_id: 1,
+            capabilities: capability::SUPPORTED,
+            hello_transcript: [0; 32],
+            broken: false,
+            chunking: None,
+            crypto: None,
+            output: Output::Json,
+        }
+    }
+
+    async fn read_frame(stream: &mut tokio::io::DuplexStream) -> Vec<u8> {
+        let mut len_bytes = [0u8; 4];
+        stream.read_exact(&mut len_bytes).await.unwrap();
+        let mut frame = len_bytes.to_vec();
+        frame.resize(4 + u32::from_be_bytes(len_bytes) as usize, 0);
+        stream.read_exact(&mut frame[4..]).await.unwrap();
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_replayed_or_reflected_response_rejected() {
+        let key = [5u8; 32];
+
+        // The server's answer to the first request is replayed for the second
+        let (stream, mut server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let frame = read_frame(&mut server).await;
+            let request = Message::from_bytes(frame.len() as u32 - 4, &frame[4..]).unwrap();
+            let mut ok = Message::new_response(
+                request.request_id,
+                request.operation,
+                StatusCode::Success,
+                Vec::new(),
+            );
+            ok.sign(&key, auth::Direction::Response);
+            server.write_all(&ok.to_bytes()).await.unwrap();
+            read_frame(&mut server).await;
+            server.write_all(&ok.to_bytes()).await.unwrap();
+        });
+        let mut client = test_client(stream, Some(key));
+        let response = client.request(Operation::Mkdir, b"a".to_vec()).await;
+        assert_eq!(response.unwrap().status, StatusCode::Success);
+        let err = client
+            .request(Operation::Mkdir, b"b".to_vec())
+            .await
+            .unwrap_err();
+        assert_eq!(classify(err.as_ref()), FailureKind::Integrity);
+        assert!(client.broken);
+
+        // The client's own signed request is reflected back as the response
+        let (stream, mut server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let frame = read_frame(&mut server).await;
+            server.write_all(&frame).await.unwrap();
+        });
+        let mut client = test_client(stream, Some(key));
+        let err = client
+            .request(Operation::Delete, b"a".to_vec())
+            .await
+            .unwrap_err();
+        assert_eq!(classify(err.as_ref()), FailureKind::Integrity);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_response_rejected_before_reading() {
+        let (stream, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            read_frame(&mut server).await;
+            server.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+            // Keep the stream open; the client must not wait for the body
+            std::future::pending::<()>().await;
+        });
+        let mut client = test_client(stream, None);
+        let err = client
+            .request(Operation::List, Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(classify(err.as_ref()), FailureKind::Integrity);
+        assert!(client.broken);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_hash_count_mismatch_is_integrity_failure() {
+        let dir = TestDir::new();
+        fs::create_dir_all(&dir).unwrap();
+        let mut local = fs::File::create(dir.join("partial")).unwrap();
+
+        // The server has hashes for one chunk of a file that needs two
+        let (stream, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let frame = read_frame(&mut server).await;
+            let request = Message::from_bytes(frame.len() as u32 - 4, &frame[4..]).unwrap();
+            let hashes = bincode::serialize(&vec![[0u8; 32]]).unwrap();
+            let response = Message::new_response(
+                request.request_id,
+                request.operation,
+                StatusCode::Success,
+                hashes,
+            );
+            server.write_all(&response.to_bytes()).await.unwrap();
+        });
+        let mut client = test_client(stream, None);
+        let err = client
+            .missing_local_chunks("big.iso", 0, &mut local, CHUNK_SIZE as u64 + 1)
+            .await
+            .unwrap_err();
+        assert_eq!(classify(err.as_ref()), FailureKind::Integrity);
+    }
+
+    #[test]
+    fn test_refused_status_kinds() {
+        for (status, kind) in [
+            (StatusCode::ErrorNotFound, FailureKind::NotFound),
+            (
+                StatusCode::ErrorPermissionDenied,
+                FailureKind::PermissionDenied,
+            ),
+            (StatusCode::ErrorMessageRejected, FailureKind::Integrity),
+            (StatusCode::ErrorAlreadyExists, FailureKind::Other),
+        ] {
+            let response = Message::new_response(1, Operation::List, status, b"no".to_vec());
+            let err = refused("List failed", &response);
+            assert_eq!(classify(err.as_ref()), kind);
+            assert_eq!(err.to_string(), "List failed: no");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tree_upload_stops_on_lost_connection() {
+        let root = TestDir::new();
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.txt"), b"first").unwrap();
+        fs::write(root.join("b.txt"), b"second").unwrap();
+
+        // A client past the handshake whose server has gone away
+        let (stream, server) = tokio::io::duplex(1024);
+        drop(server);
+        let mut client = test_client(stream, None);
+
+        let Err(err) = client.upload_tree(root.to_str().unwrap(), "", &[]).await else {
+            panic!("tree upload kept going without a connection");
+        };
+        assert_eq!(classify(err.as_ref()), FailureKind::Network);
+        assert!(client.broken);
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let expected = 1_714_568_400_000_000; // 2024-05-01 13:00:00 UTC
+        assert_eq!(parse_timestamp("2024-05-01 13:00:00").unwrap(), expected);
+        assert_eq!(parse_timestamp("2024-05-01T13:00:00Z").unwrap(), expected);
+        assert_eq!(
+            parse_timestamp("2024-05-01T15:00:00+02:00").unwrap(),
+            expected
+        );
+        assert_eq!(format_micros(expected), "2024-05-01 13:00:00");
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_missing_chunk_numbers() {
+        assert_eq!(
+            missing_chunk_numbers(vec![0..2, 5..6], 6).unwrap(),
+            vec![0, 1, 5]
+        );
+        assert!(missing_chunk_numbers(Vec::new(), 0).unwrap().is_empty());
+        // Past the end, overlapping, out of order or empty ranges are refused
+        for ranges in [vec![0..7], vec![0..3, 2..4], vec![4..5, 0..1], vec![3..3]] {
+            assert!(missing_chunk_numbers(ranges, 6).is_err());
+        }
+    }
+
+    #[test]
+    fn test_default_names() {
+        assert_eq!(default_local_path("docs/2024/report.pdf"), "report.pdf");
+        assert_eq!(default_local_dir("docs/2024/"), "2024");
+        assert_eq!(default_local_dir(""), ".");
+        assert_eq!(join_remote("", "a/b"), "a/b");
+        assert_eq!(join_remote("backup", "a/b"), "backup/a/b");
+    }
+}
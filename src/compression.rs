@@ -0,0 +1,75 @@
+use std::io::{self, Error, ErrorKind};
+
+/// How the bytes of a chunk are encoded, sent as a flag byte with each chunk
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Raw = 0x00,
+    /// lz4 block, preceded by the decoded length as a little-endian u32
+    Lz4 = 0x01,
+}
+
+impl Encoding {
+    pub fn from_u8(value: u8) -> io::Result<Self> {
+        match value {
+            0x00 => Ok(Encoding::Raw),
+            0x01 => Ok(Encoding::Lz4),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown chunk encoding 0x{:02x}", value),
+            )),
+        }
+    }
+}
+
+/// lz4-compress `data` if that makes it smaller, otherwise keep it raw
+pub fn compress(data: &[u8]) -> (Encoding, Vec<u8>) {
+    let compressed = lz4_flex::compress_prepend_size(data);
+    if compressed.len() < data.len() {
+        (Encoding::Lz4, compressed)
+    } else {
+        (Encoding::Raw, data.to_vec())
+    }
+}
+
+/// Decode chunk bytes, refusing anything that would grow beyond `max_len`
+pub fn decompress(encoding: Encoding, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let too_long = || Error::new(ErrorKind::InvalidData, "Decoded chunk is too large");
+    match encoding {
+        Encoding::Raw if data.len() > max_len => Err(too_long()),
+        Encoding::Raw => Ok(data.to_vec()),
+        Encoding::Lz4 => {
+            // Check the claimed size before lz4_flex allocates for it
+            let len = data
+                .get(..4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Truncated lz4 chunk"))?;
+            if len > max_len {
+                return Err(too_long());
+            }
+            lz4_flex::decompress_size_prepended(data).map_err(|e| {
+                Error::new(ErrorKind::InvalidData, format!("Corrupt lz4 chunk: {}", e))
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressible_and_incompressible_chunks() {
+        let text = b"2024-05-01 13:00:00 INFO request handled\n".repeat(1000);
+        let (encoding, bytes) = compress(&text);
+        assert_eq!(encoding, Encoding::Lz4);
+        assert!(bytes.len() < text.len() / 10);
+        assert_eq!(decompress(encoding, &bytes, text.len()).unwrap(), text);
+        // The claimed size is checked before decoding
+        assert!(decompress(encoding, &bytes, text.len() - 1).is_err());
+
+        let noise = crate::test_util::pseudo_random(4096, 0x9e37_79b9);
+        assert_eq!(compress(&noise), (Encoding::Raw, noise.clone()));
+        assert!(Encoding::from_u8(7).is_err());
+    }
+}
@@ -10,6 +10,36 @@ pub struct ServerConfig {
 
     #[serde(default = "default_storage_path")]
     pub storage_path: String,
+
+    /// Serve over TLS instead of plain TCP
+    #[serde(default)]
+    pub tls_enabled: bool,
+
+    #[serde(default = "default_tls_cert_path")]
+    pub tls_cert_path: String,
+
+    #[serde(default = "default_tls_key_path")]
+    pub tls_key_path: String,
+
+    /// Generate a self-signed cert/key pair on first start if neither file exists
+    #[serde(default)]
+    pub tls_self_signed: bool,
+
+    /// Old versions kept per file when it is overwritten or deleted (0 = unlimited)
+    #[serde(default = "default_max_versions")]
+    pub max_versions: usize,
+
+    /// Drop old versions superseded more than this many days ago (0 = keep forever)
+    #[serde(default)]
+    pub version_retention_days: u64,
+
+    /// Store new chunks lz4-compressed when that makes them smaller
+    #[serde(default)]
+    pub compress_chunks: bool,
+
+    /// Largest file a client may upload, in bytes
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: u64,
 }
 
 /// Client-specific configuration
@@ -17,6 +47,58 @@ pub struct ServerConfig {
 pub struct ClientConfig {
     #[serde(default = "default_server_address")]
     pub default_server: String,
+
+    /// Account to log in as on servers with a user database
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// Connect over TLS instead of plain TCP
+    #[serde(default)]
+    pub tls_enabled: bool,
+
+    /// PEM bundle of CA certificates to trust
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_ca_path: Option<String>,
+
+    /// Pinned SHA-256 fingerprint of the server certificate (takes precedence over tls_ca_path)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_fingerprint: Option<String>,
+
+    /// Name to verify the certificate against (defaults to the host part of the server address)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_server_name: Option<String>,
+
+    /// gitignore-style patterns skipped by recursive uploads, applied before
+    /// the tree's own .netbackupignore
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// How uploads are split into chunks
+    #[serde(default)]
+    pub chunking: ChunkingConfig,
+
+    /// Keyfile for client-side encryption (see `netbackup keygen`); when set,
+    /// file contents and names are encrypted before they leave the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyfile: Option<String>,
+}
+
+/// Upload chunking, under `[client.chunking]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkingConfig {
+    /// Cut chunks where the content says rather than every 64KB, so data
+    /// shifted by an insertion still deduplicates against earlier uploads
+    #[serde(default)]
+    pub content_defined: bool,
+
+    #[serde(default = "default_chunk_min_size")]
+    pub min_size: usize,
+
+    #[serde(default = "default_chunk_avg_size")]
+    pub avg_size: usize,
+
+    #[serde(default = "default_chunk_max_size")]
+    pub max_size: usize,
 }
 
 /// Authentication configuration
@@ -48,6 +130,34 @@ fn default_storage_path() -> String {
     "./storage_data".to_string()
 }
 
+fn default_tls_cert_path() -> String {
+    "./netbackup-cert.pem".to_string()
+}
+
+fn default_tls_key_path() -> String {
+    "./netbackup-key.pem".to_string()
+}
+
+fn default_max_versions() -> usize {
+    10
+}
+
+fn default_max_upload_size() -> u64 {
+    1 << 40
+}
+
+fn default_chunk_min_size() -> usize {
+    16 * 1024
+}
+
+fn default_chunk_avg_size() -> usize {
+    64 * 1024
+}
+
+fn default_chunk_max_size() -> usize {
+    256 * 1024
+}
+
 fn default_server_address() -> String {
     "127.0.0.1:8080".to_string()
 }
@@ -61,6 +171,14 @@ impl Default for ServerConfig {
         Self {
             bind_address: default_bind_address(),
             storage_path: default_storage_path(),
+            tls_enabled: false,
+            tls_cert_path: default_tls_cert_path(),
+            tls_key_path: default_tls_key_path(),
+            tls_self_signed: false,
+            max_versions: default_max_versions(),
+            version_retention_days: 0,
+            compress_chunks: false,
+            max_upload_size: default_max_upload_size(),
         }
     }
 }
@@ -69,6 +187,25 @@ impl Default for ClientConfig {
     fn default() -> Self {
         Self {
             default_server: default_server_address(),
+            username: None,
+            tls_enabled: false,
+            tls_ca_path: None,
+            tls_fingerprint: None,
+            tls_server_name: None,
+            exclude: Vec::new(),
+            chunking: ChunkingConfig::default(),
+            keyfile: None,
+        }
+    }
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self {
+            content_defined: false,
+            min_size: default_chunk_min_size(),
+            avg_size: default_chunk_avg_size(),
+            max_size: default_chunk_max_size(),
         }
     }
 }
@@ -82,13 +219,16 @@ impl Default for AuthConfig {
 }
 
 impl Config {
-    /// Load configuration from file, falling back to defaults if not found
-    pub fn load() -> Self {
+    /// Load configuration from file, falling back to defaults if not found.
+    /// `quiet` leaves out the notice of which file was used; warnings still show.
+    pub fn load(quiet: bool) -> Self {
         let config_paths = Self::get_config_paths();
 
         for path in config_paths {
             if path.exists() {
-                println!("[CONFIG] Loading from:  {}", path.display());
+                if !quiet {
+                    eprintln!("[CONFIG] Loading from:  {}", path.display());
+                }
                 match Self::load_from_path(&path) {
                     Ok(config) => return config,
                     Err(e) => {
@@ -103,7 +243,9 @@ impl Config {
             }
         }
 
-        println!("[CONFIG] No config file found, using defaults");
+        if !quiet {
+            eprintln!("[CONFIG] No config file found, using defaults");
+        }
         Config::default()
     }
 
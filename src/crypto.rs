@@ -0,0 +1,486 @@
+use crate::hex;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Start of every encrypted file: magic followed by the key ID
+const MAGIC: &[u8; 8] = b"NBCRYPT1";
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN;
+const KEY_ID_LEN: usize = 16;
+
+/// Each record is `[plaintext length: u32][nonce][ciphertext + tag]`; the
+/// top bit of the length marks the trailer
+const RECORD_OVERHEAD: usize = 4 + NONCE_LEN + TAG_LEN;
+const TRAILER_FLAG: u32 = 1 << 31;
+/// The trailer seals the plaintext size and SHA-256
+const TRAILER_LEN: usize = RECORD_OVERHEAD + 8 + 32;
+
+/// Most bytes encryption adds to a single upload chunk
+pub const MAX_EXPANSION: usize = HEADER_LEN + RECORD_OVERHEAD + TRAILER_LEN;
+
+/// PBKDF2-HMAC-SHA256 rounds for new keyfiles
+const KEYFILE_ITERATIONS: u32 = 600_000;
+
+/// Longest name component the server's filesystem is expected to accept
+const MAX_NAME_LEN: usize = 255;
+
+/// On-disk keyfile: a random master key sealed under a passphrase
+#[derive(Serialize, Deserialize)]
+struct Keyfile {
+    version: u32,
+    /// Hex-encoded PBKDF2 salt
+    salt: String,
+    iterations: u32,
+    /// Hex-encoded nonce and sealed master key
+    key: String,
+}
+
+/// Create a keyfile holding a new random master key. Never overwrites an
+/// existing file: losing a key makes everything encrypted with it unreadable.
+pub fn create_keyfile(path: &Path, passphrase: &str) -> Result<(), Box<dyn Error>> {
+    create_keyfile_with(path, passphrase, KEYFILE_ITERATIONS)
+}
+
+fn create_keyfile_with(
+    path: &Path,
+    passphrase: &str,
+    iterations: u32,
+) -> Result<(), Box<dyn Error>> {
+    let mut master = [0u8; 32];
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut master);
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let wrapping = ChaCha20Poly1305::new(&passphrase_key(passphrase, &salt, iterations));
+    let sealed = wrapping
+        .encrypt(Nonce::from_slice(&nonce), &master[..])
+        .map_err(|_| "Failed to seal master key")?;
+
+    let keyfile = Keyfile {
+        version: 1,
+        salt: hex::encode(&salt),
+        iterations,
+        key: hex::encode(&[&nonce[..], &sealed].concat()),
+    };
+    let contents = format!(
+        "# netbackup keyfile. Without this file and its passphrase, encrypted backups cannot be read.\n{}",
+        toml::to_string(&keyfile)?
+    );
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn passphrase_key(passphrase: &str, salt: &[u8], iterations: u32) -> Key {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+    key.into()
+}
+
+/// Keys for client-side encryption, derived from the master key in a keyfile.
+/// Encryption is deterministic: the nonce is a keyed hash of the plaintext,
+/// so identical chunks encrypt identically and still deduplicate on the server.
+#[derive(Clone)]
+pub struct Crypto {
+    data: ChaCha20Poly1305,
+    names: ChaCha20Poly1305,
+    nonce_key: [u8; 32],
+    key_id: [u8; KEY_ID_LEN],
+}
+
+impl Crypto {
+    /// Unlock a keyfile with its passphrase
+    pub fn from_keyfile(path: &Path, passphrase: &str) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read keyfile {}: {}", path.display(), e))?;
+        let keyfile: Keyfile = toml::from_str(&contents)?;
+        if keyfile.version != 1 {
+            return Err(format!("Unsupported keyfile version {}", keyfile.version).into());
+        }
+        let salt = hex::decode(&keyfile.salt).ok_or("Malformed keyfile salt")?;
+        let sealed = hex::decode(&keyfile.key).ok_or("Malformed keyfile key")?;
+        if sealed.len() != NONCE_LEN + 32 + TAG_LEN {
+            return Err("Malformed keyfile key".into());
+        }
+
+        let wrapping =
+            ChaCha20Poly1305::new(&passphrase_key(passphrase, &salt, keyfile.iterations));
+        let master = wrapping
+            .decrypt(
+                Nonce::from_slice(&sealed[..NONCE_LEN]),
+                &sealed[NONCE_LEN..],
+            )
+            .map_err(|_| "Wrong passphrase for keyfile")?;
+        Ok(Self::from_master(&master))
+    }
+
+    fn from_master(master: &[u8]) -> Self {
+        let subkey = |label: &[u8]| -> [u8; 32] {
+            let mut mac = <HmacSha256 as Mac>::new_from_slice(master)
+                .expect("HMAC accepts keys of any length");
+            mac.update(label);
+            mac.finalize().into_bytes().into()
+        };
+        let mut key_id = [0u8; KEY_ID_LEN];
+        key_id.copy_from_slice(&subkey(b"netbackup-key-id")[..KEY_ID_LEN]);
+        Self {
+            data: ChaCha20Poly1305::new(&subkey(b"netbackup-data-key").into()),
+            names: ChaCha20Poly1305::new(&subkey(b"netbackup-name-key").into()),
+            nonce_key: subkey(b"netbackup-nonce-key"),
+            key_id,
+        }
+    }
+
+    fn nonce(&self, aad: &[u8], plaintext: &[u8]) -> [u8; NONCE_LEN] {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.nonce_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(&(aad.len() as u64).to_be_bytes());
+        mac.update(aad);
+        mac.update(plaintext);
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&mac.finalize().into_bytes()[..NONCE_LEN]);
+        nonce
+    }
+
+    fn seal(&self, flag: u32, aad: &[u8], plaintext: &[u8], out: &mut Vec<u8>) {
+        let nonce = self.nonce(aad, plaintext);
+        let sealed = self
+            .data
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("ChaCha20-Poly1305 encrypts any length we use");
+        out.extend_from_slice(&(plaintext.len() as u32 | flag).to_be_bytes());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+    }
+
+    /// Upload chunk `index` of `count` for one file: the sealed plaintext,
+    /// preceded by the header in the first chunk and followed by the trailer
+    /// in the last. `path` and `size`/`checksum` (of the whole plaintext) are
+    /// only used for the trailer.
+    pub fn encrypt_chunk(
+        &self,
+        plaintext: &[u8],
+        index: usize,
+        count: usize,
+        path: &str,
+        size: u64,
+        checksum: &[u8; 32],
+    ) -> Vec<u8> {
+        let mut out = Vec::with_capacity(plaintext.len() + MAX_EXPANSION);
+        if index == 0 {
+            out.extend_from_slice(MAGIC);
+            out.extend_from_slice(&self.key_id);
+        }
+        self.seal(0, b"chunk", plaintext, &mut out);
+        if index + 1 == count {
+            let trailer = [&size.to_be_bytes()[..], checksum].concat();
+            self.seal(TRAILER_FLAG, &trailer_aad(path), &trailer, &mut out);
+        }
+        out
+    }
+
+    /// Decrypt a file written by `encrypt_chunk`, checking every record and
+    /// that the result is the whole file that was uploaded as `path`
+    pub fn decrypt_file<R: Read, W: Write>(
+        &self,
+        mut input: R,
+        mut output: W,
+        path: &str,
+    ) -> Result<u64, Box<dyn Error>> {
+        let mut header = [0u8; HEADER_LEN];
+        input
+            .read_exact(&mut header)
+            .map_err(|_| "Not an encrypted netbackup file")?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err("Not an encrypted netbackup file".into());
+        }
+        if header[MAGIC.len()..] != self.key_id {
+            return Err("File was encrypted with a different key".into());
+        }
+
+        let mut hasher = Sha256::new();
+        let mut written = 0u64;
+        loop {
+            let mut prefix = [0u8; 4 + NONCE_LEN];
+            input
+                .read_exact(&mut prefix)
+                .map_err(|_| "Encrypted file is truncated")?;
+            let word = u32::from_be_bytes(prefix[..4].try_into().unwrap());
+            let is_trailer = word & TRAILER_FLAG != 0;
+            let len = (word & !TRAILER_FLAG) as usize;
+            if len > crate::chunker::MAX_CHUNK_SIZE {
+                return Err("Encrypted record is too large".into());
+            }
+            let mut sealed = vec![0u8; len + TAG_LEN];
+            input
+                .read_exact(&mut sealed)
+                .map_err(|_| "Encrypted file is truncated")?;
+
+            let aad = if is_trailer {
+                trailer_aad(path)
+            } else {
+                b"chunk".to_vec()
+            };
+            let plaintext = self
+                .data
+                .decrypt(
+                    Nonce::from_slice(&prefix[4..]),
+                    Payload {
+                        msg: &sealed,
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| "Encrypted data failed authentication")?;
+
+            if !is_trailer {
+                hasher.update(&plaintext);
+                output.write_all(&plaintext)?;
+                written += plaintext.len() as u64;
+                continue;
+            }
+
+            let size = plaintext
+                .get(..8)
+                .map(|b| u64::from_be_bytes(b.try_into().unwrap()));
+            let checksum: [u8; 32] = hasher.finalize().into();
+            if plaintext.len() != 40 || size != Some(written) || plaintext[8..] != checksum {
+                return Err("Decrypted contents do not match the uploaded file".into());
+            }
+            if input.read(&mut [0u8; 1])? != 0 {
+                return Err("Unexpected data after end of encrypted file".into());
+            }
+            output.flush()?;
+            return Ok(written);
+        }
+    }
+
+    /// Encrypt each component of a normalised path
+    pub fn encrypt_path(&self, path: &str) -> io::Result<String> {
+        let components: io::Result<Vec<String>> = path
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .map(|component| {
+                let nonce = self.nonce(b"name", component.as_bytes());
+                let sealed = self
+                    .names
+                    .encrypt(Nonce::from_slice(&nonce), component.as_bytes())
+                    .expect("ChaCha20-Poly1305 encrypts any length we use");
+                let encoded = base32_encode(&[&nonce[..], &sealed].concat());
+                if encoded.len() > MAX_NAME_LEN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Name '{}' is too long to encrypt", component),
+                    ));
+                }
+                Ok(encoded)
+            })
+            .collect();
+        Ok(components?.join("/"))
+    }
+
+    /// Reverse `encrypt_path`; None if any component was not encrypted with this key
+    pub fn decrypt_path(&self, path: &str) -> Option<String> {
+        let components: Option<Vec<String>> = path
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .map(|component| {
+                let bytes = base32_decode(component)?;
+                if bytes.len() < NONCE_LEN + TAG_LEN {
+                    return None;
+                }
+                let plain = self
+                    .names
+                    .decrypt(Nonce::from_slice(&bytes[..NONCE_LEN]), &bytes[NONCE_LEN..])
+                    .ok()?;
+                String::from_utf8(plain).ok()
+            })
+            .collect();
+        components.map(|c| c.join("/"))
+    }
+}
+
+/// The trailer is bound to the file's path, so the server cannot swap files
+fn trailer_aad(path: &str) -> Vec<u8> {
+    [&b"trailer:"[..], path.as_bytes()].concat()
+}
+
+/// Length of the encrypted form of a chunk, see `Crypto::encrypt_chunk`
+pub fn encrypted_len(plain_len: usize, index: usize, count: usize) -> usize {
+    let mut len = plain_len + RECORD_OVERHEAD;
+    if index == 0 {
+        len += HEADER_LEN;
+    }
+    if index + 1 == count {
+        len += TRAILER_LEN;
+    }
+    len
+}
+
+// Lowercase base32, so encrypted names survive case-insensitive filesystems
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = BASE32.iter().position(|&b| b == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_crypto(seed: u8) -> Crypto {
+        Crypto::from_master(&[seed; 32])
+    }
+
+    fn encrypt_file(crypto: &Crypto, data: &[u8], chunk: usize, path: &str) -> Vec<u8> {
+        let checksum: [u8; 32] = Sha256::digest(data).into();
+        let chunks: Vec<&[u8]> = if data.is_empty() {
+            vec![&[]]
+        } else {
+            data.chunks(chunk).collect()
+        };
+        let mut out = Vec::new();
+        for (i, c) in chunks.iter().enumerate() {
+            let sealed =
+                crypto.encrypt_chunk(c, i, chunks.len(), path, data.len() as u64, &checksum);
+            assert_eq!(sealed.len(), encrypted_len(c.len(), i, chunks.len()));
+            out.extend(sealed);
+        }
+        out
+    }
+
+    #[test]
+    fn test_chunks_roundtrip_and_are_deterministic() {
+        let crypto = test_crypto(1);
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let sealed = encrypt_file(&crypto, &data, 1024, "docs/a.txt");
+        assert!(!sealed.windows(64).any(|w| data.windows(64).any(|d| d == w)));
+        assert_eq!(sealed, encrypt_file(&crypto, &data, 1024, "docs/a.txt"));
+
+        let mut out = Vec::new();
+        assert_eq!(
+            crypto
+                .decrypt_file(&sealed[..], &mut out, "docs/a.txt")
+                .unwrap(),
+            5000
+        );
+        assert_eq!(out, data);
+
+        let mut empty = Vec::new();
+        let sealed_empty = encrypt_file(&crypto, b"", 1024, "e");
+        crypto
+            .decrypt_file(&sealed_empty[..], &mut empty, "e")
+            .unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let crypto = test_crypto(1);
+        let data = vec![7u8; 3000];
+        let sealed = encrypt_file(&crypto, &data, 1024, "a.bin");
+        let decrypt = |bytes: &[u8], path: &str| crypto.decrypt_file(bytes, io::sink(), path);
+
+        let mut flipped = sealed.clone();
+        flipped[HEADER_LEN + 40] ^= 1;
+        assert!(decrypt(&flipped, "a.bin").is_err());
+        // Trailer is bound to the name
+        assert!(decrypt(&sealed, "b.bin").is_err());
+        // Dropping a record in the middle breaks the trailer checksum
+        let record = 1024 + RECORD_OVERHEAD;
+        let dropped = [&sealed[..HEADER_LEN], &sealed[HEADER_LEN + record..]].concat();
+        assert!(decrypt(&dropped, "a.bin").is_err());
+        assert!(decrypt(&sealed[..sealed.len() - 1], "a.bin").is_err());
+        assert!(test_crypto(2)
+            .decrypt_file(&sealed[..], io::sink(), "a.bin")
+            .is_err());
+    }
+
+    #[test]
+    fn test_paths_roundtrip() {
+        let crypto = test_crypto(1);
+        let encrypted = crypto.encrypt_path("photos/2024/IMG 1.jpg").unwrap();
+        assert_eq!(encrypted.split('/').count(), 3);
+        assert!(encrypted.bytes().all(|b| b == b'/' || BASE32.contains(&b)));
+        assert_eq!(
+            encrypted.split('/').next(),
+            crypto.encrypt_path("photos").unwrap().split('/').next()
+        );
+        assert_eq!(
+            crypto.decrypt_path(&encrypted).unwrap(),
+            "photos/2024/IMG 1.jpg"
+        );
+        assert!(test_crypto(2).decrypt_path(&encrypted).is_none());
+        assert!(crypto.decrypt_path("plain.txt").is_none());
+        assert!(crypto.encrypt_path(&"x".repeat(200)).is_err());
+    }
+
+    #[test]
+    fn test_keyfile_requires_passphrase() {
+        let dir = crate::test_util::TestDir::new();
+        fs::create_dir_all(&*dir).unwrap();
+        let path = dir.join("netbackup.key");
+        create_keyfile_with(&path, "correct horse", 1000).unwrap();
+        assert!(create_keyfile_with(&path, "other", 1000).is_err());
+
+        let a = Crypto::from_keyfile(&path, "correct horse").unwrap();
+        let b = Crypto::from_keyfile(&path, "correct horse").unwrap();
+        assert_eq!(a.key_id, b.key_id);
+        assert!(Crypto::from_keyfile(&path, "wrong").is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+
+/// Smallest and largest block size a client may ask signatures for
+pub const MIN_BLOCK_SIZE: u32 = 256;
+pub const MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+
+/// rsync-style signature of one block of the server's copy: a cheap rolling
+/// checksum to find candidates and a SHA-256 to confirm them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: [u8; 32],
+}
+
+impl BlockSignature {
+    pub fn of(block: &[u8]) -> Self {
+        Self {
+            weak: Rolling::new(block).digest(),
+            strong: Sha256::digest(block).into(),
+        }
+    }
+}
+
+/// One step of rebuilding data from the server's copy
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DeltaInstruction {
+    /// Bytes taken from the base file
+    Copy { offset: u64, len: u32 },
+    /// Bytes sent by the client
+    Literal(Vec<u8>),
+}
+
+/// Adler-style checksum that can slide over the data one byte at a time
+pub struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    pub fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a, b, len }
+    }
+
+    /// Slide the window one byte: `out` leaves at the front, `in_` enters at the back
+    pub fn roll(&mut self, out: u8, in_: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(in_ as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    pub fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// The server's block signatures, indexed for lookup by weak checksum
+pub struct SignatureIndex {
+    block_size: usize,
+    by_weak: HashMap<u32, Vec<(u32, [u8; 32])>>,
+}
+
+impl SignatureIndex {
+    pub fn new(block_size: usize, signatures: &[BlockSignature]) -> Self {
+        let mut by_weak: HashMap<u32, Vec<(u32, [u8; 32])>> = HashMap::new();
+        for (index, sig) in signatures.iter().enumerate() {
+            by_weak
+                .entry(sig.weak)
+                .or_default()
+                .push((index as u32, sig.strong));
+        }
+        Self {
+            block_size,
+            by_weak,
+        }
+    }
+
+    /// Index of a server block with exactly these contents
+    fn find(&self, weak: u32, block: &[u8]) -> Option<u32> {
+        let candidates = self.by_weak.get(&weak)?;
+        let strong: [u8; 32] = Sha256::digest(block).into();
+        candidates
+            .iter()
+            .find(|(_, s)| *s == strong)
+            .map(|(index, _)| *index)
+    }
+
+    /// Express `data` as copies of server blocks and literal bytes. Only whole
+    /// blocks are matched; the short final block of the server's copy never is.
+    pub fn delta(&self, data: &[u8]) -> Vec<DeltaInstruction> {
+        let bs = self.block_size;
+        let mut instructions = Vec::new();
+        let mut literal_start = 0;
+        let mut i = 0;
+        let mut rolling = (data.len() >= bs).then(|| Rolling::new(&data[..bs]));
+
+        while let Some(window) = rolling.as_mut() {
+            if let Some(block) = self.find(window.digest(), &data[i..i + bs]) {
+                push_literal(&mut instructions, &data[literal_start..i]);
+                push_copy(&mut instructions, block as u64 * bs as u64, bs as u32);
+                i += bs;
+                literal_start = i;
+                rolling = (i + bs <= data.len()).then(|| Rolling::new(&data[i..i + bs]));
+            } else if i + bs < data.len() {
+                window.roll(data[i], data[i + bs]);
+                i += 1;
+            } else {
+                break;
+            }
+        }
+        push_literal(&mut instructions, &data[literal_start..]);
+        instructions
+    }
+}
+
+fn push_literal(instructions: &mut Vec<DeltaInstruction>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        instructions.push(DeltaInstruction::Literal(bytes.to_vec()));
+    }
+}
+
+/// Append a copy, merging it into the previous one when they are contiguous
+fn push_copy(instructions: &mut Vec<DeltaInstruction>, offset: u64, len: u32) {
+    if let Some(DeltaInstruction::Copy {
+        offset: prev_offset,
+        len: prev_len,
+    }) = instructions.last_mut()
+    {
+        if *prev_offset + *prev_len as u64 == offset {
+            *prev_len += len;
+            return;
+        }
+    }
+    instructions.push(DeltaInstruction::Copy { offset, len });
+}
+
+/// Bytes a delta sends literally
+pub fn literal_len(instructions: &[DeltaInstruction]) -> usize {
+    instructions
+        .iter()
+        .map(|instruction| match instruction {
+            DeltaInstruction::Literal(bytes) => bytes.len(),
+            DeltaInstruction::Copy { .. } => 0,
+        })
+        .sum()
+}
+
+/// Rebuild data from a delta. `read_base(offset, len)` returns bytes of the
+/// base file; the result may not grow beyond `max_len`. Copies are never
+/// shorter than a block, which also bounds how many instructions can fit.
+pub fn apply<F>(
+    instructions: &[DeltaInstruction],
+    max_len: usize,
+    mut read_base: F,
+) -> io::Result<Vec<u8>>
+where
+    F: FnMut(u64, usize) -> io::Result<Vec<u8>>,
+{
+    let too_long = || Error::new(ErrorKind::InvalidInput, "Delta is longer than its chunk");
+    let mut data = Vec::new();
+    for instruction in instructions {
+        match instruction {
+            DeltaInstruction::Copy { offset, len } => {
+                let len = *len as usize;
+                if len < MIN_BLOCK_SIZE as usize {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "Delta copy is shorter than a block",
+                    ));
+                }
+                if data.len() + len > max_len {
+                    return Err(too_long());
+                }
+                let bytes = read_base(*offset, len)?;
+                if bytes.len() != len {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "Delta copies past the end of the base file",
+                    ));
+                }
+                data.extend_from_slice(&bytes);
+            }
+            DeltaInstruction::Literal(bytes) => {
+                if data.len() + bytes.len() > max_len {
+                    return Err(too_long());
+                }
+                data.extend_from_slice(bytes);
+            }
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::pseudo_random;
+
+    fn index_for(base: &[u8], block_size: usize) -> SignatureIndex {
+        let sigs: Vec<_> = base
+            .chunks(block_size)
+            .filter(|block| block.len() == block_size)
+            .map(BlockSignature::of)
+            .collect();
+        SignatureIndex::new(block_size, &sigs)
+    }
+
+    fn rebuild(base: &[u8], instructions: &[DeltaInstruction]) -> Vec<u8> {
+        apply(instructions, usize::MAX, |offset, len| {
+            let start = offset as usize;
+            Ok(base[start..(start + len).min(base.len())].to_vec())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rolling_matches_fresh_checksum() {
+        let data = pseudo_random(300, 7);
+        let mut rolling = Rolling::new(&data[..64]);
+        for i in 0..data.len() - 64 {
+            assert_eq!(rolling.digest(), Rolling::new(&data[i..i + 64]).digest());
+            rolling.roll(data[i], data[i + 64]);
+        }
+    }
+
+    #[test]
+    fn test_delta_of_edited_data() {
+        let base = pseudo_random(64 * 1024, 1);
+        let mut edited = base.clone();
+        edited[10_000] ^= 1; // in-place change
+        edited.splice(30_000..30_000, *b"inserted"); // shifts everything after
+        edited.truncate(60_000);
+
+        let index = index_for(&base, 1024);
+        let delta = index.delta(&edited);
+        assert_eq!(rebuild(&base, &delta), edited);
+        // Only the blocks around the two edits go over as literals
+        assert!(literal_len(&delta) <= 4 * 1024, "{}", literal_len(&delta));
+
+        // Unrelated data is sent whole
+        let other = pseudo_random(5000, 99);
+        let delta = index.delta(&other);
+        assert_eq!(delta, vec![DeltaInstruction::Literal(other.clone())]);
+    }
+
+    #[test]
+    fn test_apply_rejects_oversized_delta() {
+        let base = [1u8; 100];
+        let copy = [DeltaInstruction::Copy { offset: 0, len: 80 }];
+        assert!(apply(&copy, 50, |o, l| Ok(
+            base[o as usize..o as usize + l].to_vec()
+        ))
+        .is_err());
+        let past_end = [DeltaInstruction::Copy {
+            offset: 90,
+            len: 20,
+        }];
+        assert!(apply(&past_end, 50, |o, _| Ok(base[o as usize..].to_vec())).is_err());
+
+        // Many tiny copies would each cost a base read
+        let base = [1u8; 1000];
+        let tiny = vec![DeltaInstruction::Copy { offset: 0, len: 1 }; 10];
+        assert!(apply(&tiny, 1000, |o, l| Ok(
+            base[o as usize..o as usize + l].to_vec()
+        ))
+        .is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Broad class of a failed command, each with its own process exit code so
+/// scripts can react without parsing messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// Anything not covered below
+    Other,
+    /// Wrong credentials, or the server could not prove it knows them
+    Auth,
+    /// The account's role does not allow the operation
+    PermissionDenied,
+    /// The file or directory does not exist, here or on the server
+    NotFound,
+    /// Data failed a checksum, MAC or decryption check
+    Integrity,
+    /// The server could not be reached or the connection broke
+    Network,
+}
+
+impl FailureKind {
+    /// Exit code for the process; 2 is left to usage errors reported by clap
+    pub fn exit_code(self) -> i32 {
+        match self {
+            FailureKind::Other => 1,
+            FailureKind::Auth => 3,
+            FailureKind::PermissionDenied => 4,
+            FailureKind::NotFound => 5,
+            FailureKind::Integrity => 6,
+            FailureKind::Network => 7,
+        }
+    }
+
+    /// Name used for the kind in JSON output
+    pub fn name(self) -> &'static str {
+        match self {
+            FailureKind::Other => "error",
+            FailureKind::Auth => "auth",
+            FailureKind::PermissionDenied => "permission_denied",
+            FailureKind::NotFound => "not_found",
+            FailureKind::Integrity => "integrity",
+            FailureKind::Network => "network",
+        }
+    }
+}
+
+/// An error message tagged with the kind of failure it reports
+#[derive(Debug)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(kind: FailureKind, message: impl Into<String>) -> Self {
+        Failure {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// Prefix an error's message with what was being attempted, keeping
+    /// the kind it would have been classified as
+    pub fn context(what: &str, error: &(dyn Error + 'static)) -> Self {
+        Failure::new(classify(error), format!("{}: {}", what, error))
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Failure {}
+
+/// Work out the kind of an error that ended a command. Errors not tagged
+/// with a `Failure` are judged by their I/O error kind, if they have one.
+pub fn classify(error: &(dyn Error + 'static)) -> FailureKind {
+    if let Some(failure) = error.downcast_ref::<Failure>() {
+        return failure.kind;
+    }
+    let Some(io_error) = error.downcast_ref::<io::Error>() else {
+        return FailureKind::Other;
+    };
+    match io_error.kind() {
+        io::ErrorKind::NotFound => FailureKind::NotFound,
+        io::ErrorKind::PermissionDenied => FailureKind::PermissionDenied,
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::AddrNotAvailable
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::UnexpectedEof => FailureKind::Network,
+        _ => FailureKind::Other,
+    }
+}
+
+/// What a failed command prints to stderr under `--output json`
+pub fn error_document(error: &(dyn Error + 'static)) -> serde_json::Value {
+    let kind = classify(error);
+    serde_json::json!({
+        "error": {
+            "kind": kind.name(),
+            "message": error.to_string(),
+            "exit_code": kind.exit_code(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let tagged: Box<dyn Error> =
+            Failure::new(FailureKind::Integrity, "Checksum mismatch").into();
+        assert_eq!(classify(tagged.as_ref()), FailureKind::Integrity);
+        assert_eq!(tagged.to_string(), "Checksum mismatch");
+
+        let refused: Box<dyn Error> = io::Error::from(io::ErrorKind::ConnectionRefused).into();
+        assert_eq!(classify(refused.as_ref()), FailureKind::Network);
+        let missing: Box<dyn Error> = io::Error::from(io::ErrorKind::NotFound).into();
+        assert_eq!(classify(missing.as_ref()), FailureKind::NotFound);
+        let plain: Box<dyn Error> = "Passphrases do not match".into();
+        assert_eq!(classify(plain.as_ref()), FailureKind::Other);
+
+        // Every kind has its own exit code
+        let kinds = [
+            FailureKind::Other,
+            FailureKind::Auth,
+            FailureKind::PermissionDenied,
+            FailureKind::NotFound,
+            FailureKind::Integrity,
+            FailureKind::Network,
+        ];
+        let mut codes: Vec<i32> = kinds.iter().map(|k| k.exit_code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), kinds.len());
+    }
+
+    #[test]
+    fn test_config_load_failures_keep_their_kind() {
+        let dir = crate::test_util::TestDir::new();
+        std::fs::create_dir_all(&*dir).unwrap();
+        let load = |path: std::path::PathBuf| {
+            let e = crate::config::Config::load_from_path(&path).unwrap_err();
+            Failure::context("Failed to load config", e.as_ref())
+        };
+
+        let missing = load(dir.join("missing.toml"));
+        assert_eq!(missing.kind, FailureKind::NotFound);
+        let document = error_document(&missing);
+        assert_eq!(document["error"]["kind"], "not_found");
+        assert_eq!(document["error"]["exit_code"], 5);
+
+        let unparsable = dir.join("bad.toml");
+        std::fs::write(&unparsable, "[server\n").unwrap();
+        assert_eq!(load(unparsable).kind, FailureKind::Other);
+    }
+}
@@ -0,0 +1,30 @@
+/// Lowercase hex encoding of `bytes`
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Bytes of a hex string in either case; None if it is not valid hex
+pub fn decode(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(encode(&[0x00, 0xab, 0x7f]), "00ab7f");
+        assert_eq!(decode("00AB7f").unwrap(), vec![0x00, 0xab, 0x7f]);
+        assert_eq!(decode("").unwrap(), Vec::<u8>::new());
+        assert!(decode("abc").is_none());
+        assert!(decode("zz").is_none());
+        assert!(decode("é1").is_none());
+    }
+}
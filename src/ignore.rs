@@ -0,0 +1,358 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Per-directory ignore file; its rules apply to everything below the
+/// directory it sits in, relative to that directory
+pub const IGNORE_FILE: &str = ".netbackupignore";
+
+/// One gitignore-style pattern line
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: Vec<char>,
+    /// `!pattern` re-includes what an earlier rule excluded
+    negated: bool,
+    /// `pattern/` only matches directories
+    dir_only: bool,
+    /// Patterns containing a `/` match the whole relative path; others match
+    /// the last component at any depth
+    anchored: bool,
+    /// Directory of the ignore file the rule came from; empty for the root
+    /// and configured patterns
+    base: String,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            pattern: line.chars().collect(),
+            negated,
+            dir_only,
+            anchored,
+            base: String::new(),
+        })
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let path = if self.base.is_empty() {
+            path
+        } else {
+            match path
+                .strip_prefix(self.base.as_str())
+                .and_then(|rest| rest.strip_prefix('/'))
+            {
+                Some(rest) => rest,
+                None => return false,
+            }
+        };
+        let subject = if self.anchored {
+            path
+        } else {
+            path.rsplit('/').next().unwrap_or(path)
+        };
+        let text: Vec<char> = subject.chars().collect();
+        glob_match(&self.pattern, &text)
+    }
+}
+
+/// Ordered include/exclude rules; as in gitignore, the last matching rule wins
+#[derive(Debug, Default, Clone)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_line(&mut self, line: &str) {
+        if let Some(rule) = Rule::parse(line) {
+            self.rules.push(rule);
+        }
+    }
+
+    /// Rules for walking `root`: the configured patterns first, then the
+    /// tree's own `.netbackupignore`, so the tree can override the config
+    pub fn load(root: &Path, configured: &[String]) -> io::Result<Self> {
+        let mut rules = Self::new();
+        for line in configured {
+            rules.add_line(line);
+        }
+        rules.load_dir(root, "")?;
+        Ok(rules)
+    }
+
+    /// Add the rules of the ignore file in `dir`, if there is one. `base` is
+    /// the directory's path relative to the walked root. Files deeper in the
+    /// tree must be loaded after their parents' so their rules win.
+    pub fn load_dir(&mut self, dir: &Path, base: &str) -> io::Result<()> {
+        let contents = match fs::read_to_string(dir.join(IGNORE_FILE)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for line in contents.lines() {
+            if let Some(mut rule) = Rule::parse(line) {
+                rule.base = base.to_string();
+                self.rules.push(rule);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a '/'-separated path relative to the walked root is excluded.
+    /// Anything inside an excluded directory stays excluded.
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        let mut end = 0;
+        while let Some(pos) = path[end..].find('/') {
+            end += pos;
+            if self.matches(&path[..end], true) {
+                return true;
+            }
+            end += 1;
+        }
+        self.matches(path, is_dir)
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(path, is_dir))
+            .map(|rule| !rule.negated)
+            .unwrap_or(false)
+    }
+}
+
+/// Whether a '/'-separated path passes a listing filter. A pattern with
+/// `*`, `?` or `[` is a glob; anything else matches as a prefix. Patterns
+/// containing a `/` look at the whole path, others at its last component.
+pub fn filter_matches(pattern: &str, path: &str) -> bool {
+    let subject = if pattern.contains('/') {
+        path
+    } else {
+        path.rsplit('/').next().unwrap_or(path)
+    };
+    if !pattern.contains(['*', '?', '[']) {
+        return subject.starts_with(pattern);
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = subject.chars().collect();
+    glob_match(&pattern, &text)
+}
+
+/// Glob match where `*` and `?` stay within one path component, `**` spans
+/// components and `[...]` is a character class. Tracks every text position
+/// the pattern so far can end at, one pattern element at a time, so the work
+/// is bounded by pattern length times text length however many wildcards
+/// there are.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    // reachable[i]: the pattern consumed so far can match text[..i]
+    let mut reachable = vec![false; text.len() + 1];
+    reachable[0] = true;
+    let mut p = 0;
+    while p < pattern.len() {
+        let mut next = vec![false; text.len() + 1];
+        if pattern[p..].starts_with(&['*', '*']) {
+            if pattern.get(p + 2) == Some(&'/') {
+                // "**/" matches zero or more whole directories
+                let mut seen = false;
+                for i in 0..=text.len() {
+                    next[i] = reachable[i] || (seen && text[i - 1] == '/');
+                    seen |= reachable[i];
+                }
+                p += 3;
+            } else {
+                let mut seen = false;
+                for i in 0..=text.len() {
+                    seen |= reachable[i];
+                    next[i] = seen;
+                }
+                p += 2;
+            }
+        } else if pattern[p] == '*' {
+            for i in 0..=text.len() {
+                next[i] = reachable[i] || (i > 0 && next[i - 1] && text[i - 1] != '/');
+            }
+            p += 1;
+        } else {
+            let len = match_one(&pattern[p..], '\0').1;
+            for i in 0..text.len() {
+                next[i + 1] = reachable[i] && match_one(&pattern[p..], text[i]).0;
+            }
+            p += len;
+        }
+        reachable = next;
+    }
+    reachable[text.len()]
+}
+
+/// Match one character against the element at the start of `pattern` that
+/// stands for exactly one character. Returns whether it matched and the
+/// element's length, which does not depend on the character.
+fn match_one(pattern: &[char], c: char) -> (bool, usize) {
+    match pattern[0] {
+        '?' => (c != '/', 1),
+        // No closing bracket: treat '[' literally
+        '[' => match_class(pattern, Some(c)).unwrap_or((c == '[', 1)),
+        '\\' if pattern.len() > 1 => (c == pattern[1], 2),
+        literal => (c == literal, 1),
+    }
+}
+
+/// Match one character against a `[...]` class at the start of `pattern`.
+/// Returns whether it matched and the length of the class, or None if the
+/// class is not terminated.
+fn match_class(pattern: &[char], c: Option<char>) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+    let start = i;
+    let mut matched = false;
+    while i < pattern.len() {
+        if pattern[i] == ']' && i > start {
+            let matched = match c {
+                Some(c) if c != '/' => matched != negated,
+                _ => false,
+            };
+            return Some((matched, i + 1));
+        }
+        if let Some(c) = c {
+            if pattern.get(i + 1) == Some(&'-') && i + 2 < pattern.len() && pattern[i + 2] != ']' {
+                matched |= pattern[i] <= c && c <= pattern[i + 2];
+                i += 3;
+                continue;
+            }
+            matched |= pattern[i] == c;
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(lines: &[&str]) -> IgnoreRules {
+        let mut rules = IgnoreRules::new();
+        for line in lines {
+            rules.add_line(line);
+        }
+        rules
+    }
+
+    #[test]
+    fn test_basename_and_anchored_patterns() {
+        let r = rules(&["# editor files", "*.swp", "/build", "docs/*.pdf", ""]);
+        assert!(r.is_ignored(".main.rs.swp", false));
+        assert!(r.is_ignored("src/deep/.x.swp", false));
+        assert!(r.is_ignored("build", true));
+        assert!(!r.is_ignored("src/build", true));
+        assert!(r.is_ignored("docs/a.pdf", false));
+        assert!(!r.is_ignored("docs/sub/a.pdf", false));
+        assert!(!r.is_ignored("main.rs", false));
+    }
+
+    #[test]
+    fn test_directory_rules_cover_contents() {
+        let r = rules(&["target/", "node_modules"]);
+        assert!(r.is_ignored("target", true));
+        assert!(!r.is_ignored("target", false));
+        assert!(r.is_ignored("target/debug/app", false));
+        assert!(r.is_ignored("web/node_modules/x/index.js", false));
+    }
+
+    #[test]
+    fn test_negation_last_match_wins() {
+        let r = rules(&["*.env", "!example.env", "secrets/", "!secrets/keep"]);
+        assert!(r.is_ignored("prod.env", false));
+        assert!(!r.is_ignored("config/example.env", false));
+        // Files inside an excluded directory cannot be re-included
+        assert!(r.is_ignored("secrets/keep", false));
+    }
+
+    #[test]
+    fn test_double_star_and_classes() {
+        let r = rules(&["**/cache/**", "logs/**/*.log", "*.[oa]", "file?.tmp"]);
+        assert!(r.is_ignored("cache/x", false));
+        assert!(r.is_ignored("a/b/cache/x/y", false));
+        assert!(r.is_ignored("logs/app.log", false));
+        assert!(r.is_ignored("logs/2024/01/app.log", false));
+        assert!(r.is_ignored("lib/x.o", false));
+        assert!(!r.is_ignored("lib/x.so", false));
+        assert!(r.is_ignored("file1.tmp", false));
+        assert!(!r.is_ignored("file10.tmp", false));
+    }
+
+    #[test]
+    fn test_nested_rules_apply_below_their_directory() {
+        let mut r = rules(&["*.log"]);
+        r.rules
+            .extend(["!keep.log", "/out", "tmp/"].iter().map(|line| Rule {
+                base: "web".to_string(),
+                ..Rule::parse(line).unwrap()
+            }));
+        assert!(r.is_ignored("app.log", false));
+        assert!(!r.is_ignored("web/keep.log", false));
+        assert!(r.is_ignored("keep.log", false));
+        assert!(r.is_ignored("web/out", true));
+        assert!(!r.is_ignored("out", true));
+        assert!(!r.is_ignored("api/web/out", true));
+        assert!(r.is_ignored("web/src/tmp/x", false));
+        assert!(!r.is_ignored("tmp/x", false));
+    }
+
+    #[test]
+    fn test_listing_filters() {
+        assert!(filter_matches("*.pdf", "docs/2024/report.pdf"));
+        assert!(!filter_matches("*.pdf", "docs/report.pdf.bak"));
+        assert!(filter_matches("rep", "docs/report.pdf"));
+        assert!(!filter_matches("docs", "old/docs-notes.txt/x"));
+        assert!(filter_matches("docs/20", "docs/2024/report.pdf"));
+        assert!(filter_matches("docs/**/*.pdf", "docs/2024/report.pdf"));
+        assert!(!filter_matches("docs/*.pdf", "docs/2024/report.pdf"));
+        assert!(filter_matches(
+            "**/20*/r?port.[op]df",
+            "docs/2024/report.pdf"
+        ));
+        assert!(!filter_matches("*.[!p]df", "report.pdf"));
+        assert!(filter_matches("\\[a*", "[abc"));
+    }
+
+    #[test]
+    fn test_many_wildcards_match_quickly() {
+        // A backtracking matcher takes exponential time on these
+        let name = "a".repeat(60);
+        let start = std::time::Instant::now();
+        assert!(!filter_matches(&format!("{}b", "*a".repeat(16)), &name));
+        assert!(!filter_matches(&format!("{}b", "**a".repeat(16)), &name));
+        assert!(filter_matches(&"*".repeat(64), &name));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,240 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Journal records replayed before the index is rewritten as a snapshot,
+/// beyond one per indexed file
+const MIN_COMPACT_RECORDS: usize = 1024;
+
+/// What a listing shows for one stored file, kept so List does not have to
+/// read its manifest
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub size: u64,
+    pub checksum: [u8; 32],
+    /// Modification time of the manifest, in microseconds since the epoch
+    pub modified: u64,
+    /// Length of the manifest; with `modified`, tells whether the file was
+    /// replaced behind the index's back
+    pub manifest_len: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+enum JournalRecord {
+    Put(String, IndexEntry),
+    Remove(String),
+}
+
+struct IndexState {
+    entries: HashMap<String, IndexEntry>,
+    journal: File,
+    journal_records: usize,
+}
+
+/// Persistent map from file path to its listing metadata. Changes are
+/// appended to a journal and folded into a snapshot from time to time.
+/// The index is only a cache: entries are checked against the manifest's
+/// modification time and length before they are trusted.
+pub struct MetadataIndex {
+    snapshot_path: PathBuf,
+    journal_path: PathBuf,
+    state: Mutex<IndexState>,
+}
+
+impl MetadataIndex {
+    /// Load the index kept in `dir`, replaying any journalled changes
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let snapshot_path = dir.join("index");
+        let journal_path = dir.join("index.journal");
+
+        let mut entries: HashMap<String, IndexEntry> = match fs::read(&snapshot_path) {
+            Ok(bytes) => bincode::deserialize(&bytes).unwrap_or_else(|e| {
+                eprintln!("Ignoring unreadable metadata index: {}", e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut replayed = false;
+        if let Ok(mut file) = File::open(&journal_path) {
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+            let mut rest = &bytes[..];
+            // A torn record at the end is a write that never finished
+            while let Some((record, tail)) = next_record(rest) {
+                match record {
+                    JournalRecord::Put(path, entry) => entries.insert(path, entry),
+                    JournalRecord::Remove(path) => entries.remove(&path),
+                };
+                rest = tail;
+            }
+            replayed = !bytes.is_empty();
+        }
+
+        let index = Self {
+            state: Mutex::new(IndexState {
+                entries,
+                journal: open_append(&journal_path)?,
+                journal_records: 0,
+            }),
+            snapshot_path,
+            journal_path,
+        };
+        // Start from a clean journal, so nothing is appended after a torn record
+        if replayed {
+            index.compact()?;
+        }
+        Ok(index)
+    }
+
+    pub fn get(&self, path: &str) -> Option<IndexEntry> {
+        self.state.lock().unwrap().entries.get(path).cloned()
+    }
+
+    pub fn put(&self, path: &str, entry: IndexEntry) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.get(path) == Some(&entry) {
+            return Ok(());
+        }
+        state.entries.insert(path.to_string(), entry.clone());
+        self.append(&mut state, JournalRecord::Put(path.to_string(), entry))
+    }
+
+    pub fn remove(&self, path: &str) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.remove(path).is_none() {
+            return Ok(());
+        }
+        self.append(&mut state, JournalRecord::Remove(path.to_string()))
+    }
+
+    /// Move the entry for `from`, and those of everything below it, to `to`
+    pub fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let prefix = format!("{}/", from);
+        let moved: Vec<String> = state
+            .entries
+            .keys()
+            .filter(|path| *path == from || path.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for path in moved {
+            let entry = state.entries.remove(&path).expect("key was just listed");
+            let new_path = format!("{}{}", to, &path[from.len()..]);
+            self.append(&mut state, JournalRecord::Remove(path))?;
+            state.entries.insert(new_path.clone(), entry.clone());
+            self.append(&mut state, JournalRecord::Put(new_path, entry))?;
+        }
+        Ok(())
+    }
+
+    /// Paths currently in the index
+    pub fn paths(&self) -> Vec<String> {
+        self.state.lock().unwrap().entries.keys().cloned().collect()
+    }
+
+    fn append(&self, state: &mut IndexState, record: JournalRecord) -> io::Result<()> {
+        let body = bincode::serialize(&record).map_err(io::Error::other)?;
+        let mut bytes = (body.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&body);
+        state.journal.write_all(&bytes)?;
+        state.journal_records += 1;
+        if state.journal_records > state.entries.len().max(MIN_COMPACT_RECORDS) {
+            self.compact_locked(state)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the snapshot and start an empty journal
+    pub fn compact(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.compact_locked(&mut state)
+    }
+
+    fn compact_locked(&self, state: &mut IndexState) -> io::Result<()> {
+        let bytes = bincode::serialize(&state.entries).map_err(io::Error::other)?;
+        let tmp = self.snapshot_path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.snapshot_path)?;
+
+        File::create(&self.journal_path)?;
+        state.journal = open_append(&self.journal_path)?;
+        state.journal_records = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Split the next length-prefixed record off the journal
+fn next_record(bytes: &[u8]) -> Option<(JournalRecord, &[u8])> {
+    let len = u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+    let body = bytes.get(4..4 + len)?;
+    let record = bincode::deserialize(body).ok()?;
+    Some((record, &bytes[4 + len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(size: u64) -> IndexEntry {
+        IndexEntry {
+            size,
+            checksum: [size as u8; 32],
+            modified: 1_700_000_000_000_000 + size,
+            manifest_len: 80,
+        }
+    }
+
+    #[test]
+    fn test_index_survives_reopen_and_torn_journal() {
+        let dir = crate::test_util::TestDir::new();
+        fs::create_dir_all(&dir).unwrap();
+
+        let index = MetadataIndex::open(&dir).unwrap();
+        index.put("a.txt", entry(1)).unwrap();
+        index.put("docs/b.txt", entry(2)).unwrap();
+        index.compact().unwrap();
+        index.put("a.txt", entry(3)).unwrap();
+        index.remove("docs/b.txt").unwrap();
+        index.put("c.txt", entry(4)).unwrap();
+        drop(index);
+
+        // Half of a record that never finished writing
+        let mut journal = OpenOptions::new()
+            .append(true)
+            .open(dir.join("index.journal"))
+            .unwrap();
+        journal.write_all(&[0, 0, 0, 90, 1, 2]).unwrap();
+        drop(journal);
+
+        let index = MetadataIndex::open(&dir).unwrap();
+        assert_eq!(index.get("a.txt"), Some(entry(3)));
+        assert_eq!(index.get("docs/b.txt"), None);
+        assert_eq!(index.get("c.txt"), Some(entry(4)));
+        let mut paths = index.paths();
+        paths.sort();
+        assert_eq!(paths, ["a.txt", "c.txt"]);
+
+        // Changes after the torn record are not lost
+        index.remove("c.txt").unwrap();
+        index.put("docs/d.txt", entry(5)).unwrap();
+        index.put("docs2/e.txt", entry(6)).unwrap();
+        index.rename("docs", "archive/docs").unwrap();
+        drop(index);
+        let index = MetadataIndex::open(&dir).unwrap();
+        let mut paths = index.paths();
+        paths.sort();
+        assert_eq!(paths, ["a.txt", "archive/docs/d.txt", "docs2/e.txt"]);
+        assert_eq!(index.get("archive/docs/d.txt"), Some(entry(5)));
+    }
+}
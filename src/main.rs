@@ -1,11 +1,25 @@
+mod auth;
+mod chunker;
 mod client;
+mod compression;
 mod config;
+mod crypto;
+mod delta;
+mod failure;
+mod hex;
+mod ignore;
+mod index;
 mod protocol;
 mod server;
 mod storage;
+// Helpers shared by the unit tests of several modules
+#[cfg(test)]
+mod test_util;
+mod tls;
+mod users;
 
 use clap::{Parser, Subcommand};
-use config::Config;
+use config::{ClientConfig, Config};
 use crossterm::{
     event::{read, Event, KeyCode, KeyModifiers},
     terminal::{disable_raw_mode, enable_raw_mode},
@@ -13,9 +27,9 @@ use crossterm::{
 use std::io::{self, Write};
 use std::path::PathBuf;
 
-fn prompt_password_masked() -> Result<String, io::Error> {
-    print!("Password: ");
-    io::stdout().flush()?;
+fn prompt_masked(label: &str) -> Result<String, io::Error> {
+    eprint!("{}: ", label);
+    io::stderr().flush()?;
 
     // Enable raw mode to read individual keystrokes
     enable_raw_mode()?;
@@ -24,7 +38,7 @@ fn prompt_password_masked() -> Result<String, io::Error> {
 
     loop {
         // Read a single event
-        let event = read().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let event = read().map_err(io::Error::other)?;
 
         match event {
             Event::Key(key_event) => {
@@ -32,32 +46,30 @@ fn prompt_password_masked() -> Result<String, io::Error> {
                     KeyCode::Enter => {
                         // User pressed Enter - we're done
                         disable_raw_mode()?;
-                        println!(); // Move to next line
+                        eprintln!(); // Move to next line
                         return Ok(password);
                     }
 
-                    KeyCode::Backspace => {
+                    KeyCode::Backspace if !password.is_empty() => {
                         // Handle backspace - remove last character
-                        if !password.is_empty() {
-                            password.pop();
-                            // Move cursor back, overwrite with space, move back again
-                            print!("\x08 \x08");
-                            io::stdout().flush()?;
-                        }
+                        password.pop();
+                        // Move cursor back, overwrite with space, move back again
+                        eprint!("\x08 \x08");
+                        io::stderr().flush()?;
                     }
 
                     KeyCode::Char('c') if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
                         // Handle Ctrl+C - exit gracefully
                         disable_raw_mode()?;
-                        println!();
+                        eprintln!();
                         std::process::exit(0);
                     }
 
                     KeyCode::Char(c) => {
                         // Regular character - add to password and print asterisk
                         password.push(c);
-                        print!("*");
-                        io::stdout().flush()?;
+                        eprint!("*");
+                        io::stderr().flush()?;
                     }
 
                     _ => {
@@ -75,7 +87,7 @@ fn prompt_password_masked() -> Result<String, io::Error> {
 fn get_password(cli_password: Option<String>) -> String {
     match cli_password {
         Some(p) => p,
-        None => prompt_password_masked().unwrap_or_else(|e| {
+        None => prompt_masked("Password").unwrap_or_else(|e| {
             // Make sure raw mode is disabled on error
             let _ = disable_raw_mode();
             eprintln!("Error reading password:  {}", e);
@@ -84,6 +96,101 @@ fn get_password(cli_password: Option<String>) -> String {
     }
 }
 
+// Helper to get the keyfile passphrase: NETBACKUP_PASSPHRASE > prompt
+fn get_passphrase(confirm: bool) -> Result<String, Box<dyn std::error::Error>> {
+    if let Ok(passphrase) = std::env::var("NETBACKUP_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    let read = |label| {
+        prompt_masked(label).inspect_err(|_| {
+            let _ = disable_raw_mode();
+        })
+    };
+    let passphrase = read("Passphrase")?;
+    if confirm && read("Repeat passphrase")? != passphrase {
+        return Err("Passphrases do not match".into());
+    }
+    Ok(passphrase)
+}
+
+// Helper to choose a new account password: CLI flag > prompt (twice)
+fn get_new_password(cli_password: Option<String>) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(password) = cli_password {
+        return Ok(password);
+    }
+    let read = |label| {
+        prompt_masked(label).inspect_err(|_| {
+            let _ = disable_raw_mode();
+        })
+    };
+    let password = read("New password")?;
+    if read("Repeat password")? != password {
+        return Err("Passwords do not match".into());
+    }
+    Ok(password)
+}
+
+// Resolve connection settings: CLI flags > config file
+fn connect_options(
+    server: Option<String>,
+    username: &Option<String>,
+    output: client::Output,
+    password: Option<String>,
+    config: &ClientConfig,
+) -> Result<client::ConnectOptions, Box<dyn std::error::Error>> {
+    let server_addr = server.unwrap_or_else(|| config.default_server.clone());
+    let tls = if config.tls_enabled {
+        Some(tls::client_tls(config, &server_addr)?)
+    } else {
+        None
+    };
+    let chunking = if config.chunking.content_defined {
+        let chunker = chunker::ChunkerConfig {
+            min_size: config.chunking.min_size,
+            avg_size: config.chunking.avg_size,
+            max_size: config.chunking.max_size,
+        };
+        chunker
+            .validate()
+            .map_err(|e| format!("Invalid [client.chunking] settings: {}", e))?;
+        Some(chunker)
+    } else {
+        None
+    };
+    let password = get_password(password);
+    let crypto = match &config.keyfile {
+        Some(path) => {
+            // Sealed chunks are slightly larger and must still fit in a message
+            if config.chunking.content_defined
+                && config.chunking.max_size + crypto::MAX_EXPANSION > chunker::MAX_CHUNK_SIZE
+            {
+                return Err(format!(
+                    "Invalid [client.chunking] settings: max_size must leave {} bytes for encryption",
+                    crypto::MAX_EXPANSION
+                )
+                .into());
+            }
+            Some(crypto::Crypto::from_keyfile(
+                path.as_ref(),
+                &get_passphrase(false)?,
+            )?)
+        }
+        None => None,
+    };
+    Ok(client::ConnectOptions {
+        server_addr,
+        username: username
+            .clone()
+            .or_else(|| config.username.clone())
+            .unwrap_or_default(),
+        password,
+        tls,
+        chunking,
+        crypto,
+        output,
+    })
+}
+
 #[derive(Parser)]
 #[command(name = "netbackup")]
 #[command(about = "Network backup and storage system", long_about = None)]
@@ -92,6 +199,14 @@ struct Cli {
     #[arg(short, long, global = true)]
     config: Option<PathBuf>,
 
+    /// Account to log in as (overrides config)
+    #[arg(short, long, global = true)]
+    user: Option<String>,
+
+    /// text, or json for one JSON document per command and JSON errors on stderr
+    #[arg(long, global = true, default_value = "text")]
+    output: client::Output,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -107,12 +222,18 @@ enum Commands {
         #[arg(short, long)]
         storage: Option<String>,
     },
-    /// Upload a file to the server
+    /// Upload a file (or, with -r, a directory tree) to the server
     Upload {
-        /// <local_file> - Path to the local file to upload
+        /// <local_file> - Path to the local file or directory to upload
         local_file: String,
-        /// [remote_name] - Optional remote filename (defaults to local filename)
+        /// [remote_name] - Optional remote filename or directory (defaults to local name)
         remote_name: Option<String>,
+        /// Upload a directory and everything below it
+        #[arg(short, long)]
+        recursive: bool,
+        /// Print what would be uploaded, after ignore rules, without connecting
+        #[arg(long)]
+        dry_run: bool,
         /// Server address (overrides config) [default: from config]
         #[arg(short, long)]
         server: Option<String>,
@@ -120,12 +241,24 @@ enum Commands {
         #[arg(short, long)]
         password: Option<String>,
     },
-    /// Download a file from the server
+    /// Download a file (or, with -r, a directory tree) from the server
     Download {
-        /// <remote_file> - Filename on the remote server
+        /// <remote_file> - Filename or directory on the remote server
         remote_file: String,
-        /// [local_path] - Optional local path to save (defaults to remote filename)
+        /// [local_path] - Optional local path to save (defaults to remote name)
         local_path: Option<String>,
+        /// Download a directory and everything below it
+        #[arg(short, long, conflicts_with_all = ["version", "at"])]
+        recursive: bool,
+        /// Fetch an older version by ID (see `netbackup versions`)
+        #[arg(long, conflicts_with = "at")]
+        version: Option<u64>,
+        /// Fetch the version that was current at this UTC time, e.g. "2024-05-01 13:00:00"
+        #[arg(long)]
+        at: Option<String>,
+        /// Continue a partial download, fetching only chunks that are missing or differ
+        #[arg(long)]
+        resume: bool,
         /// Server address (overrides config) [default: from config]
         #[arg(short, long)]
         server: Option<String>,
@@ -133,8 +266,25 @@ enum Commands {
         #[arg(short, long)]
         password: Option<String>,
     },
-    /// List all files on the server
+    /// List files and directories on the server
     List {
+        /// [path] - Remote directory to list (defaults to the root)
+        path: Option<String>,
+        /// Include the contents of subdirectories
+        #[arg(short, long)]
+        recursive: bool,
+        /// Only show paths matching a glob (e.g. '*.pdf') or starting with a prefix
+        #[arg(long)]
+        filter: Option<String>,
+        /// Order by name, size (largest first) or mtime (newest first)
+        #[arg(long, default_value = "name")]
+        sort: storage::SortKey,
+        /// Show size, modification time and checksum of each entry, and totals
+        #[arg(short, long)]
+        long: bool,
+        /// Show at most this many entries
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        limit: Option<u32>,
         /// Server address (overrides config) [default: from config]
         #[arg(short, long)]
         server: Option<String>,
@@ -153,6 +303,76 @@ enum Commands {
         #[arg(short, long)]
         password: Option<String>,
     },
+    /// List the stored versions of a file
+    Versions {
+        /// <remote_file> - Filename on the remote server
+        remote_file: String,
+        /// Server address (overrides config) [default: from config]
+        #[arg(short, long)]
+        server: Option<String>,
+        /// Password for authentication (will prompt if not provided)
+        #[arg(short, long)]
+        password: Option<String>,
+    },
+    /// Show details of one remote file or directory
+    Stat {
+        /// <remote_path> - File or directory on the remote server
+        remote_path: String,
+        /// Server address (overrides config) [default: from config]
+        #[arg(short, long)]
+        server: Option<String>,
+        /// Password for authentication (will prompt if not provided)
+        #[arg(short, long)]
+        password: Option<String>,
+    },
+    /// Rename or move a file or directory on the server
+    Mv {
+        /// <remote_path> - File or directory to move
+        from: String,
+        /// <new_path> - New path, or an existing directory to move it into
+        to: String,
+        /// Server address (overrides config) [default: from config]
+        #[arg(short, long)]
+        server: Option<String>,
+        /// Password for authentication (will prompt if not provided)
+        #[arg(short, long)]
+        password: Option<String>,
+    },
+    /// Copy a file or directory on the server without transferring its data
+    Cp {
+        /// <remote_path> - File or directory to copy
+        from: String,
+        /// <new_path> - Path of the copy, or an existing directory to copy it into
+        to: String,
+        /// Server address (overrides config) [default: from config]
+        #[arg(short, long)]
+        server: Option<String>,
+        /// Password for authentication (will prompt if not provided)
+        #[arg(short, long)]
+        password: Option<String>,
+    },
+    /// Create a directory on the server, including missing parents
+    Mkdir {
+        /// <remote_dir> - Directory path to create
+        remote_dir: String,
+        /// Server address (overrides config) [default: from config]
+        #[arg(short, long)]
+        server: Option<String>,
+        /// Password for authentication (will prompt if not provided)
+        #[arg(short, long)]
+        password: Option<String>,
+    },
+    /// Remove an empty directory from the server
+    Rmdir {
+        /// <remote_dir> - Directory path to remove
+        remote_dir: String,
+        /// Server address (overrides config) [default: from config]
+        #[arg(short, long)]
+        server: Option<String>,
+        /// Password for authentication (will prompt if not provided)
+        #[arg(short, long)]
+        password: Option<String>,
+    },
     /// Connect to server in interactive mode
     Connect {
         /// Server address (overrides config) [default: from config]
@@ -162,66 +382,198 @@ enum Commands {
         #[arg(short, long)]
         password: Option<String>,
     },
+    /// Manage the accounts in the server's user database
+    User {
+        #[command(subcommand)]
+        action: UserAction,
+        /// Storage directory holding the database (overrides config) [default: from config]
+        #[arg(short, long)]
+        storage: Option<String>,
+    },
+    /// Create a keyfile for client-side encryption
+    Keygen {
+        /// [file] - Path to write the keyfile [default: client.keyfile from config, else ./netbackup.key]
+        #[arg(short = 'o', long)]
+        file: Option<PathBuf>,
+    },
     /// Generate a default configuration file
     InitConfig {
-        /// [output] - Path to write config file [default: ./netbackup.toml]
+        /// [file] - Path to write config file [default: ./netbackup.toml]
+        #[arg(short = 'o', long)]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum UserAction {
+    /// Add an account with its own empty namespace
+    Add {
+        /// <username> - Letters, digits, '.', '_' and '-'
+        username: String,
+        /// Password for the account (will prompt if not provided)
+        #[arg(short, long)]
+        password: Option<String>,
+        /// admin (full access), append (upload new files only) or read-only
+        #[arg(short, long, default_value = "admin")]
+        role: users::Role,
+        /// Work on this user's files instead of a namespace of its own
         #[arg(short, long)]
-        output: Option<PathBuf>,
+        namespace: Option<String>,
+    },
+    /// Remove an account; its files stay on disk
+    Remove {
+        /// <username> - Account to remove
+        username: String,
+    },
+    /// Change an account's password
+    Passwd {
+        /// <username> - Account to update
+        username: String,
+        /// New password (will prompt if not provided)
+        #[arg(short, long)]
+        password: Option<String>,
     },
+    /// Change what an account may do
+    Role {
+        /// <username> - Account to update
+        username: String,
+        /// <role> - admin (full access), append (upload new files only) or read-only
+        role: users::Role,
+    },
+    /// List the accounts and their roles
+    List,
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() {
     let cli = Cli::parse();
+    let output = cli.output;
+
+    // Each class of failure exits with its own code, so scripts can tell them apart
+    if let Err(e) = run(cli).await {
+        match output {
+            client::Output::Text => eprintln!("Error: {}", e),
+            client::Output::Json => eprintln!("{}", failure::error_document(e.as_ref())),
+        }
+        std::process::exit(failure::classify(e.as_ref()).exit_code());
+    }
+}
 
+async fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
     // Load configuration
     let config = match &cli.config {
-        Some(path) => Config::load_from_path(path).unwrap_or_else(|e| {
-            eprintln!(
-                "[ERROR] Failed to load config from {}: {}",
-                path.display(),
-                e
-            );
-            std::process::exit(1);
-        }),
-        None => Config::load(),
+        Some(path) => Config::load_from_path(path).map_err(|e| {
+            failure::Failure::context(
+                &format!("Failed to load config from {}", path.display()),
+                e.as_ref(),
+            )
+        })?,
+        // Keep stderr to the error document when scripts read JSON
+        None => Config::load(cli.output == client::Output::Json),
     };
 
     match cli.command {
         Commands::Server { bind, storage } => {
             // CLI args override config file values
-            let bind_addr = bind.unwrap_or(config.server.bind_address);
-            let storage_path = storage.unwrap_or(config.server.storage_path);
+            let bind_addr = bind.unwrap_or(config.server.bind_address.clone());
+            let storage_path = storage.unwrap_or(config.server.storage_path.clone());
+            let tls = if config.server.tls_enabled {
+                Some(tls::server_acceptor(&config.server)?)
+            } else {
+                None
+            };
 
-            server::run(bind_addr, storage_path, config.auth.password).await?;
+            let retention = storage::VersionRetention {
+                max_versions: config.server.max_versions,
+                max_age_days: config.server.version_retention_days,
+            };
+
+            server::run(
+                bind_addr,
+                storage_path,
+                config.auth.password,
+                tls,
+                retention,
+                config.server.compress_chunks,
+                config.server.max_upload_size,
+            )
+            .await?;
         }
 
         Commands::Upload {
             local_file,
             remote_name,
+            recursive,
+            dry_run,
             server,
             password,
         } => {
-            let server_addr = server.unwrap_or(config.client.default_server);
-            let pass = get_password(password);
-            client::upload(&server_addr, &local_file, remote_name.as_deref(), &pass).await?;
+            if dry_run {
+                client::upload_dry_run(
+                    &local_file,
+                    remote_name.as_deref(),
+                    recursive,
+                    &config.client.exclude,
+                    cli.output,
+                )?;
+                return Ok(());
+            }
+            let options = connect_options(server, &cli.user, cli.output, password, &config.client)?;
+            client::upload(
+                &options,
+                &local_file,
+                remote_name.as_deref(),
+                recursive,
+                &config.client.exclude,
+            )
+            .await?;
         }
 
         Commands::Download {
             remote_file,
             local_path,
+            recursive,
+            version,
+            at,
+            resume,
             server,
             password,
         } => {
-            let server_addr = server.unwrap_or(config.client.default_server);
-            let pass = get_password(password);
-            client::download(&server_addr, &remote_file, local_path.as_deref(), &pass).await?;
+            let selector = match (version, at) {
+                (Some(id), _) => client::VersionSelector::Id(id),
+                (None, Some(at)) => client::VersionSelector::At(client::parse_timestamp(&at)?),
+                (None, None) => client::VersionSelector::Current,
+            };
+            let options = connect_options(server, &cli.user, cli.output, password, &config.client)?;
+            client::download(
+                &options,
+                &remote_file,
+                local_path.as_deref(),
+                resume,
+                recursive,
+                selector,
+            )
+            .await?;
         }
 
-        Commands::List { server, password } => {
-            let server_addr = server.unwrap_or(config.client.default_server);
-            let pass = get_password(password);
-            client::list(&server_addr, &pass).await?;
+        Commands::List {
+            path,
+            recursive,
+            filter,
+            sort,
+            long,
+            limit,
+            server,
+            password,
+        } => {
+            let options = connect_options(server, &cli.user, cli.output, password, &config.client)?;
+            let view = client::ListView {
+                filter,
+                sort,
+                long,
+                limit: limit.map(|limit| limit as usize),
+            };
+            client::list(&options, path.as_deref().unwrap_or(""), recursive, &view).await?;
         }
 
         Commands::Delete {
@@ -229,23 +581,172 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             server,
             password,
         } => {
-            let server_addr = server.unwrap_or(config.client.default_server);
-            let pass = get_password(password);
-            client::delete(&server_addr, &remote_file, &pass).await?;
+            let options = connect_options(server, &cli.user, cli.output, password, &config.client)?;
+            client::delete(&options, &remote_file).await?;
+        }
+
+        Commands::Versions {
+            remote_file,
+            server,
+            password,
+        } => {
+            let options = connect_options(server, &cli.user, cli.output, password, &config.client)?;
+            client::versions(&options, &remote_file).await?;
+        }
+
+        Commands::Stat {
+            remote_path,
+            server,
+            password,
+        } => {
+            let options = connect_options(server, &cli.user, cli.output, password, &config.client)?;
+            client::stat(&options, &remote_path).await?;
+        }
+
+        Commands::Mv {
+            from,
+            to,
+            server,
+            password,
+        } => {
+            let options = connect_options(server, &cli.user, cli.output, password, &config.client)?;
+            client::move_path(&options, &from, &to, false).await?;
+        }
+
+        Commands::Cp {
+            from,
+            to,
+            server,
+            password,
+        } => {
+            let options = connect_options(server, &cli.user, cli.output, password, &config.client)?;
+            client::move_path(&options, &from, &to, true).await?;
+        }
+
+        Commands::Mkdir {
+            remote_dir,
+            server,
+            password,
+        } => {
+            let options = connect_options(server, &cli.user, cli.output, password, &config.client)?;
+            client::mkdir(&options, &remote_dir).await?;
+        }
+
+        Commands::Rmdir {
+            remote_dir,
+            server,
+            password,
+        } => {
+            let options = connect_options(server, &cli.user, cli.output, password, &config.client)?;
+            client::rmdir(&options, &remote_dir).await?;
         }
         Commands::Connect { server, password } => {
-            let server_addr = server.unwrap_or(config.client.default_server);
-            let pass = get_password(password);
-            client::interactive_session(&server_addr, &pass).await?;
+            let options = connect_options(server, &cli.user, cli.output, password, &config.client)?;
+            client::interactive_session(&options, &config.client.exclude).await?;
+        }
+
+        Commands::User { action, storage } => {
+            let storage_path = storage.unwrap_or(config.server.storage_path.clone());
+            let path = users::users_file(&storage_path);
+            let mut db = users::UserDatabase::load(&path)?;
+
+            match action {
+                UserAction::Add {
+                    username,
+                    password,
+                    role,
+                    namespace,
+                } => {
+                    users::validate_username(&username)?;
+                    if db.account(&username).is_some() {
+                        return Err(format!("User '{}' already exists", username).into());
+                    }
+                    db.add(
+                        &username,
+                        &get_new_password(password)?,
+                        role,
+                        namespace.as_deref(),
+                    )?;
+                    db.save(&path)?;
+                    println!("[SUCCESS] Added user {} ({})", username, role);
+                    if let Some(namespace) = namespace.filter(|n| *n != username) {
+                        println!("          Works on the files of:  {}", namespace);
+                    }
+                    if db.users().count() == 1 {
+                        println!("          The server now requires a user account; the shared [auth] password no longer works.");
+                        println!("          Restart a running server to switch it to per-user namespaces.");
+                    }
+                }
+                UserAction::Remove { username } => {
+                    db.remove(&username)?;
+                    db.save(&path)?;
+                    println!("[SUCCESS] Removed user {}", username);
+                    println!(
+                        "          Their files remain in:  {}",
+                        users::user_root(&storage_path, &username).display()
+                    );
+                }
+                UserAction::Passwd { username, password } => {
+                    if db.account(&username).is_none() {
+                        return Err(format!("No such user: {}", username).into());
+                    }
+                    db.set_password(&username, &get_new_password(password)?)?;
+                    db.save(&path)?;
+                    println!("[SUCCESS] Password changed for {}", username);
+                }
+                UserAction::Role { username, role } => {
+                    db.set_role(&username, role)?;
+                    db.save(&path)?;
+                    println!("[SUCCESS] {} is now {}", username, role);
+                    println!("          Sessions already logged in keep their old role until they reconnect.");
+                }
+                UserAction::List => {
+                    println!("{:<32} {:<10} NAMESPACE", "USER", "ROLE");
+                    for (username, role, namespace) in db.users() {
+                        println!(
+                            "{:<32} {:<10} {}",
+                            username,
+                            role,
+                            namespace.unwrap_or(username)
+                        );
+                    }
+                }
+            }
+        }
+
+        Commands::Keygen { file } => {
+            let path = file
+                .or_else(|| config.client.keyfile.as_ref().map(PathBuf::from))
+                .unwrap_or_else(|| PathBuf::from("netbackup.key"));
+
+            if path.exists() {
+                return Err(failure::Failure::new(
+                    failure::FailureKind::Other,
+                    format!(
+                        "Keyfile already exists at {}; replacing it would make existing backups unreadable",
+                        path.display()
+                    ),
+                )
+                .into());
+            }
+
+            crypto::create_keyfile(&path, &get_passphrase(true)?)?;
+            println!("[SUCCESS] Keyfile created at: {}", path.display());
+            println!("          Keep a copy somewhere safe: without it and the passphrase, encrypted backups cannot be restored.");
         }
 
-        Commands::InitConfig { output } => {
-            let path = output.unwrap_or_else(|| PathBuf::from("netbackup.toml"));
+        Commands::InitConfig { file } => {
+            let path = file.unwrap_or_else(|| PathBuf::from("netbackup.toml"));
 
             if path.exists() {
-                eprintln!("[ERROR] Config file already exists at:  {}", path.display());
-                eprintln!("        Use a different path or delete the existing file.");
-                std::process::exit(1);
+                return Err(failure::Failure::new(
+                    failure::FailureKind::Other,
+                    format!(
+                        "Config file already exists at {}; use a different path or delete the existing file",
+                        path.display()
+                    ),
+                )
+                .into());
             }
 
             Config::generate_default(&path)?;
@@ -1,5 +1,12 @@
+use crate::auth;
+use crate::chunker::MAX_CHUNK_SIZE;
+use crate::compression::{self, Encoding};
+use crate::delta::{BlockSignature, DeltaInstruction};
+use crate::storage::SortKey;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::io::{self, Error, ErrorKind};
+use std::ops::Range;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -12,6 +19,19 @@ pub enum Operation {
     StoreChunk = 0x06,    // Store a single chunk
     RetrieveChunk = 0x07, // Retrieve a single chunk
     StoreComplete = 0x08, // Signal all chunks sent
+    Hello = 0x09,         // Version and capability negotiation
+    UploadBegin = 0x0A,   // Start or resume an upload session
+    UploadStatus = 0x0B,  // Query the chunks an upload session is still missing
+    ChunkHashes = 0x0C,   // Per-chunk SHA-256 hashes of a stored file
+    Mkdir = 0x0D,         // Create a directory (and missing parents)
+    Rmdir = 0x0E,         // Remove an empty directory
+    ListVersions = 0x0F,  // List the stored versions of a file
+    HaveChunks = 0x10,    // Offer chunk hashes so the server can reuse data it already stores
+    Signatures = 0x11,    // rsync-style block signatures of a stored file
+    StoreDelta = 0x12,    // Store a chunk as a delta against the current file
+    Stat = 0x13,          // Metadata of a single file or directory
+    Rename = 0x14,        // Move a file or directory to a new path
+    Copy = 0x15,          // Copy a file or directory, sharing its chunks
 }
 
 impl Operation {
@@ -25,7 +45,46 @@ impl Operation {
             0x06 => Ok(Operation::StoreChunk),
             0x07 => Ok(Operation::RetrieveChunk),
             0x08 => Ok(Operation::StoreComplete),
-            _ => Err(Error::new(ErrorKind::InvalidData, "Invalid operation code")),
+            0x09 => Ok(Operation::Hello),
+            0x0A => Ok(Operation::UploadBegin),
+            0x0B => Ok(Operation::UploadStatus),
+            0x0C => Ok(Operation::ChunkHashes),
+            0x0D => Ok(Operation::Mkdir),
+            0x0E => Ok(Operation::Rmdir),
+            0x0F => Ok(Operation::ListVersions),
+            0x10 => Ok(Operation::HaveChunks),
+            0x11 => Ok(Operation::Signatures),
+            0x12 => Ok(Operation::StoreDelta),
+            0x13 => Ok(Operation::Stat),
+            0x14 => Ok(Operation::Rename),
+            0x15 => Ok(Operation::Copy),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid operation code 0x{:02x}", value),
+            )),
+        }
+    }
+
+    /// Capability both peers must have negotiated before this operation may be used
+    pub fn required_capability(&self) -> u32 {
+        match self {
+            Operation::Store
+            | Operation::Retrieve
+            | Operation::Delete
+            | Operation::List
+            | Operation::Auth
+            | Operation::StoreChunk
+            | Operation::RetrieveChunk
+            | Operation::StoreComplete
+            | Operation::Hello => 0,
+            Operation::UploadBegin | Operation::UploadStatus => capability::RESUMABLE_UPLOADS,
+            Operation::ChunkHashes => capability::RESUMABLE_DOWNLOADS,
+            Operation::Mkdir | Operation::Rmdir => capability::DIRECTORIES,
+            Operation::ListVersions => capability::VERSIONS,
+            Operation::HaveChunks => capability::DEDUP,
+            Operation::Signatures | Operation::StoreDelta => capability::DELTA,
+            Operation::Stat => capability::STAT,
+            Operation::Rename | Operation::Copy => capability::RENAME,
         }
     }
 }
@@ -38,6 +97,14 @@ pub enum StatusCode {
     ErrorPermissionDenied = 0x02,
     ErrorInvalidData = 0x03,
     ErrorServerError = 0x04,
+    ErrorIncompatibleVersion = 0x05,
+    ErrorUnsupported = 0x06,
+    /// The request cannot be carried out as asked, e.g. a directory that is
+    /// not empty or a chunk count that does not match the file
+    ErrorInvalidRequest = 0x07,
+    ErrorAlreadyExists = 0x08,
+    /// The request's MAC did not verify, or it reused an earlier request ID
+    ErrorMessageRejected = 0x09,
 }
 
 impl StatusCode {
@@ -48,9 +115,132 @@ impl StatusCode {
             0x02 => Ok(StatusCode::ErrorPermissionDenied),
             0x03 => Ok(StatusCode::ErrorInvalidData),
             0x04 => Ok(StatusCode::ErrorServerError),
+            0x05 => Ok(StatusCode::ErrorIncompatibleVersion),
+            0x06 => Ok(StatusCode::ErrorUnsupported),
+            0x07 => Ok(StatusCode::ErrorInvalidRequest),
+            0x08 => Ok(StatusCode::ErrorAlreadyExists),
+            0x09 => Ok(StatusCode::ErrorMessageRejected),
             _ => Err(Error::new(ErrorKind::InvalidData, "Invalid status code")),
         }
     }
+
+    /// The status to send instead to a client that did not negotiate
+    /// `capability::STATUS_CODES`
+    pub fn for_capabilities(self, capabilities: u32) -> Self {
+        match self {
+            StatusCode::ErrorInvalidRequest | StatusCode::ErrorAlreadyExists
+                if capabilities & capability::STATUS_CODES == 0 =>
+            {
+                StatusCode::ErrorInvalidData
+            }
+            StatusCode::ErrorMessageRejected if capabilities & capability::STATUS_CODES == 0 => {
+                StatusCode::ErrorPermissionDenied
+            }
+            status => status,
+        }
+    }
+}
+
+// Protocol versioning. The version only changes when the encoding of an
+// existing message does; new operations are gated on capability bits instead.
+// MIN_PROTOCOL_VERSION is the oldest encoding the server still decodes.
+// v2: StoreChunk/StoreComplete address an upload session ID instead of a filename
+// v3: StoreComplete carries the expected whole-file size and SHA-256
+// v4: paths may be nested; List takes a ListRequest and entries carry a type
+// v5: chunk downloads and chunk hashes address a file version
+// v6: List returns a Listing with logical and physical size totals
+// v7: UploadBegin may carry a content-defined chunk layout
+// v8: chunk data in StoreChunk and RetrieveChunk is preceded by an encoding flag
+// v9: Auth names a user; the challenge carries that user's salt and PBKDF2 rounds
+// v10: List is paged, filtered and sorted; a Listing carries a continuation cursor
+// v11: SCRAM-style auth proofs; they and the session key cover the hello exchange
+// v12: message MACs name the direction the message travels
+// v13: missing chunks of an upload session are sent as ranges
+pub const PROTOCOL_VERSION: u16 = 13;
+/// Sessions before v12 MAC requests and responses alike, so a request can be
+/// reflected back as its own response, and v12 lists every missing chunk of
+/// an upload one by one; neither is accepted
+pub const MIN_PROTOCOL_VERSION: u16 = 13;
+
+/// Optional features, negotiated as a bitset in the hello exchange
+pub mod capability {
+    pub const COMPRESSION: u32 = 1 << 0;
+    pub const RESUMABLE_UPLOADS: u32 = 1 << 1;
+    pub const DIRECTORIES: u32 = 1 << 2;
+    pub const RESUMABLE_DOWNLOADS: u32 = 1 << 3;
+    pub const VERSIONS: u32 = 1 << 4;
+    pub const DEDUP: u32 = 1 << 5;
+    pub const DELTA: u32 = 1 << 6;
+    pub const STAT: u32 = 1 << 7;
+    pub const RENAME: u32 = 1 << 8;
+    /// Requests that fail for reasons other than bad data are answered with
+    /// `ErrorInvalidRequest` or `ErrorAlreadyExists`, and forged or replayed
+    /// ones with `ErrorMessageRejected`
+    pub const STATUS_CODES: u32 = 1 << 9;
+
+    /// Capabilities implemented by this build
+    pub const SUPPORTED: u32 = COMPRESSION
+        | RESUMABLE_UPLOADS
+        | DIRECTORIES
+        | RESUMABLE_DOWNLOADS
+        | VERSIONS
+        | DEDUP
+        | DELTA
+        | STAT
+        | RENAME
+        | STATUS_CODES;
+
+    pub fn names(bits: u32) -> Vec<&'static str> {
+        let all = [
+            (COMPRESSION, "compression"),
+            (RESUMABLE_UPLOADS, "resumable-uploads"),
+            (DIRECTORIES, "directories"),
+            (RESUMABLE_DOWNLOADS, "resumable-downloads"),
+            (VERSIONS, "versions"),
+            (DEDUP, "dedup"),
+            (DELTA, "delta"),
+            (STAT, "stat"),
+            (RENAME, "rename"),
+            (STATUS_CODES, "status-codes"),
+        ];
+        all.iter()
+            .filter(|(bit, _)| bits & bit != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+/// Hello payload, sent by the client first and echoed by the server with the
+/// negotiated capability set
+/// Format: [version: u16][capabilities: u32]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub version: u16,
+    pub capabilities: u32,
+}
+
+impl Hello {
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(6);
+        payload.extend_from_slice(&self.version.to_be_bytes());
+        payload.extend_from_slice(&self.capabilities.to_be_bytes());
+        payload
+    }
+
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        if payload.len() < 6 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Hello payload too short",
+            ));
+        }
+        let version = u16::from_be_bytes([payload[0], payload[1]]);
+        let capabilities = u32::from_be_bytes([payload[2], payload[3], payload[4], payload[5]]);
+        Ok(Self {
+            version,
+            capabilities,
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -124,6 +314,31 @@ impl Message {
         calculated == self.checksum
     }
 
+    /// Fill the auth field with a MAC under the session key for a message
+    /// travelling in `direction`. Must be called after the request ID is set.
+    pub fn sign(&mut self, session_key: &[u8; 32], direction: auth::Direction) {
+        self.auth_token = auth::message_mac(
+            session_key,
+            direction,
+            self.request_id,
+            self.operation as u8,
+            self.status as u8,
+            &self.checksum,
+        );
+    }
+
+    pub fn verify_mac(&self, session_key: &[u8; 32], direction: auth::Direction) -> bool {
+        auth::verify_message_mac(
+            session_key,
+            direction,
+            self.request_id,
+            self.operation as u8,
+            self.status as u8,
+            &self.checksum,
+            &self.auth_token,
+        )
+    }
+
     /// Serialize message to bytes
     /// Format: [length: u32][request_id: u32][op: u8][status: u8][checksum: 32][auth: 32][payload]
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -218,48 +433,68 @@ impl Message {
     }
 }
 
-// Simple authentication helper
-pub fn generate_auth_token(password: &str) -> [u8; 32] {
-    let mut hasher = Sha256::new();
-    hasher.update(password.as_bytes());
-    hasher.finalize().into()
-}
-
 // Chunk constants
 #[allow(dead_code)]
 pub const CHUNK_SIZE: usize = 65536; // 64KB
 
+/// Most entries the server returns in one page of a listing
+pub const MAX_LIST_PAGE: u32 = 1000;
+/// Longest listing filter the server accepts, in characters
+pub const MAX_LIST_FILTER_LEN: usize = 256;
+/// Most `*`, `?` and `[` a listing filter may contain
+pub const MAX_LIST_FILTER_WILDCARDS: usize = 16;
+
+/// Longest frame either side reads, length prefix excluded. A chunk of up to
+/// `MAX_CHUNK_SIZE` or a full list page fits many times over, as do the chunk
+/// hashes and block signatures of files up to about a hundred GiB.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Decode a frame's length prefix, refusing lengths over `MAX_FRAME_LEN` so
+/// a peer cannot make the reader allocate gigabytes with four bytes
+pub fn frame_length(prefix: [u8; 4]) -> io::Result<u32> {
+    let length = u32::from_be_bytes(prefix);
+    if length > MAX_FRAME_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Frame of {} bytes exceeds the {} byte limit",
+                length, MAX_FRAME_LEN
+            ),
+        ));
+    }
+    Ok(length)
+}
+
 // Chunk metadata helpers
 #[derive(Debug)]
 pub struct ChunkMetadata {
-    pub filename: String,
+    pub upload_id: String,
     pub chunk_number: u32,
     pub total_chunks: u32,
     pub data: Vec<u8>,
 }
 
 impl ChunkMetadata {
-    /// Encode chunk metadata into payload
-    /// Format: [filename_len: u32][filename][chunk_num: u32][total_chunks: u32][data]
-    #[allow(dead_code)]
-    pub fn to_payload(&self) -> Vec<u8> {
-        let filename_bytes = self.filename.as_bytes();
-        let filename_len = filename_bytes.len() as u32;
+    /// Encode chunk metadata into payload, lz4-compressing the data if
+    /// `compress` is set and that makes it smaller
+    /// Format: [upload_id_len: u32][upload_id][chunk_num: u32][total_chunks: u32][encoding: u8][data]
+    pub fn to_payload(&self, compress: bool) -> Vec<u8> {
+        let id_bytes = self.upload_id.as_bytes();
+        let id_len = id_bytes.len() as u32;
 
         let mut payload = Vec::new();
-        payload.extend_from_slice(&filename_len.to_be_bytes());
-        payload.extend_from_slice(filename_bytes);
+        payload.extend_from_slice(&id_len.to_be_bytes());
+        payload.extend_from_slice(id_bytes);
         payload.extend_from_slice(&self.chunk_number.to_be_bytes());
         payload.extend_from_slice(&self.total_chunks.to_be_bytes());
-        payload.extend_from_slice(&self.data);
+        encode_chunk_data(&mut payload, &self.data, compress);
 
         payload
     }
 
-    /// Decode chunk metadata from payload
-    #[allow(dead_code)]
+    /// Decode chunk metadata from payload, decompressing the data
     pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
-        if payload.len() < 12 {
+        if payload.len() < 13 {
             return Err(Error::new(
                 ErrorKind::InvalidData,
                 "Chunk payload too short",
@@ -268,8 +503,8 @@ impl ChunkMetadata {
 
         let mut offset = 0;
 
-        // Filename length
-        let filename_len = u32::from_be_bytes([
+        // Upload ID length
+        let id_len = u32::from_be_bytes([
             payload[offset],
             payload[offset + 1],
             payload[offset + 2],
@@ -277,13 +512,13 @@ impl ChunkMetadata {
         ]) as usize;
         offset += 4;
 
-        if payload.len() < offset + filename_len + 8 {
+        if payload.len() < offset + id_len + 9 {
             return Err(Error::new(ErrorKind::InvalidData, "Invalid chunk payload"));
         }
 
-        // Filename
-        let filename = String::from_utf8_lossy(&payload[offset..offset + filename_len]).to_string();
-        offset += filename_len;
+        // Upload ID
+        let upload_id = String::from_utf8_lossy(&payload[offset..offset + id_len]).to_string();
+        offset += id_len;
 
         // Chunk number
         let chunk_number = u32::from_be_bytes([
@@ -304,24 +539,77 @@ impl ChunkMetadata {
         offset += 4;
 
         // Data
-        let data = payload[offset..].to_vec();
+        let data = decode_chunk_data(&payload[offset..])?;
 
         Ok(Self {
-            filename,
+            upload_id,
             chunk_number,
             total_chunks,
             data,
         })
     }
 }
+/// Start (or resume) an upload session. A pending session for the same
+/// filename, size and checksum is resumed instead of starting over.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadBeginRequest {
+    pub filename: String,
+    pub total_size: u64,
+    pub total_chunks: u32,
+    pub checksum: [u8; 32],
+    /// Length of every chunk when the client cut the file at content-defined
+    /// boundaries; empty for fixed `CHUNK_SIZE` chunks
+    pub chunk_sizes: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadBeginResponse {
+    pub upload_id: String,
+    /// Chunks the server still needs, as sorted half-open ranges
+    pub missing_chunks: Vec<Range<u32>>,
+}
+
+impl UploadBeginRequest {
+    pub fn to_payload(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing to memory cannot fail")
+    }
+
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        bincode::deserialize(payload).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid upload request: {}", e),
+            )
+        })
+    }
+}
+
+impl UploadBeginResponse {
+    pub fn to_payload(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing to memory cannot fail")
+    }
+
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        bincode::deserialize(payload).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid upload response: {}", e),
+            )
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct ChunkDownloadRequest {
     pub filename: String,
     pub chunk_number: u32,
     pub chunk_size: u32,
+    /// Which version to read; 0 is the current file
+    pub version: u64,
 }
 
 impl ChunkDownloadRequest {
+    /// Format: [filename_len: u32][filename][chunk_num: u32][chunk_size: u32][version: u64]
     pub fn to_payload(&self) -> Vec<u8> {
         let filename_bytes = self.filename.as_bytes();
         let filename_len = filename_bytes.len() as u32;
@@ -331,6 +619,7 @@ impl ChunkDownloadRequest {
         payload.extend_from_slice(filename_bytes);
         payload.extend_from_slice(&self.chunk_number.to_be_bytes());
         payload.extend_from_slice(&self.chunk_size.to_be_bytes());
+        payload.extend_from_slice(&self.version.to_be_bytes());
         payload
     }
 
@@ -364,10 +653,222 @@ impl ChunkDownloadRequest {
             payload[offset + 2],
             payload[offset + 3],
         ]);
+        offset += 4;
+        let version = match payload.get(offset..offset + 8) {
+            Some(bytes) => u64::from_be_bytes(bytes.try_into().unwrap()),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Missing version in payload",
+                ))
+            }
+        };
         Ok(Self {
             filename,
             chunk_number,
             chunk_size,
+            version,
+        })
+    }
+}
+
+/// Finish an upload session. The server only commits the file if the
+/// reassembled data matches this size and SHA-256.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoreCompleteRequest {
+    pub upload_id: String,
+    pub total_size: u64,
+    pub checksum: [u8; 32],
+}
+
+impl StoreCompleteRequest {
+    pub fn to_payload(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing to memory cannot fail")
+    }
+
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        bincode::deserialize(payload).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid store complete request: {}", e),
+            )
+        })
+    }
+}
+
+/// SHA-256 hashes of consecutive chunks of an upload session, starting at
+/// `first_chunk`. The server copies chunks it already stores into the session
+/// and replies with the chunks still missing, like `UploadStatus`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct HaveChunksRequest {
+    pub upload_id: String,
+    pub first_chunk: u32,
+    pub hashes: Vec<[u8; 32]>,
+}
+
+impl HaveChunksRequest {
+    pub fn to_payload(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing to memory cannot fail")
+    }
+
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        bincode::deserialize(payload).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid chunk offer: {}", e),
+            )
+        })
+    }
+}
+
+/// Ask for rsync-style signatures of every `block_size` block of the
+/// current version of a file
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SignaturesRequest {
+    pub filename: String,
+    pub block_size: u32,
+}
+
+/// Block signatures plus the SHA-256 of the file they were taken from,
+/// which a delta must quote back as its base
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SignaturesResponse {
+    pub checksum: [u8; 32],
+    pub signatures: Vec<BlockSignature>,
+}
+
+/// One chunk of an upload session, sent as copies from the current file
+/// plus literal bytes instead of its full contents
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DeltaChunk {
+    pub upload_id: String,
+    pub chunk_number: u32,
+    pub total_chunks: u32,
+    pub base_checksum: [u8; 32],
+    pub instructions: Vec<DeltaInstruction>,
+}
+
+impl SignaturesRequest {
+    pub fn to_payload(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing to memory cannot fail")
+    }
+
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        bincode::deserialize(payload).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid signatures request: {}", e),
+            )
+        })
+    }
+}
+
+impl SignaturesResponse {
+    pub fn to_payload(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing to memory cannot fail")
+    }
+
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        bincode::deserialize(payload).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid signatures response: {}", e),
+            )
+        })
+    }
+}
+
+impl DeltaChunk {
+    pub fn to_payload(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing to memory cannot fail")
+    }
+
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        bincode::deserialize(payload).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid delta chunk: {}", e),
+            )
+        })
+    }
+}
+
+/// Ask for the SHA-256 of every `chunk_size` block of a stored file, so a
+/// client can tell which parts of a partial local copy are already correct
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChunkHashesRequest {
+    pub filename: String,
+    pub chunk_size: u32,
+    /// Which version to hash; 0 is the current file
+    pub version: u64,
+}
+
+impl ChunkHashesRequest {
+    pub fn to_payload(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing to memory cannot fail")
+    }
+
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        bincode::deserialize(payload).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid chunk hash request: {}", e),
+            )
+        })
+    }
+}
+
+/// Which directory to list and which page of it. An empty payload asks for
+/// the first page of the root, non-recursively.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ListRequest {
+    pub path: String,
+    pub recursive: bool,
+    /// Only entries whose path matches this glob or prefix
+    pub filter: Option<String>,
+    pub sort: SortKey,
+    /// Entries per page; 0 means `MAX_LIST_PAGE`, which also caps larger values
+    pub limit: u32,
+    /// Where the previous page ended, from its `Listing::next_cursor`
+    pub cursor: Option<String>,
+}
+
+impl ListRequest {
+    pub fn to_payload(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing to memory cannot fail")
+    }
+
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        if payload.is_empty() {
+            return Ok(Self::default());
+        }
+        bincode::deserialize(payload).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid list request: {}", e),
+            )
+        })
+    }
+}
+
+/// Source and destination of a `Rename` or `Copy`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MoveRequest {
+    pub from: String,
+    pub to: String,
+}
+
+impl MoveRequest {
+    pub fn to_payload(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing to memory cannot fail")
+    }
+
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        bincode::deserialize(payload).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid rename or copy request: {}", e),
+            )
         })
     }
 }
@@ -382,22 +883,29 @@ pub struct ChunkDownloadResponse {
 }
 
 impl ChunkDownloadResponse {
-    pub fn to_payload(&self) -> Vec<u8> {
-        let mut payload = Vec::with_capacity(12 + self.data.len());
+    /// Format: [chunk_num: u32][total_chunks: u32][bytes_in_chunk: u32][encoding: u8][data]
+    pub fn to_payload(&self, compress: bool) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(13 + self.data.len());
         payload.extend_from_slice(&self.chunk_number.to_be_bytes());
         payload.extend_from_slice(&self.total_chunks.to_be_bytes());
         payload.extend_from_slice(&self.bytes_in_chunk.to_be_bytes());
-        payload.extend_from_slice(&self.data);
+        encode_chunk_data(&mut payload, &self.data, compress);
         payload
     }
     pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
-        if payload.len() < 12 {
+        if payload.len() < 13 {
             return Err(Error::new(ErrorKind::InvalidData, "Payload too short"));
         }
         let chunk_number = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
         let total_chunks = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
         let bytes_in_chunk = u32::from_be_bytes([payload[8], payload[9], payload[10], payload[11]]);
-        let data = payload[12..].to_vec();
+        let data = decode_chunk_data(&payload[12..])?;
+        if data.len() != bytes_in_chunk as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Chunk length does not match its header",
+            ));
+        }
         Ok(Self {
             chunk_number,
             total_chunks,
@@ -406,6 +914,26 @@ impl ChunkDownloadResponse {
         })
     }
 }
+
+/// Append the encoding flag and chunk data, compressed if asked and worthwhile
+fn encode_chunk_data(payload: &mut Vec<u8>, data: &[u8], compress: bool) {
+    if compress {
+        let (encoding, bytes) = compression::compress(data);
+        payload.push(encoding as u8);
+        payload.extend_from_slice(&bytes);
+    } else {
+        payload.push(Encoding::Raw as u8);
+        payload.extend_from_slice(data);
+    }
+}
+
+fn decode_chunk_data(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let (&flag, data) = bytes
+        .split_first()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Missing chunk encoding"))?;
+    compression::decompress(Encoding::from_u8(flag)?, data, MAX_CHUNK_SIZE)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -418,7 +946,7 @@ mod tests {
 
     #[test]
     fn test_message_with_auth() {
-        let token = generate_auth_token("my_secret_password");
+        let token = auth::password_key("my_secret_password", b"salt", 1);
         let msg = Message::new_with_auth(Operation::Store, b"data".to_vec(), token);
         assert_eq!(msg.auth_token, token);
     }
@@ -437,4 +965,130 @@ mod tests {
         assert_eq!(parsed.payload, b"test");
         assert!(parsed.verify_checksum());
     }
+
+    #[test]
+    fn test_hello_roundtrip() {
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: capability::COMPRESSION | capability::DIRECTORIES,
+        };
+        assert_eq!(Hello::from_payload(&hello.to_payload()).unwrap(), hello);
+        assert!(Hello::from_payload(&[0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_status_codes_for_older_clients() {
+        for status in [
+            StatusCode::ErrorInvalidRequest,
+            StatusCode::ErrorAlreadyExists,
+        ] {
+            assert_eq!(StatusCode::from_u8(status as u8).unwrap(), status);
+            assert_eq!(status.for_capabilities(capability::SUPPORTED), status);
+            assert_eq!(
+                status.for_capabilities(capability::SUPPORTED & !capability::STATUS_CODES),
+                StatusCode::ErrorInvalidData
+            );
+        }
+        let rejected = StatusCode::ErrorMessageRejected;
+        assert_eq!(StatusCode::from_u8(rejected as u8).unwrap(), rejected);
+        assert_eq!(
+            rejected.for_capabilities(capability::SUPPORTED & !capability::STATUS_CODES),
+            StatusCode::ErrorPermissionDenied
+        );
+        assert_eq!(
+            StatusCode::ErrorNotFound.for_capabilities(0),
+            StatusCode::ErrorNotFound
+        );
+    }
+
+    #[test]
+    fn test_list_request_payload() {
+        assert_eq!(
+            ListRequest::from_payload(&[]).unwrap(),
+            ListRequest::default()
+        );
+        let req = ListRequest {
+            path: "docs/2024".to_string(),
+            recursive: true,
+            filter: Some("*.pdf".to_string()),
+            sort: SortKey::Size,
+            limit: 50,
+            cursor: Some("next".to_string()),
+        };
+        assert_eq!(ListRequest::from_payload(&req.to_payload()).unwrap(), req);
+    }
+
+    #[test]
+    fn test_chunk_download_request_roundtrip() {
+        let req = ChunkDownloadRequest {
+            filename: "docs/a.txt".to_string(),
+            chunk_number: 7,
+            chunk_size: CHUNK_SIZE as u32,
+            version: 1_700_000_000_000_000,
+        };
+        let parsed = ChunkDownloadRequest::from_payload(&req.to_payload()).unwrap();
+        assert_eq!(parsed.filename, req.filename);
+        assert_eq!(parsed.chunk_number, 7);
+        assert_eq!(parsed.version, req.version);
+
+        let payload = req.to_payload();
+        assert!(ChunkDownloadRequest::from_payload(&payload[..payload.len() - 8]).is_err());
+    }
+
+    #[test]
+    fn test_chunk_payloads_compress_when_asked() {
+        let chunk = ChunkMetadata {
+            upload_id: "abc".to_string(),
+            chunk_number: 2,
+            total_chunks: 5,
+            data: vec![b'x'; 10_000],
+        };
+        let raw = chunk.to_payload(false);
+        let compressed = chunk.to_payload(true);
+        assert!(compressed.len() < raw.len() / 10);
+        for payload in [raw, compressed] {
+            let parsed = ChunkMetadata::from_payload(&payload).unwrap();
+            assert_eq!(parsed.chunk_number, 2);
+            assert_eq!(parsed.data, chunk.data);
+        }
+
+        let response = ChunkDownloadResponse {
+            chunk_number: 0,
+            total_chunks: 1,
+            bytes_in_chunk: 10_000,
+            data: chunk.data.clone(),
+        };
+        let parsed = ChunkDownloadResponse::from_payload(&response.to_payload(true)).unwrap();
+        assert_eq!(parsed.data, response.data);
+        let mut lying = response.to_payload(false);
+        lying[11] ^= 1;
+        assert!(ChunkDownloadResponse::from_payload(&lying).is_err());
+    }
+
+    #[test]
+    fn test_frame_length_limit() {
+        assert_eq!(
+            frame_length(MAX_FRAME_LEN.to_be_bytes()).unwrap(),
+            MAX_FRAME_LEN
+        );
+        assert!(frame_length((MAX_FRAME_LEN + 1).to_be_bytes()).is_err());
+        assert!(frame_length(u32::MAX.to_be_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_message_mac() {
+        let key = [7u8; 32];
+        let mut msg = Message::new(Operation::List, Vec::new());
+        msg.set_request_id(3);
+        msg.sign(&key, auth::Direction::Request);
+        assert!(msg.verify_mac(&key, auth::Direction::Request));
+        assert!(!msg.verify_mac(&[8u8; 32], auth::Direction::Request));
+
+        // A signed request does not pass as a response
+        assert!(!msg.verify_mac(&key, auth::Direction::Response));
+
+        // The MAC is bound to the request ID
+        msg.set_request_id(4);
+        assert!(!msg.verify_mac(&key, auth::Direction::Request));
+    }
 }
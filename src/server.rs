@@ -1,47 +1,230 @@
-use crate::protocol::{generate_auth_token, ChunkMetadata, Message, Operation, StatusCode};
+use crate::auth;
+use crate::delta;
+use crate::protocol::{
+    capability, frame_length, ChunkHashesRequest, ChunkMetadata, DeltaChunk, HaveChunksRequest,
+    Hello, ListRequest, Message, MoveRequest, Operation, SignaturesRequest, SignaturesResponse,
+    StatusCode, StoreCompleteRequest, UploadBeginRequest, UploadBeginResponse, MAX_FRAME_LEN,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 use crate::protocol::{ChunkDownloadRequest, ChunkDownloadResponse};
-use crate::storage::Storage;
+use crate::storage::{Overwrite, Storage, VersionRetention};
+use crate::tls::Transport;
+use crate::users::{self, Account, Role, UserDatabase};
+use std::collections::HashMap;
 use std::error::Error;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 
 pub async fn run(
     bind_addr: String,
     storage_path: String,
     password: String,
+    tls: Option<TlsAcceptor>,
+    retention: VersionRetention,
+    compress_chunks: bool,
+    max_upload_size: u64,
 ) -> Result<(), Box<dyn Error>> {
-    let storage = Arc::new(Storage::new(&storage_path)?);
+    let users_file = users::users_file(&storage_path);
+    let accounts = if users_file.exists() {
+        let db = UserDatabase::load(&users_file)?;
+        if db.upgraded() > 0 {
+            db.save(&users_file)?;
+            println!(
+                "Converted {} user record(s) to keys that cannot be used to log in",
+                db.upgraded()
+            );
+        }
+        println!(
+            "User database: {} ({} user(s)), each with a private namespace",
+            users_file.display(),
+            db.users().count()
+        );
+        Accounts::per_user(
+            PathBuf::from(&storage_path),
+            retention,
+            compress_chunks,
+            max_upload_size,
+        )
+    } else {
+        let storage = Storage::with_retention(&storage_path, retention)?
+            .with_compression(compress_chunks)
+            .with_max_upload_size(max_upload_size);
+        println!("No user database; all clients share the configured password");
+        Accounts::shared(&password, storage)
+    };
+    let accounts = Arc::new(accounts);
     println!("Storage initialized at: {}", storage_path);
 
-    // CHANGE: Use password parameter instead of SERVER_PASSWORD
-    let auth_token = generate_auth_token(&password);
-    println!("Server auth token configured");
-
     let listener = TcpListener::bind(&bind_addr).await?;
-    println!("Server listening on {}", bind_addr);
+    let scheme = if tls.is_some() { "TLS" } else { "plain TCP" };
+    println!("Server listening on {} ({})", bind_addr, scheme);
     println!("Access from other devices using your local IP address\n");
 
     loop {
         let (socket, addr) = listener.accept().await?;
         println!("[{}] New connection", addr);
 
-        let storage = Arc::clone(&storage);
+        let accounts = Arc::clone(&accounts);
+        let tls = tls.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_client(socket, storage, auth_token).await {
+            let result = match tls {
+                Some(acceptor) => match acceptor.accept(socket).await {
+                    Ok(stream) => handle_client(stream, addr, accounts).await,
+                    Err(e) => Err(format!("TLS handshake failed: {}", e).into()),
+                },
+                None => handle_client(socket, addr, accounts).await,
+            };
+            if let Err(e) = result {
                 eprintln!("[{}] Error:  {}", addr, e);
             }
         });
     }
 }
 
-async fn handle_client(
-    mut socket: TcpStream,
-    storage: Arc<Storage>,
-    expected_token: [u8; 32],
+/// Who may log in, and which storage each login sees
+enum AccountMode {
+    /// No user database: one password and one namespace for everybody
+    Shared {
+        credentials: auth::Credentials,
+        storage: Arc<Storage>,
+    },
+    /// Accounts from the user database, each confined to its own subtree.
+    /// The database is re-read on every login so `netbackup user` changes
+    /// apply without a restart.
+    PerUser {
+        storage_path: PathBuf,
+        retention: VersionRetention,
+        compress_chunks: bool,
+        max_upload_size: u64,
+        storages: Mutex<HashMap<String, Arc<Storage>>>,
+    },
+}
+
+struct Accounts {
+    mode: AccountMode,
+    /// Keys the decoy salts handed out for unknown usernames
+    decoy_secret: [u8; 32],
+}
+
+impl Accounts {
+    fn shared(password: &str, storage: Storage) -> Self {
+        Self::new(AccountMode::Shared {
+            credentials: auth::Credentials::new(password, auth::PASSWORD_ITERATIONS),
+            storage: Arc::new(storage),
+        })
+    }
+
+    fn per_user(
+        storage_path: PathBuf,
+        retention: VersionRetention,
+        compress_chunks: bool,
+        max_upload_size: u64,
+    ) -> Self {
+        Self::new(AccountMode::PerUser {
+            storage_path,
+            retention,
+            compress_chunks,
+            max_upload_size,
+            storages: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn new(mode: AccountMode) -> Self {
+        Self {
+            mode,
+            decoy_secret: auth::generate_nonce(),
+        }
+    }
+
+    /// The account to challenge `username` with, if it exists. The shared
+    /// password grants full access.
+    fn account(&self, username: &str) -> Option<Account> {
+        match &self.mode {
+            AccountMode::Shared { credentials, .. } => Some(Account {
+                credentials: credentials.clone(),
+                role: Role::Admin,
+                namespace: String::new(),
+            }),
+            AccountMode::PerUser { storage_path, .. } => {
+                match UserDatabase::load(&users::users_file(storage_path)) {
+                    Ok(db) => db.account(username),
+                    Err(e) => {
+                        eprintln!("✗ Failed to read user database: {}", e);
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    /// The storage behind a namespace, opened on first use and shared by
+    /// every session working on it
+    fn storage(&self, namespace: &str) -> std::io::Result<Arc<Storage>> {
+        match &self.mode {
+            AccountMode::Shared { storage, .. } => Ok(Arc::clone(storage)),
+            AccountMode::PerUser {
+                storage_path,
+                retention,
+                compress_chunks,
+                max_upload_size,
+                storages,
+            } => {
+                let mut storages = storages.lock().unwrap();
+                if let Some(storage) = storages.get(namespace) {
+                    return Ok(Arc::clone(storage));
+                }
+                let storage = Arc::new(
+                    Storage::with_retention(users::user_root(storage_path, namespace), *retention)?
+                        .with_compression(*compress_chunks)
+                        .with_max_upload_size(*max_upload_size),
+                );
+                storages.insert(namespace.to_string(), Arc::clone(&storage));
+                Ok(storage)
+            }
+        }
+    }
+}
+
+/// Per-connection authentication state
+enum AuthState {
+    /// Waiting for the client to request a challenge
+    Start,
+    /// Nonce sent, waiting for the client's proof. `account` is None for an
+    /// unknown user, whose proof can never verify.
+    Challenged {
+        server_nonce: [u8; auth::NONCE_LEN],
+        username: String,
+        account: Option<Account>,
+    },
+    /// Handshake complete; every request must carry a MAC under this key
+    Authenticated {
+        session_key: [u8; 32],
+        storage: Arc<Storage>,
+        session: Session,
+    },
+}
+
+/// Who an authenticated connection acts as
+struct Session {
+    username: String,
+    role: Role,
+}
+
+async fn handle_client<S: Transport>(
+    mut socket: S,
+    peer_addr: SocketAddr,
+    accounts: Arc<Accounts>,
 ) -> Result<(), Box<dyn Error>> {
-    let peer_addr = socket.peer_addr()?;
-    let mut authenticated = false;
+    let mut state = AuthState::Start;
+    // Capability set agreed in the hello exchange; nothing else is accepted before it
+    let mut capabilities: Option<u32> = None;
+    // Digest of that exchange, which the auth handshake proves both sides saw
+    let mut transcript = [0u8; 32];
+    let mut last_request_id = 0u32;
     let mut request_counter = 0u32;
 
     loop {
@@ -55,7 +238,7 @@ async fn handle_client(
             Err(e) => return Err(e.into()),
         }
 
-        let length = u32::from_be_bytes(len_bytes);
+        let length = frame_length(len_bytes)?;
         let mut data = vec![0u8; length as usize];
         socket.read_exact(&mut data).await?;
 
@@ -67,72 +250,631 @@ async fn handle_client(
                     request_counter,
                     Operation::Store,
                     StatusCode::ErrorInvalidData,
-                    b"Invalid message format".to_vec(),
+                    format!("Invalid message format: {}", e).into_bytes(),
                 );
-                socket.write_all(&error_response.to_bytes()).await?;
+                send_response(&mut socket, error_response, &state).await?;
                 continue;
             }
         };
 
         request_counter += 1;
 
-        if !matches!(message.operation, Operation::Auth) {
-            if !authenticated {
-                let response = Message::new_response(
-                    message.request_id,
-                    message.operation,
-                    StatusCode::ErrorPermissionDenied,
-                    b"Authentication required".to_vec(),
-                );
-                socket.write_all(&response.to_bytes()).await?;
-                continue;
+        let response = if message.operation == Operation::Hello {
+            let response = handle_hello(&message, &mut capabilities, peer_addr);
+            if response.status == StatusCode::Success {
+                transcript = auth::hello_transcript(&message.payload, &response.payload);
             }
-
-            if message.auth_token != expected_token {
-                let response = Message::new_response(
+            response
+        } else if capabilities.is_none() {
+            Message::new_response(
+                message.request_id,
+                message.operation,
+                StatusCode::ErrorIncompatibleVersion,
+                b"Protocol hello required before any other operation".to_vec(),
+            )
+        } else if message.operation == Operation::Auth {
+            if matches!(state, AuthState::Authenticated { .. }) {
+                // Auth frames carry no MAC, so one must not touch a live session
+                Message::new_response(
                     message.request_id,
                     message.operation,
                     StatusCode::ErrorPermissionDenied,
-                    b"Invalid authentication token".to_vec(),
-                );
-                socket.write_all(&response.to_bytes()).await?;
-                continue;
-            }
-        }
-
-        let response = if message.operation == Operation::Auth {
-            if message.auth_token == expected_token {
-                authenticated = true;
-                println!("[{}] ✓ Client authenticated", peer_addr);
-                Message::new_response(
-                    message.request_id,
-                    Operation::Auth,
-                    StatusCode::Success,
-                    b"Authenticated".to_vec(),
+                    b"Already authenticated".to_vec(),
                 )
             } else {
-                println!("[{}] ✗ Authentication failed", peer_addr);
-                Message::new_response(
+                let response = handle_auth(&message, &mut state, &accounts, &transcript, peer_addr);
+                last_request_id = last_request_id.max(message.request_id);
+                response
+            }
+        } else {
+            match &state {
+                AuthState::Authenticated {
+                    session_key,
+                    storage,
+                    session,
+                } => {
+                    let negotiated = capabilities.unwrap_or(0);
+                    if !message.verify_mac(session_key, auth::Direction::Request) {
+                        Message::new_response(
+                            message.request_id,
+                            message.operation,
+                            StatusCode::ErrorMessageRejected.for_capabilities(negotiated),
+                            b"Invalid message authentication code".to_vec(),
+                        )
+                    } else if message.request_id <= last_request_id {
+                        eprintln!(
+                            "[{}] ✗ Rejected replayed request {}",
+                            peer_addr, message.request_id
+                        );
+                        Message::new_response(
+                            message.request_id,
+                            message.operation,
+                            StatusCode::ErrorMessageRejected.for_capabilities(negotiated),
+                            b"Replayed or out-of-order request".to_vec(),
+                        )
+                    } else {
+                        last_request_id = message.request_id;
+                        let required = message.operation.required_capability();
+                        if negotiated & required != required {
+                            Message::new_response(
+                                message.request_id,
+                                message.operation,
+                                StatusCode::ErrorUnsupported,
+                                format!(
+                                    "{:?} requires capability '{}', which was not negotiated",
+                                    message.operation,
+                                    capability::names(required).join(", ")
+                                )
+                                .into_bytes(),
+                            )
+                        } else {
+                            let mut response =
+                                handle_storage_operation(message, storage, negotiated, session);
+                            response.status = response.status.for_capabilities(negotiated);
+                            response
+                        }
+                    }
+                }
+                _ => Message::new_response(
                     message.request_id,
-                    Operation::Auth,
+                    message.operation,
                     StatusCode::ErrorPermissionDenied,
-                    b"Invalid password".to_vec(),
-                )
+                    b"Authentication required".to_vec(),
+                ),
             }
-        } else {
-            handle_storage_operation(message, &storage)
         };
 
-        socket.write_all(&response.to_bytes()).await?;
+        send_response(&mut socket, response, &state).await?;
+    }
+}
+
+/// Sign the response with the session key (once there is one) and send it.
+/// A response too long for the client to read is replaced by an error.
+async fn send_response<S: Transport>(
+    socket: &mut S,
+    mut response: Message,
+    state: &AuthState,
+) -> Result<(), Box<dyn Error>> {
+    if response.to_bytes().len() - 4 > MAX_FRAME_LEN as usize {
+        response = Message::new_response(
+            response.request_id,
+            response.operation,
+            StatusCode::ErrorServerError,
+            b"Response too large".to_vec(),
+        );
+    }
+    if let AuthState::Authenticated { session_key, .. } = state {
+        response.sign(session_key, auth::Direction::Response);
+    }
+    socket.write_all(&response.to_bytes()).await?;
+    Ok(())
+}
+
+/// Agree on a protocol version and the capabilities both sides support
+fn handle_hello(
+    message: &Message,
+    capabilities: &mut Option<u32>,
+    peer_addr: SocketAddr,
+) -> Message {
+    if capabilities.is_some() {
+        return Message::new_response(
+            message.request_id,
+            Operation::Hello,
+            StatusCode::ErrorInvalidData,
+            b"Hello already completed".to_vec(),
+        );
+    }
+
+    let hello = match Hello::from_payload(&message.payload) {
+        Ok(hello) => hello,
+        Err(e) => {
+            return Message::new_response(
+                message.request_id,
+                Operation::Hello,
+                StatusCode::ErrorInvalidData,
+                format!("Malformed hello: {}", e).into_bytes(),
+            )
+        }
+    };
+
+    if hello.version < MIN_PROTOCOL_VERSION {
+        println!(
+            "[{}] ✗ Rejected client with protocol v{}",
+            peer_addr, hello.version
+        );
+        return Message::new_response(
+            message.request_id,
+            Operation::Hello,
+            StatusCode::ErrorIncompatibleVersion,
+            format!(
+                "Protocol version {} is not supported; server accepts versions {} to {}",
+                hello.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )
+            .into_bytes(),
+        );
     }
+
+    let negotiated = hello.capabilities & capability::SUPPORTED;
+    *capabilities = Some(negotiated);
+    println!(
+        "[{}] Hello: protocol v{}, capabilities [{}]",
+        peer_addr,
+        hello.version.min(PROTOCOL_VERSION),
+        capability::names(negotiated).join(", ")
+    );
+
+    Message::new_response(
+        message.request_id,
+        Operation::Hello,
+        StatusCode::Success,
+        Hello {
+            version: hello.version.min(PROTOCOL_VERSION),
+            capabilities: negotiated,
+        }
+        .to_payload(),
+    )
 }
 
-fn handle_storage_operation(message: Message, storage: &Storage) -> Message {
+/// Two-step challenge-response handshake.
+/// The first message names the user and is answered with a nonce, salt and
+/// PBKDF2 rounds; the second carries [client_nonce][proof].
+fn handle_auth(
+    message: &Message,
+    state: &mut AuthState,
+    accounts: &Accounts,
+    transcript: &[u8; 32],
+    peer_addr: SocketAddr,
+) -> Message {
+    let (server_nonce, username, account) = match std::mem::replace(state, AuthState::Start) {
+        // A challenge is good for exactly one attempt
+        AuthState::Challenged {
+            server_nonce,
+            username,
+            account,
+        } => (server_nonce, username, account),
+        _ => return issue_challenge(message, state, accounts),
+    };
+
+    let (client_nonce, proof) = match auth::decode_auth_response(&message.payload) {
+        Some(parts) => parts,
+        None => {
+            return Message::new_response(
+                message.request_id,
+                Operation::Auth,
+                StatusCode::ErrorInvalidData,
+                b"Malformed auth response".to_vec(),
+            )
+        }
+    };
+
+    let auth_message = auth::auth_message(&username, &server_nonce, &client_nonce, transcript);
+    let verified = account.and_then(|account| {
+        auth::verify_client_proof(&account.credentials.stored_key, &auth_message, &proof)
+            .map(|client_key| (account, client_key))
+    });
+    let (account, client_key) = match verified {
+        Some(verified) => verified,
+        None => {
+            println!("[{}] ✗ Authentication failed for '{}'", peer_addr, username);
+            return Message::new_response(
+                message.request_id,
+                Operation::Auth,
+                StatusCode::ErrorPermissionDenied,
+                b"Invalid username or password".to_vec(),
+            );
+        }
+    };
+    let storage = match accounts.storage(&account.namespace) {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!(
+                "[{}] ✗ Failed to open storage for {}: {}",
+                peer_addr, username, e
+            );
+            return Message::new_response(
+                message.request_id,
+                Operation::Auth,
+                StatusCode::ErrorServerError,
+                b"Failed to open user storage".to_vec(),
+            );
+        }
+    };
+
+    match &accounts.mode {
+        AccountMode::Shared { .. } => println!("[{}] ✓ Client authenticated", peer_addr),
+        AccountMode::PerUser { .. } if account.namespace != username => println!(
+            "[{}] ✓ Authenticated as {} ({}, working on {}'s files)",
+            peer_addr, username, account.role, account.namespace
+        ),
+        AccountMode::PerUser { .. } => println!(
+            "[{}] ✓ Authenticated as {} ({})",
+            peer_addr, username, account.role
+        ),
+    }
+    *state = AuthState::Authenticated {
+        session_key: auth::derive_session_key(&client_key, &auth_message),
+        storage,
+        session: Session {
+            username,
+            role: account.role,
+        },
+    };
+    Message::new_response(
+        message.request_id,
+        Operation::Auth,
+        StatusCode::Success,
+        auth::server_proof(&account.credentials.server_key, &auth_message).to_vec(),
+    )
+}
+
+/// First handshake step: look the user up and send the challenge. Unknown
+/// users get a decoy salt so the answer does not reveal whether they exist.
+fn issue_challenge(message: &Message, state: &mut AuthState, accounts: &Accounts) -> Message {
+    let username = match String::from_utf8(message.payload.clone()) {
+        Ok(username) => username,
+        Err(_) => {
+            return Message::new_response(
+                message.request_id,
+                Operation::Auth,
+                StatusCode::ErrorInvalidData,
+                b"Username is not valid UTF-8".to_vec(),
+            )
+        }
+    };
+
+    let account = accounts.account(&username);
+    let (salt, iterations) = match &account {
+        Some(account) => (account.credentials.salt, account.credentials.iterations),
+        None => (
+            auth::decoy_salt(&accounts.decoy_secret, &username),
+            auth::PASSWORD_ITERATIONS,
+        ),
+    };
+    let challenge = auth::Challenge {
+        server_nonce: auth::generate_nonce(),
+        salt,
+        iterations,
+    };
+    *state = AuthState::Challenged {
+        server_nonce: challenge.server_nonce,
+        username,
+        account,
+    };
+    Message::new_response(
+        message.request_id,
+        Operation::Auth,
+        StatusCode::Success,
+        challenge.to_payload(),
+    )
+}
+
+/// Map a storage error onto the closest protocol status
+fn status_for(e: &std::io::Error) -> StatusCode {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => StatusCode::ErrorNotFound,
+        std::io::ErrorKind::InvalidData => StatusCode::ErrorInvalidData,
+        std::io::ErrorKind::InvalidInput => StatusCode::ErrorInvalidRequest,
+        std::io::ErrorKind::AlreadyExists => StatusCode::ErrorAlreadyExists,
+        _ => StatusCode::ErrorServerError,
+    }
+}
+
+/// Status for a failed write. An append account that loses the race for a
+/// new name is refused just as if the file had existed when it asked.
+fn write_status(e: &std::io::Error, overwrite: Overwrite) -> StatusCode {
+    if overwrite == Overwrite::Refuse && e.kind() == std::io::ErrorKind::AlreadyExists {
+        StatusCode::ErrorPermissionDenied
+    } else {
+        status_for(e)
+    }
+}
+
+/// Refuse what the session's role does not allow, with the reason why
+fn check_permission(message: &Message, storage: &Storage, role: Role) -> Result<(), String> {
+    if !role.permits(message.operation) {
+        return Err(format!(
+            "Permission denied: {} accounts may not use {:?}",
+            role, message.operation
+        ));
+    }
+    if role != Role::Append {
+        return Ok(());
+    }
+
+    // Append-only accounts may add files but never replace one
+    let filename = match message.operation {
+        Operation::Store => message
+            .payload
+            .iter()
+            .position(|&b| b == 0)
+            .map(|pos| String::from_utf8_lossy(&message.payload[..pos]).to_string()),
+        Operation::UploadBegin => UploadBeginRequest::from_payload(&message.payload)
+            .ok()
+            .map(|req| req.filename),
+        Operation::Copy => MoveRequest::from_payload(&message.payload)
+            .ok()
+            .map(|req| req.to),
+        _ => None,
+    };
+    match filename.map(|name| (storage.exists(&name), name)) {
+        Some((Ok(true), name)) => Err(format!(
+            "Permission denied: append accounts may not replace existing file {}",
+            name
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn handle_storage_operation(
+    message: Message,
+    storage: &Storage,
+    capabilities: u32,
+    session: &Session,
+) -> Message {
+    if let Err(reason) = check_permission(&message, storage, session.role) {
+        eprintln!(
+            "✗ DENIED: {} ({}) {:?}: {}",
+            session.username, session.role, message.operation, reason
+        );
+        return Message::new_response(
+            message.request_id,
+            message.operation,
+            StatusCode::ErrorPermissionDenied,
+            reason.into_bytes(),
+        );
+    }
+    // The check above can be overtaken by another session, so append writes
+    // are also refused when they land on an existing file
+    let overwrite = if session.role == Role::Append {
+        Overwrite::Refuse
+    } else {
+        Overwrite::Replace
+    };
+
     match message.operation {
+        Operation::UploadBegin => match UploadBeginRequest::from_payload(&message.payload) {
+            Ok(req) => match storage.begin_upload(
+                &req.filename,
+                req.total_size,
+                req.total_chunks,
+                req.checksum,
+                req.chunk_sizes.clone(),
+            ) {
+                Ok((upload_id, missing_chunks)) => {
+                    let missing: usize = missing_chunks.iter().map(|r| r.len()).sum();
+                    if missing < req.total_chunks as usize {
+                        println!(
+                            "✓ UPLOAD RESUME: {} ({} of {} chunks missing)",
+                            req.filename, missing, req.total_chunks
+                        );
+                    } else {
+                        println!(
+                            "✓ UPLOAD BEGIN: {} ({} bytes)",
+                            req.filename, req.total_size
+                        );
+                    }
+                    Message::new_response(
+                        message.request_id,
+                        Operation::UploadBegin,
+                        StatusCode::Success,
+                        UploadBeginResponse {
+                            upload_id,
+                            missing_chunks,
+                        }
+                        .to_payload(),
+                    )
+                }
+                Err(e) => {
+                    eprintln!("✗ UPLOAD BEGIN failed: {}", e);
+                    Message::new_response(
+                        message.request_id,
+                        Operation::UploadBegin,
+                        status_for(&e),
+                        e.to_string().into_bytes(),
+                    )
+                }
+            },
+            Err(e) => Message::new_response(
+                message.request_id,
+                Operation::UploadBegin,
+                StatusCode::ErrorInvalidData,
+                e.to_string().into_bytes(),
+            ),
+        },
+        Operation::UploadStatus => {
+            let upload_id = String::from_utf8_lossy(&message.payload).to_string();
+
+            match storage.upload_status(&upload_id) {
+                Ok(missing) => Message::new_response(
+                    message.request_id,
+                    Operation::UploadStatus,
+                    StatusCode::Success,
+                    bincode::serialize(&missing).unwrap(),
+                ),
+                Err(e) => Message::new_response(
+                    message.request_id,
+                    Operation::UploadStatus,
+                    status_for(&e),
+                    e.to_string().into_bytes(),
+                ),
+            }
+        }
+        Operation::StoreDelta => match DeltaChunk::from_payload(&message.payload) {
+            Ok(chunk) => {
+                match storage.store_delta(
+                    &chunk.upload_id,
+                    chunk.chunk_number,
+                    chunk.total_chunks,
+                    &chunk.base_checksum,
+                    &chunk.instructions,
+                ) {
+                    Ok(complete) => {
+                        println!(
+                            "✓ DELTA CHUNK: {} - {}/{} ({} literal bytes){}",
+                            chunk.upload_id,
+                            chunk.chunk_number + 1,
+                            chunk.total_chunks,
+                            delta::literal_len(&chunk.instructions),
+                            if complete { " (COMPLETE)" } else { "" }
+                        );
+
+                        let status = if complete { "COMPLETE" } else { "OK" };
+                        Message::new_response(
+                            message.request_id,
+                            Operation::StoreDelta,
+                            StatusCode::Success,
+                            status.as_bytes().to_vec(),
+                        )
+                    }
+                    Err(e) => {
+                        eprintln!("✗ DELTA CHUNK failed: {}", e);
+                        Message::new_response(
+                            message.request_id,
+                            Operation::StoreDelta,
+                            status_for(&e),
+                            format!("Delta chunk failed: {}", e).into_bytes(),
+                        )
+                    }
+                }
+            }
+            Err(e) => Message::new_response(
+                message.request_id,
+                Operation::StoreDelta,
+                StatusCode::ErrorInvalidData,
+                e.to_string().into_bytes(),
+            ),
+        },
+        Operation::Signatures => match SignaturesRequest::from_payload(&message.payload) {
+            Ok(req) => match storage.block_signatures(&req.filename, req.block_size as usize) {
+                Ok((checksum, signatures)) => {
+                    println!(
+                        "✓ SIGNATURES: {} ({} blocks of {} bytes)",
+                        req.filename,
+                        signatures.len(),
+                        req.block_size
+                    );
+                    Message::new_response(
+                        message.request_id,
+                        Operation::Signatures,
+                        StatusCode::Success,
+                        SignaturesResponse {
+                            checksum,
+                            signatures,
+                        }
+                        .to_payload(),
+                    )
+                }
+                Err(e) => Message::new_response(
+                    message.request_id,
+                    Operation::Signatures,
+                    status_for(&e),
+                    e.to_string().into_bytes(),
+                ),
+            },
+            Err(e) => Message::new_response(
+                message.request_id,
+                Operation::Signatures,
+                StatusCode::ErrorInvalidData,
+                e.to_string().into_bytes(),
+            ),
+        },
+        Operation::HaveChunks => match HaveChunksRequest::from_payload(&message.payload) {
+            Ok(req) => match storage.offer_chunks(&req.upload_id, req.first_chunk, &req.hashes) {
+                Ok(missing) => {
+                    println!(
+                        "✓ HAVE CHUNKS: {} offered, {} still missing",
+                        req.hashes.len(),
+                        missing.len()
+                    );
+                    Message::new_response(
+                        message.request_id,
+                        Operation::HaveChunks,
+                        StatusCode::Success,
+                        bincode::serialize(&missing).unwrap(),
+                    )
+                }
+                Err(e) => {
+                    eprintln!("✗ HAVE CHUNKS failed: {}", e);
+                    Message::new_response(
+                        message.request_id,
+                        Operation::HaveChunks,
+                        status_for(&e),
+                        e.to_string().into_bytes(),
+                    )
+                }
+            },
+            Err(e) => Message::new_response(
+                message.request_id,
+                Operation::HaveChunks,
+                StatusCode::ErrorInvalidData,
+                e.to_string().into_bytes(),
+            ),
+        },
+        Operation::ChunkHashes => match ChunkHashesRequest::from_payload(&message.payload) {
+            Ok(req) => {
+                match storage.chunk_hashes(&req.filename, req.version, req.chunk_size as usize) {
+                    Ok(hashes) => {
+                        println!("✓ CHUNK HASHES: {} ({} chunks)", req.filename, hashes.len());
+                        Message::new_response(
+                            message.request_id,
+                            Operation::ChunkHashes,
+                            StatusCode::Success,
+                            bincode::serialize(&hashes).unwrap(),
+                        )
+                    }
+                    Err(e) => {
+                        eprintln!("✗ CHUNK HASHES failed: {}", e);
+                        Message::new_response(
+                            message.request_id,
+                            Operation::ChunkHashes,
+                            status_for(&e),
+                            e.to_string().into_bytes(),
+                        )
+                    }
+                }
+            }
+            Err(e) => Message::new_response(
+                message.request_id,
+                Operation::ChunkHashes,
+                StatusCode::ErrorInvalidData,
+                e.to_string().into_bytes(),
+            ),
+        },
         Operation::StoreChunk => match ChunkMetadata::from_payload(&message.payload) {
             Ok(chunk) => {
+                // The payload only comes out smaller than its data when compressed
+                let wire = match message
+                    .payload
+                    .len()
+                    .checked_sub(chunk.upload_id.len() + 13)
+                {
+                    Some(len) if len < chunk.data.len() => {
+                        format!(" (lz4: {} -> {} bytes)", chunk.data.len(), len)
+                    }
+                    _ => String::new(),
+                };
                 match storage.store_chunk(
-                    &chunk.filename,
+                    &chunk.upload_id,
                     chunk.chunk_number,
                     chunk.total_chunks,
                     chunk.data,
@@ -140,17 +882,19 @@ fn handle_storage_operation(message: Message, storage: &Storage) -> Message {
                     Ok(complete) => {
                         if complete {
                             println!(
-                                "✓ CHUNK: {} - {}/{} (COMPLETE)",
-                                chunk.filename,
+                                "✓ CHUNK: {} - {}/{} (COMPLETE){}",
+                                chunk.upload_id,
                                 chunk.chunk_number + 1,
-                                chunk.total_chunks
+                                chunk.total_chunks,
+                                wire
                             );
                         } else {
                             println!(
-                                "✓ CHUNK: {} - {}/{}",
-                                chunk.filename,
+                                "✓ CHUNK: {} - {}/{}{}",
+                                chunk.upload_id,
                                 chunk.chunk_number + 1,
-                                chunk.total_chunks
+                                chunk.total_chunks,
+                                wire
                             );
                         }
 
@@ -162,13 +906,13 @@ fn handle_storage_operation(message: Message, storage: &Storage) -> Message {
                             status.as_bytes().to_vec(),
                         )
                     }
-                    Err(_) => {
-                        eprintln!("✗ CHUNK STORE failed");
+                    Err(e) => {
+                        eprintln!("✗ CHUNK STORE failed: {}", e);
                         Message::new_response(
                             message.request_id,
                             Operation::StoreChunk,
-                            StatusCode::ErrorServerError,
-                            b"Chunk storage failed".to_vec(),
+                            status_for(&e),
+                            format!("Chunk storage failed: {}", e).into_bytes(),
                         )
                     }
                 }
@@ -181,10 +925,25 @@ fn handle_storage_operation(message: Message, storage: &Storage) -> Message {
             ),
         },
         Operation::StoreComplete => {
-            let filename = String::from_utf8_lossy(&message.payload).to_string();
+            let req = match StoreCompleteRequest::from_payload(&message.payload) {
+                Ok(req) => req,
+                Err(e) => {
+                    return Message::new_response(
+                        message.request_id,
+                        Operation::StoreComplete,
+                        StatusCode::ErrorInvalidData,
+                        e.to_string().into_bytes(),
+                    )
+                }
+            };
 
-            match storage.complete_chunked_upload(&filename) {
-                Ok(_) => {
+            match storage.complete_chunked_upload(
+                &req.upload_id,
+                req.total_size,
+                &req.checksum,
+                overwrite,
+            ) {
+                Ok(filename) => {
                     println!("✓ STORE COMPLETE: {}", filename);
                     Message::new_response(
                         message.request_id,
@@ -193,13 +952,13 @@ fn handle_storage_operation(message: Message, storage: &Storage) -> Message {
                         b"File stored successfully".to_vec(),
                     )
                 }
-                Err(_) => {
-                    eprintln!("✗ STORE COMPLETE failed");
+                Err(e) => {
+                    eprintln!("✗ STORE COMPLETE failed: {}", e);
                     Message::new_response(
                         message.request_id,
                         Operation::StoreComplete,
-                        StatusCode::ErrorServerError,
-                        b"Failed to finalize upload".to_vec(),
+                        write_status(&e, overwrite),
+                        format!("Failed to finalize upload: {}", e).into_bytes(),
                     )
                 }
             }
@@ -220,7 +979,7 @@ fn handle_storage_operation(message: Message, storage: &Storage) -> Message {
             let filename = String::from_utf8_lossy(&message.payload[..null_pos]).to_string();
             let file_data = &message.payload[null_pos + 1..];
 
-            match storage.store(&filename, file_data) {
+            match storage.store_with(&filename, file_data, overwrite) {
                 Ok(_) => {
                     println!("✓ STORE: {} ({} bytes)", filename, file_data.len());
                     Message::new_response(
@@ -230,13 +989,13 @@ fn handle_storage_operation(message: Message, storage: &Storage) -> Message {
                         b"OK".to_vec(),
                     )
                 }
-                Err(_) => {
-                    eprintln!("✗ STORE failed");
+                Err(e) => {
+                    eprintln!("✗ STORE failed: {}", e);
                     Message::new_response(
                         message.request_id,
                         Operation::Store,
-                        StatusCode::ErrorServerError,
-                        b"Storage failed".to_vec(),
+                        write_status(&e, overwrite),
+                        e.to_string().into_bytes(),
                     )
                 }
             }
@@ -254,22 +1013,13 @@ fn handle_storage_operation(message: Message, storage: &Storage) -> Message {
                         data,
                     )
                 }
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                    eprintln!("✗ RETRIEVE failed: File not found");
-                    Message::new_response(
-                        message.request_id,
-                        Operation::Retrieve,
-                        StatusCode::ErrorNotFound,
-                        b"File not found".to_vec(),
-                    )
-                }
-                Err(_) => {
-                    eprintln!("✗ RETRIEVE failed");
+                Err(e) => {
+                    eprintln!("✗ RETRIEVE failed: {}", e);
                     Message::new_response(
                         message.request_id,
                         Operation::Retrieve,
-                        StatusCode::ErrorServerError,
-                        b"Retrieval failed".to_vec(),
+                        status_for(&e),
+                        e.to_string().into_bytes(),
                     )
                 }
             }
@@ -278,10 +1028,8 @@ fn handle_storage_operation(message: Message, storage: &Storage) -> Message {
             match ChunkDownloadRequest::from_payload(&message.payload) {
                 Ok(req) => {
                     // file size
-                    use std::fs;
-                    let file_path = storage.root_dir().join(&req.filename);
-                    let meta = match fs::metadata(&file_path) {
-                        Ok(m) => m,
+                    let total_size = match storage.file_size(&req.filename, req.version) {
+                        Ok(size) => size as usize,
                         Err(e) => {
                             return Message::new_response(
                                 message.request_id,
@@ -291,22 +1039,25 @@ fn handle_storage_operation(message: Message, storage: &Storage) -> Message {
                             )
                         }
                     };
-                    let total_size = meta.len() as usize;
                     let chunk_size = req.chunk_size as usize;
-                    let total_chunks = ((total_size + chunk_size - 1) / chunk_size) as u32;
-
-                    let chunk_data =
-                        match storage.retrieve_chunk(&req.filename, req.chunk_number, chunk_size) {
-                            Ok(d) => d,
-                            Err(e) => {
-                                return Message::new_response(
-                                    message.request_id,
-                                    Operation::RetrieveChunk,
-                                    StatusCode::ErrorNotFound,
-                                    format!("Chunk read error: {}", e).into_bytes(),
-                                )
-                            }
-                        };
+                    let chunk_data = match storage.retrieve_chunk(
+                        &req.filename,
+                        req.version,
+                        req.chunk_number,
+                        chunk_size,
+                    ) {
+                        Ok(d) => d,
+                        Err(e) => {
+                            return Message::new_response(
+                                message.request_id,
+                                Operation::RetrieveChunk,
+                                status_for(&e),
+                                format!("Chunk read error: {}", e).into_bytes(),
+                            )
+                        }
+                    };
+                    // retrieve_chunk has rejected a zero chunk size by now
+                    let total_chunks = total_size.div_ceil(chunk_size) as u32;
                     let response = ChunkDownloadResponse {
                         chunk_number: req.chunk_number,
                         total_chunks,
@@ -317,7 +1068,7 @@ fn handle_storage_operation(message: Message, storage: &Storage) -> Message {
                         message.request_id,
                         Operation::RetrieveChunk,
                         StatusCode::Success,
-                        response.to_payload(),
+                        response.to_payload(capabilities & capability::COMPRESSION != 0),
                     )
                 }
                 Err(e) => Message::new_response(
@@ -341,52 +1092,812 @@ fn handle_storage_operation(message: Message, storage: &Storage) -> Message {
                         b"OK".to_vec(),
                     )
                 }
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                    eprintln!("✗ DELETE failed: File not found");
+                Err(e) => {
+                    eprintln!("✗ DELETE failed: {}", e);
                     Message::new_response(
                         message.request_id,
                         Operation::Delete,
-                        StatusCode::ErrorNotFound,
-                        b"File not found".to_vec(),
+                        status_for(&e),
+                        e.to_string().into_bytes(),
                     )
                 }
-                Err(_) => {
-                    eprintln!("✗ DELETE failed");
+            }
+        }
+        Operation::List => {
+            let req = match ListRequest::from_payload(&message.payload) {
+                Ok(req) => req,
+                Err(e) => {
+                    return Message::new_response(
+                        message.request_id,
+                        Operation::List,
+                        StatusCode::ErrorInvalidData,
+                        e.to_string().into_bytes(),
+                    )
+                }
+            };
+            match storage.list_page(&req) {
+                Ok(listing) => {
+                    let payload = bincode::serialize(&listing).unwrap(); // Or serde_json
+                    if req.cursor.is_none() {
+                        println!(
+                            "✓ LIST: /{} ({} of {} entries)",
+                            req.path,
+                            listing.entries.len(),
+                            listing.total_entries
+                        );
+                    } else {
+                        println!(
+                            "✓ LIST: /{} ({} more entries)",
+                            req.path,
+                            listing.entries.len()
+                        );
+                    }
                     Message::new_response(
                         message.request_id,
-                        Operation::Delete,
-                        StatusCode::ErrorServerError,
-                        b"Deletion failed".to_vec(),
+                        Operation::List,
+                        StatusCode::Success,
+                        payload,
+                    )
+                }
+                Err(e) => {
+                    eprintln!("✗ LIST failed: {}", e);
+                    Message::new_response(
+                        message.request_id,
+                        Operation::List,
+                        status_for(&e),
+                        e.to_string().into_bytes(),
                     )
                 }
             }
         }
-        Operation::List => match storage.list() {
-            Ok(files) => {
-                let payload = bincode::serialize(&files).unwrap(); // Or serde_json
-                println!("✓ LIST: {} files", files.len());
-                Message::new_response(
-                    message.request_id,
-                    Operation::List,
-                    StatusCode::Success,
-                    payload,
-                )
+        Operation::Mkdir => {
+            let path = String::from_utf8_lossy(&message.payload).to_string();
+
+            match storage.mkdir(&path) {
+                Ok(_) => {
+                    println!("✓ MKDIR: {}", path);
+                    Message::new_response(
+                        message.request_id,
+                        Operation::Mkdir,
+                        StatusCode::Success,
+                        b"OK".to_vec(),
+                    )
+                }
+                Err(e) => {
+                    eprintln!("✗ MKDIR failed: {}", e);
+                    Message::new_response(
+                        message.request_id,
+                        Operation::Mkdir,
+                        status_for(&e),
+                        e.to_string().into_bytes(),
+                    )
+                }
             }
-            Err(_) => {
-                eprintln!("✗ LIST failed");
-                Message::new_response(
-                    message.request_id,
-                    Operation::List,
-                    StatusCode::ErrorServerError,
-                    b"List failed".to_vec(),
-                )
+        }
+        Operation::Rmdir => {
+            let path = String::from_utf8_lossy(&message.payload).to_string();
+
+            match storage.rmdir(&path) {
+                Ok(_) => {
+                    println!("✓ RMDIR: {}", path);
+                    Message::new_response(
+                        message.request_id,
+                        Operation::Rmdir,
+                        StatusCode::Success,
+                        b"OK".to_vec(),
+                    )
+                }
+                Err(e) => {
+                    eprintln!("✗ RMDIR failed: {}", e);
+                    Message::new_response(
+                        message.request_id,
+                        Operation::Rmdir,
+                        status_for(&e),
+                        e.to_string().into_bytes(),
+                    )
+                }
+            }
+        }
+        Operation::ListVersions => {
+            let filename = String::from_utf8_lossy(&message.payload).to_string();
+
+            match storage.list_versions(&filename) {
+                Ok(versions) => {
+                    println!(
+                        "✓ LIST VERSIONS: {} ({} versions)",
+                        filename,
+                        versions.len()
+                    );
+                    Message::new_response(
+                        message.request_id,
+                        Operation::ListVersions,
+                        StatusCode::Success,
+                        bincode::serialize(&versions).unwrap(),
+                    )
+                }
+                Err(e) => {
+                    eprintln!("✗ LIST VERSIONS failed: {}", e);
+                    Message::new_response(
+                        message.request_id,
+                        Operation::ListVersions,
+                        status_for(&e),
+                        e.to_string().into_bytes(),
+                    )
+                }
+            }
+        }
+        Operation::Stat => {
+            let path = String::from_utf8_lossy(&message.payload).to_string();
+
+            match storage.stat(&path) {
+                Ok(stat) => {
+                    println!("✓ STAT: {}", path);
+                    Message::new_response(
+                        message.request_id,
+                        Operation::Stat,
+                        StatusCode::Success,
+                        bincode::serialize(&stat).unwrap(),
+                    )
+                }
+                Err(e) => {
+                    eprintln!("✗ STAT failed: {}", e);
+                    Message::new_response(
+                        message.request_id,
+                        Operation::Stat,
+                        status_for(&e),
+                        e.to_string().into_bytes(),
+                    )
+                }
+            }
+        }
+        Operation::Rename | Operation::Copy => match MoveRequest::from_payload(&message.payload) {
+            Ok(req) => {
+                let (verb, result) = if message.operation == Operation::Rename {
+                    ("RENAME", storage.rename(&req.from, &req.to))
+                } else {
+                    ("COPY", storage.copy(&req.from, &req.to, overwrite))
+                };
+                match result {
+                    Ok(()) => {
+                        println!("✓ {}: {} -> {}", verb, req.from, req.to);
+                        Message::new_response(
+                            message.request_id,
+                            message.operation,
+                            StatusCode::Success,
+                            Vec::new(),
+                        )
+                    }
+                    Err(e) => {
+                        eprintln!("✗ {} failed: {}", verb, e);
+                        Message::new_response(
+                            message.request_id,
+                            message.operation,
+                            write_status(&e, overwrite),
+                            e.to_string().into_bytes(),
+                        )
+                    }
+                }
             }
+            Err(e) => Message::new_response(
+                message.request_id,
+                message.operation,
+                StatusCode::ErrorInvalidData,
+                e.to_string().into_bytes(),
+            ),
         },
-        Operation::Auth => Message::new_response(
+        Operation::Auth | Operation::Hello => Message::new_response(
             message.request_id,
-            Operation::Auth,
+            message.operation,
             StatusCode::ErrorServerError,
-            b"Unexpected auth operation".to_vec(),
+            b"Unexpected handshake operation".to_vec(),
         ),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::CHUNK_SIZE;
+    use crate::storage::DEFAULT_MAX_UPLOAD_SIZE;
+    use crate::test_util::TestDir;
+    use sha2::{Digest, Sha256};
+    use tokio::net::TcpStream;
+
+    async fn start_test_server(password: &str) -> (SocketAddr, TestDir) {
+        let dir = TestDir::new();
+        // Few rounds keep handshakes quick in debug builds
+        let addr = serve(Accounts::new(AccountMode::Shared {
+            credentials: auth::Credentials::new(password, auth::MIN_ITERATIONS),
+            storage: Arc::new(Storage::new(&dir).unwrap()),
+        }))
+        .await;
+        (addr, dir)
+    }
+
+    async fn serve(accounts: Accounts) -> SocketAddr {
+        let accounts = Arc::new(accounts);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (socket, peer) = listener.accept().await.unwrap();
+                let accounts = Arc::clone(&accounts);
+                tokio::spawn(async move {
+                    let _ = handle_client(socket, peer, accounts).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn send_raw(stream: &mut TcpStream, bytes: &[u8]) -> Message {
+        stream.write_all(bytes).await.unwrap();
+        let mut len_bytes = [0u8; 4];
+        stream.read_exact(&mut len_bytes).await.unwrap();
+        let length = u32::from_be_bytes(len_bytes);
+        let mut data = vec![0u8; length as usize];
+        stream.read_exact(&mut data).await.unwrap();
+        Message::from_bytes(length, &data).unwrap()
+    }
+
+    fn request(id: u32, operation: Operation, payload: Vec<u8>) -> Message {
+        let mut msg = Message::new(operation, payload);
+        msg.set_request_id(id);
+        msg
+    }
+
+    fn our_hello() -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: capability::SUPPORTED,
+        }
+    }
+
+    async fn hello(stream: &mut TcpStream) -> Message {
+        send_raw(
+            stream,
+            &request(1, Operation::Hello, our_hello().to_payload()).to_bytes(),
+        )
+        .await
+    }
+
+    /// The hello transcript as the client that sent `our_hello` sees it
+    fn transcript(response: &Message) -> [u8; 32] {
+        auth::hello_transcript(&our_hello().to_payload(), &response.payload)
+    }
+
+    async fn challenge(stream: &mut TcpStream, username: &str) -> auth::Challenge {
+        let response = send_raw(
+            stream,
+            &request(2, Operation::Auth, username.as_bytes().to_vec()).to_bytes(),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::Success);
+        auth::Challenge::from_payload(&response.payload).unwrap()
+    }
+
+    /// Answer `username`'s challenge, returning the raw auth-response frame,
+    /// the password key, the auth message and the response
+    async fn answer(
+        stream: &mut TcpStream,
+        username: &str,
+        challenge: &auth::Challenge,
+        transcript: &[u8; 32],
+        password: &str,
+    ) -> (Vec<u8>, [u8; 32], Vec<u8>, Message) {
+        let key = auth::password_key(password, &challenge.salt, challenge.iterations);
+        let client_nonce = auth::generate_nonce();
+        let auth_message =
+            auth::auth_message(username, &challenge.server_nonce, &client_nonce, transcript);
+        let proof = auth::client_proof(&auth::client_key(&key), &auth_message);
+        let frame = request(
+            3,
+            Operation::Auth,
+            auth::encode_auth_response(&client_nonce, &proof),
+        )
+        .to_bytes();
+        let response = send_raw(stream, &frame).await;
+        (frame, key, auth_message, response)
+    }
+
+    /// Run the hello and auth handshakes, returning the raw auth-response frame and the session key
+    async fn handshake(
+        stream: &mut TcpStream,
+        username: &str,
+        password: &str,
+    ) -> (Vec<u8>, [u8; 32]) {
+        let hello = hello(stream).await;
+        assert_eq!(hello.status, StatusCode::Success);
+        let transcript = transcript(&hello);
+        let challenge = challenge(stream, username).await;
+        let (frame, key, auth_message, response) =
+            answer(stream, username, &challenge, &transcript, password).await;
+        assert_eq!(response.status, StatusCode::Success);
+        assert!(auth::verify_server_proof(
+            &auth::server_key(&key),
+            &auth_message,
+            &response.payload
+        ));
+        (
+            frame,
+            auth::derive_session_key(&auth::client_key(&key), &auth_message),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_authenticated_request_succeeds() {
+        let (addr, _dir) = start_test_server("hunter2").await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let (_, session_key) = handshake(&mut stream, "", "hunter2").await;
+
+        let mut list = request(4, Operation::List, Vec::new());
+        list.sign(&session_key, auth::Direction::Request);
+        let response = send_raw(&mut stream, &list.to_bytes()).await;
+        assert_eq!(response.status, StatusCode::Success);
+        assert!(response.verify_mac(&session_key, auth::Direction::Response));
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password_rejected() {
+        let (addr, _dir) = start_test_server("hunter2").await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let transcript = transcript(&hello(&mut stream).await);
+        let challenge = challenge(&mut stream, "").await;
+        let (_, _, _, response) = answer(&mut stream, "", &challenge, &transcript, "wrong").await;
+        assert_eq!(response.status, StatusCode::ErrorPermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_tampered_hello_fails_authentication() {
+        let (addr, _dir) = start_test_server("hunter2").await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        // A man in the middle strips capabilities from the client's offer
+        let stripped = Hello {
+            capabilities: capability::COMPRESSION,
+            ..our_hello()
+        };
+        let response = send_raw(
+            &mut stream,
+            &request(1, Operation::Hello, stripped.to_payload()).to_bytes(),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::Success);
+
+        // The client still believes it offered everything, so its proof does not verify
+        let challenge = challenge(&mut stream, "").await;
+        let (_, _, _, response) = answer(
+            &mut stream,
+            "",
+            &challenge,
+            &transcript(&response),
+            "hunter2",
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::ErrorPermissionDenied);
+    }
+
+    async fn count_entries(stream: &mut TcpStream, session_key: &[u8; 32], id: u32) -> usize {
+        let mut list = request(id, Operation::List, Vec::new());
+        list.sign(session_key, auth::Direction::Request);
+        let response = send_raw(stream, &list.to_bytes()).await;
+        assert_eq!(response.status, StatusCode::Success);
+        bincode::deserialize::<crate::storage::Listing>(&response.payload)
+            .unwrap()
+            .entries
+            .len()
+    }
+
+    #[tokio::test]
+    async fn test_users_see_only_their_own_files() {
+        let dir = TestDir::new();
+        let mut db = UserDatabase::load(&users::users_file(&dir)).unwrap();
+        db.add("alice", "alice-pw", Role::Admin, None).unwrap();
+        db.add("bob", "bob-pw", Role::Admin, None).unwrap();
+        db.save(&users::users_file(&dir)).unwrap();
+        let addr = serve(Accounts::per_user(
+            dir.to_path_buf(),
+            VersionRetention::default(),
+            false,
+            DEFAULT_MAX_UPLOAD_SIZE,
+        ))
+        .await;
+
+        let mut alice = TcpStream::connect(addr).await.unwrap();
+        let (_, alice_key) = handshake(&mut alice, "alice", "alice-pw").await;
+        let mut store = request(4, Operation::Store, b"notes.txt\0hello".to_vec());
+        store.sign(&alice_key, auth::Direction::Request);
+        assert_eq!(
+            send_raw(&mut alice, &store.to_bytes()).await.status,
+            StatusCode::Success
+        );
+        assert_eq!(count_entries(&mut alice, &alice_key, 5).await, 1);
+        assert!(users::user_root(&dir, "alice").join("notes.txt").exists());
+
+        let mut bob = TcpStream::connect(addr).await.unwrap();
+        let (_, bob_key) = handshake(&mut bob, "bob", "bob-pw").await;
+        assert_eq!(count_entries(&mut bob, &bob_key, 4).await, 0);
+
+        // Another user's password does not work, and unknown users look like known ones
+        let mut mallory = TcpStream::connect(addr).await.unwrap();
+        let transcript = transcript(&hello(&mut mallory).await);
+        let bob_challenge = challenge(&mut mallory, "bob").await;
+        let (_, _, _, response) =
+            answer(&mut mallory, "bob", &bob_challenge, &transcript, "alice-pw").await;
+        assert_eq!(response.status, StatusCode::ErrorPermissionDenied);
+        let first = challenge(&mut mallory, "carol").await;
+        let (_, _, _, response) =
+            answer(&mut mallory, "carol", &first, &transcript, "anything").await;
+        assert_eq!(response.status, StatusCode::ErrorPermissionDenied);
+        let second = challenge(&mut mallory, "carol").await;
+        assert_eq!(first.salt, second.salt);
+        assert_eq!(first.iterations, bob_challenge.iterations);
+    }
+
+    #[tokio::test]
+    async fn test_captured_frames_replayed_on_new_connection_rejected() {
+        let (addr, _dir) = start_test_server("hunter2").await;
+
+        // Legitimate session, observed by an attacker
+        let mut victim = TcpStream::connect(addr).await.unwrap();
+        let (auth_frame, session_key) = handshake(&mut victim, "", "hunter2").await;
+        let mut list = request(4, Operation::List, Vec::new());
+        list.sign(&session_key, auth::Direction::Request);
+        let captured = list.to_bytes();
+        assert_eq!(
+            send_raw(&mut victim, &captured).await.status,
+            StatusCode::Success
+        );
+
+        // Replaying a signed request on a fresh connection
+        let mut attacker = TcpStream::connect(addr).await.unwrap();
+        hello(&mut attacker).await;
+        let response = send_raw(&mut attacker, &captured).await;
+        assert_eq!(response.status, StatusCode::ErrorPermissionDenied);
+
+        // Replaying the captured proof against a new challenge
+        let mut attacker = TcpStream::connect(addr).await.unwrap();
+        hello(&mut attacker).await;
+        send_raw(
+            &mut attacker,
+            &request(2, Operation::Auth, Vec::new()).to_bytes(),
+        )
+        .await;
+        let response = send_raw(&mut attacker, &auth_frame).await;
+        assert_eq!(response.status, StatusCode::ErrorPermissionDenied);
+        let response = send_raw(&mut attacker, &captured).await;
+        assert_eq!(response.status, StatusCode::ErrorPermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_replay_within_session_rejected() {
+        let (addr, _dir) = start_test_server("hunter2").await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let (_, session_key) = handshake(&mut stream, "", "hunter2").await;
+
+        let mut list = request(4, Operation::List, Vec::new());
+        list.sign(&session_key, auth::Direction::Request);
+        let frame = list.to_bytes();
+        assert_eq!(
+            send_raw(&mut stream, &frame).await.status,
+            StatusCode::Success
+        );
+        assert_eq!(
+            send_raw(&mut stream, &frame).await.status,
+            StatusCode::ErrorMessageRejected
+        );
+        let mut forged = request(5, Operation::List, Vec::new());
+        forged.sign(&[0; 32], auth::Direction::Request);
+        assert_eq!(
+            send_raw(&mut stream, &forged.to_bytes()).await.status,
+            StatusCode::ErrorMessageRejected
+        );
+
+        // An unsigned Auth frame mid-session neither resets the replay window
+        // nor restarts the handshake
+        let response = send_raw(
+            &mut stream,
+            &request(1, Operation::Auth, Vec::new()).to_bytes(),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::ErrorPermissionDenied);
+        assert_eq!(
+            send_raw(&mut stream, &frame).await.status,
+            StatusCode::ErrorMessageRejected
+        );
+        assert_eq!(count_entries(&mut stream, &session_key, 5).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_closes_connection() {
+        let (addr, _dir) = start_test_server("hunter2").await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        // Announcing a 4 GiB frame before hello ends the connection at once
+        stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        let mut byte = [0u8; 1];
+        assert_eq!(stream.read(&mut byte).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_hello_required_and_version_checked() {
+        let (addr, _dir) = start_test_server("hunter2").await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let response = send_raw(
+            &mut stream,
+            &request(1, Operation::Auth, Vec::new()).to_bytes(),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::ErrorIncompatibleVersion);
+
+        let old = Hello {
+            version: MIN_PROTOCOL_VERSION - 1,
+            capabilities: 0,
+        };
+        let response = send_raw(
+            &mut stream,
+            &request(2, Operation::Hello, old.to_payload()).to_bytes(),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::ErrorIncompatibleVersion);
+
+        let response = hello(&mut stream).await;
+        assert_eq!(response.status, StatusCode::Success);
+        let negotiated = Hello::from_payload(&response.payload).unwrap();
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert_eq!(negotiated.capabilities & !capability::SUPPORTED, 0);
+    }
+
+    async fn send_signed(
+        stream: &mut TcpStream,
+        session_key: &[u8; 32],
+        id: u32,
+        operation: Operation,
+        payload: &[u8],
+    ) -> Message {
+        let mut msg = request(id, operation, payload.to_vec());
+        msg.sign(session_key, auth::Direction::Request);
+        send_raw(stream, &msg.to_bytes()).await
+    }
+
+    #[tokio::test]
+    async fn test_rejected_paths_keep_their_reason() {
+        let (addr, _dir) = start_test_server("hunter2").await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let (_, key) = handshake(&mut stream, "", "hunter2").await;
+
+        for (id, operation, payload) in [
+            (4, Operation::Store, b"../escape.txt\0data".to_vec()),
+            (5, Operation::Retrieve, b"../escape.txt".to_vec()),
+            (6, Operation::Delete, b"../escape.txt".to_vec()),
+        ] {
+            let response = send_signed(&mut stream, &key, id, operation, &payload).await;
+            assert_eq!(response.status, StatusCode::ErrorInvalidRequest);
+            assert_eq!(response.payload, b"Path must not contain '..'");
+        }
+        let response = send_signed(&mut stream, &key, 7, Operation::Retrieve, b"missing").await;
+        assert_eq!(response.status, StatusCode::ErrorNotFound);
+    }
+
+    #[tokio::test]
+    async fn test_roles_enforced() {
+        let dir = TestDir::new();
+        let mut db = UserDatabase::default();
+        db.add("laptop", "laptop-pw", Role::Append, None).unwrap();
+        db.add("restore", "restore-pw", Role::ReadOnly, Some("laptop"))
+            .unwrap();
+        db.save(&users::users_file(&dir)).unwrap();
+        let addr = serve(Accounts::per_user(
+            dir.to_path_buf(),
+            VersionRetention::default(),
+            false,
+            DEFAULT_MAX_UPLOAD_SIZE,
+        ))
+        .await;
+
+        let mut laptop = TcpStream::connect(addr).await.unwrap();
+        let (_, key) = handshake(&mut laptop, "laptop", "laptop-pw").await;
+        let store = |name: &str| [name.as_bytes(), b"\0backup"].concat();
+        let response = send_signed(&mut laptop, &key, 4, Operation::Store, &store("a.txt")).await;
+        assert_eq!(response.status, StatusCode::Success);
+        assert_eq!(count_entries(&mut laptop, &key, 5).await, 1);
+
+        // No replacing, deleting or reading back
+        for (id, operation, payload) in [
+            (6, Operation::Store, store("a.txt")),
+            (7, Operation::Delete, b"a.txt".to_vec()),
+            (8, Operation::Retrieve, b"a.txt".to_vec()),
+        ] {
+            let response = send_signed(&mut laptop, &key, id, operation, &payload).await;
+            assert_eq!(response.status, StatusCode::ErrorPermissionDenied);
+            assert!(String::from_utf8_lossy(&response.payload).starts_with("Permission denied"));
+        }
+        // ...but it can look at what it stored
+        let response = send_signed(&mut laptop, &key, 9, Operation::Stat, b"a.txt").await;
+        assert_eq!(response.status, StatusCode::Success);
+        let stat: crate::storage::FileStat = bincode::deserialize(&response.payload).unwrap();
+        assert_eq!((stat.metadata.size, stat.chunks), (6, 1));
+
+        // Copying to a new name adds a file; moving or copying over one does not
+        let copy = |to: &str| {
+            MoveRequest {
+                from: "a.txt".to_string(),
+                to: to.to_string(),
+            }
+            .to_payload()
+        };
+        let response = send_signed(&mut laptop, &key, 10, Operation::Copy, &copy("b.txt")).await;
+        assert_eq!(response.status, StatusCode::Success);
+        for (id, operation) in [(11, Operation::Copy), (12, Operation::Rename)] {
+            let response = send_signed(&mut laptop, &key, id, operation, &copy("b.txt")).await;
+            assert_eq!(response.status, StatusCode::ErrorPermissionDenied);
+        }
+
+        // A read-only login on the same namespace can restore but not change anything
+        let mut restore = TcpStream::connect(addr).await.unwrap();
+        let (_, key) = handshake(&mut restore, "restore", "restore-pw").await;
+        let response = send_signed(&mut restore, &key, 4, Operation::Retrieve, b"a.txt").await;
+        assert_eq!(response.status, StatusCode::Success);
+        assert_eq!(response.payload, b"backup");
+        let response = send_signed(&mut restore, &key, 5, Operation::Store, &store("b.txt")).await;
+        assert_eq!(response.status, StatusCode::ErrorPermissionDenied);
+        let response = send_signed(&mut restore, &key, 6, Operation::Mkdir, b"docs").await;
+        assert_eq!(response.status, StatusCode::ErrorPermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_append_upload_race_keeps_first_file() {
+        let dir = TestDir::new();
+        let mut db = UserDatabase::default();
+        db.add("laptop", "laptop-pw", Role::Append, None).unwrap();
+        db.save(&users::users_file(&dir)).unwrap();
+        let addr = serve(Accounts::per_user(
+            dir.to_path_buf(),
+            VersionRetention::default(),
+            false,
+            DEFAULT_MAX_UPLOAD_SIZE,
+        ))
+        .await;
+
+        // Both sessions pass the existence check before either completes
+        let mut sessions = Vec::new();
+        for data in [b"first".to_vec(), b"second".to_vec()] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let (_, key) = handshake(&mut stream, "laptop", "laptop-pw").await;
+            let checksum: [u8; 32] = Sha256::digest(&data).into();
+            let begin = UploadBeginRequest {
+                filename: "a.txt".to_string(),
+                total_size: data.len() as u64,
+                total_chunks: 1,
+                checksum,
+                chunk_sizes: Vec::new(),
+            };
+            let response = send_signed(
+                &mut stream,
+                &key,
+                4,
+                Operation::UploadBegin,
+                &begin.to_payload(),
+            )
+            .await;
+            assert_eq!(response.status, StatusCode::Success);
+            let upload_id = UploadBeginResponse::from_payload(&response.payload)
+                .unwrap()
+                .upload_id;
+            let chunk = ChunkMetadata {
+                upload_id: upload_id.clone(),
+                chunk_number: 0,
+                total_chunks: 1,
+                data: data.clone(),
+            };
+            let response = send_signed(
+                &mut stream,
+                &key,
+                5,
+                Operation::StoreChunk,
+                &chunk.to_payload(false),
+            )
+            .await;
+            assert_eq!(response.status, StatusCode::Success);
+            let complete = StoreCompleteRequest {
+                upload_id,
+                total_size: data.len() as u64,
+                checksum,
+            };
+            sessions.push((stream, key, complete.to_payload()));
+        }
+
+        let mut statuses = Vec::new();
+        for (stream, key, complete) in &mut sessions {
+            let response = send_signed(stream, key, 6, Operation::StoreComplete, complete).await;
+            statuses.push(response.status);
+        }
+        assert_eq!(
+            statuses,
+            [StatusCode::Success, StatusCode::ErrorPermissionDenied]
+        );
+
+        // The first upload stays, with no version archived behind it
+        let (stream, key, _) = &mut sessions[0];
+        let response = send_signed(stream, key, 7, Operation::Stat, b"a.txt").await;
+        let stat: crate::storage::FileStat = bincode::deserialize(&response.payload).unwrap();
+        assert_eq!((stat.metadata.size, stat.versions), (5, 0));
+    }
+
+    /// Options for the real client to log in to a test server
+    fn client_options(addr: SocketAddr, password: &str) -> crate::client::ConnectOptions {
+        crate::client::ConnectOptions {
+            server_addr: addr.to_string(),
+            username: String::new(),
+            password: password.to_string(),
+            tls: None,
+            chunking: None,
+            crypto: None,
+            output: crate::client::Output::Text,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resumed_download_repairs_partial_copy() {
+        let (addr, _dir) = start_test_server("hunter2").await;
+        let options = client_options(addr, "hunter2");
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let local_dir = TestDir::new();
+        std::fs::create_dir_all(&*local_dir).unwrap();
+        let local = local_dir.join("local.bin");
+        let local = local.to_str().unwrap();
+        std::fs::write(local, &data).unwrap();
+        crate::client::upload(&options, local, Some("data.bin"), false, &[])
+            .await
+            .unwrap();
+
+        // A good first chunk, a damaged second one and nothing after
+        let mut damaged = data[..2 * CHUNK_SIZE].to_vec();
+        damaged[CHUNK_SIZE + 7] ^= 0xff;
+        // Trailing bytes beyond the remote file are cut off
+        let mut longer = data.clone();
+        longer.extend_from_slice(&[0u8; 10]);
+
+        for partial in [damaged, longer] {
+            std::fs::write(local, partial).unwrap();
+            let selector = crate::client::VersionSelector::Current;
+            crate::client::download(&options, "data.bin", Some(local), true, false, selector)
+                .await
+                .unwrap();
+            assert_eq!(std::fs::read(local).unwrap(), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_errors_carry_exit_codes() {
+        let (addr, _dir) = start_test_server("hunter2").await;
+        let local_dir = TestDir::new();
+        std::fs::create_dir_all(&*local_dir).unwrap();
+        let local = local_dir.join("out.bin");
+        let local = local.to_str().unwrap();
+
+        for (password, kind, code) in [("wrong", "auth", 3), ("hunter2", "not_found", 5)] {
+            let options = crate::client::ConnectOptions {
+                output: crate::client::Output::Json,
+                ..client_options(addr, password)
+            };
+            let selector = crate::client::VersionSelector::Current;
+            let err = crate::client::download(
+                &options,
+                "missing.bin",
+                Some(local),
+                false,
+                false,
+                selector,
+            )
+            .await
+            .unwrap_err();
+            let document = crate::failure::error_document(err.as_ref());
+            assert_eq!(document["error"]["kind"], kind);
+            assert_eq!(document["error"]["exit_code"], code);
+            assert_eq!(document["error"]["message"], err.to_string());
+            assert_eq!(crate::failure::classify(err.as_ref()).exit_code(), code);
+        }
+        assert!(!std::path::Path::new(local).exists());
+    }
+}
@@ -1,235 +1,3227 @@
+use crate::chunker::{MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+use crate::compression::{self, Encoding};
+use crate::delta::{self, BlockSignature, DeltaInstruction, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+use crate::hex;
+use crate::ignore::filter_matches;
+use crate::index::{IndexEntry, MetadataIndex};
+use crate::protocol::{
+    ListRequest, CHUNK_SIZE, MAX_LIST_FILTER_LEN, MAX_LIST_FILTER_WILDCARDS, MAX_LIST_PAGE,
+};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::fs::File;
-use std::io::{self, Error, ErrorKind, Read};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Error, ErrorKind, Read, Write};
 use std::io::{Seek, SeekFrom};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Directory inside the storage root that holds server-internal state
+pub const INTERNAL_DIR: &str = ".netbackup";
 
 pub struct Storage {
     root_dir: PathBuf,
-    pending_chunks: Mutex<HashMap<String, ChunkedUpload>>,
+    staging_dir: PathBuf,
+    tmp_dir: PathBuf,
+    versions_dir: PathBuf,
+    chunks_dir: PathBuf,
+    retention: VersionRetention,
+    /// Write new chunks lz4-compressed when that makes them smaller
+    compress_chunks: bool,
+    /// Largest file an upload session may be started for
+    max_upload_size: u64,
+    /// Upload sessions by ID. A session's own lock is always taken before
+    /// this map's, never while holding it.
+    pending_chunks: Mutex<HashMap<String, Arc<Mutex<ChunkedUpload>>>>,
+    /// How many manifest entries refer to each stored chunk
+    chunk_refs: Mutex<HashMap<[u8; 32], u32>>,
+    /// Uncompressed size of all chunks in `chunk_refs`, updated with it
+    stored_bytes: AtomicU64,
+    /// Listing metadata of current files, so List need not read manifests
+    index: MetadataIndex,
+}
+
+/// How many superseded versions of each file to keep
+#[derive(Debug, Clone, Copy)]
+pub struct VersionRetention {
+    /// Keep at most this many old versions per file (0 = no limit)
+    pub max_versions: usize,
+    /// Drop old versions superseded more than this many days ago (0 = no limit)
+    pub max_age_days: u64,
+}
+
+impl Default for VersionRetention {
+    fn default() -> Self {
+        Self {
+            max_versions: 10,
+            max_age_days: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")] // Shown as "file"/"directory" in JSON output
+pub enum EntryType {
+    File,
+    Directory,
+}
+
+/// What a write does when its destination already holds a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overwrite {
+    /// Replace the file, keeping its old contents as a version
+    Replace,
+    /// Fail with `AlreadyExists`, even if the file appeared while the write
+    /// was in progress. Nothing is archived, so no versions are pruned.
+    Refuse,
+}
+
+/// Order of a listing. Ties are broken by path, so every entry has one place.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum SortKey {
+    #[default]
+    Name,
+    /// Largest first
+    Size,
+    /// Most recently modified first
+    Modified,
+}
+
+impl SortKey {
+    pub fn compare(&self, a: &FileMetadata, b: &FileMetadata) -> std::cmp::Ordering {
+        let by_key = match self {
+            SortKey::Name => std::cmp::Ordering::Equal,
+            SortKey::Size => b.size.cmp(&a.size),
+            SortKey::Modified => b.last_modified.cmp(&a.last_modified),
+        };
+        by_key.then_with(|| a.filename.cmp(&b.filename))
+    }
+}
+
+impl std::str::FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(SortKey::Name),
+            "size" => Ok(SortKey::Size),
+            "mtime" | "modified" => Ok(SortKey::Modified),
+            _ => Err(format!(
+                "Unknown sort key '{}': expected name, size or mtime",
+                s
+            )),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)] // For easier debugging
 pub struct FileMetadata {
-    pub filename: String,
+    pub filename: String, // Full path from the storage root, e.g. "docs/notes.txt"
     pub size: u64,
     pub last_modified: String,
-    pub checksum: String, // Optional
+    pub checksum: String, // Empty for directories
+    pub entry_type: EntryType,
 }
 
-struct ChunkedUpload {
-    chunks: HashMap<u32, Vec<u8>>,
-    total_chunks: u32,
+/// One version of a file. Version 0 is the current file; older versions are
+/// identified by the time they were stored, in microseconds since the epoch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VersionInfo {
+    pub version: u64,
+    pub size: u64,
+    /// When this version was stored, in microseconds since the epoch
+    pub stored_at: u64,
+    pub checksum: String,
 }
 
-impl ChunkedUpload {
-    fn new(total_chunks: u32) -> Self {
+/// Everything the server can tell about one stored path
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileStat {
+    pub metadata: FileMetadata,
+    /// Older versions kept besides the current file
+    pub versions: u32,
+    /// Chunks the current file is stored as
+    pub chunks: u32,
+    /// Of those, chunks that other files or versions refer to as well
+    pub shared_chunks: u32,
+}
+
+/// A directory listing, or one page of it, with the storage it accounts for
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Listing {
+    pub entries: Vec<FileMetadata>,
+    /// Entries matching the request, on all pages together. Only filled in
+    /// on the first page; later pages leave it at zero.
+    pub total_entries: u64,
+    /// Sum of the sizes of all matching files, on all pages together. Only
+    /// filled in on the first page, like `total_entries`.
+    pub logical_size: u64,
+    /// Bytes of distinct chunks in the whole chunk store, old versions included
+    pub physical_size: u64,
+    /// Pass back in the next request to get the following page; None on the last
+    pub next_cursor: Option<String>,
+}
+
+/// Leading bytes of every manifest, so plain files from older releases can
+/// be told apart and converted
+const MANIFEST_MAGIC: &[u8; 8] = b"NBMANIF1";
+
+/// One chunk of a stored file, named by the SHA-256 of its contents
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct ChunkRef {
+    hash: [u8; 32],
+    len: u32,
+}
+
+/// What a file in the namespace actually holds: the chunks that make up its
+/// contents, in order
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Manifest {
+    size: u64,
+    checksum: [u8; 32],
+    chunks: Vec<ChunkRef>,
+}
+
+impl Manifest {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = MANIFEST_MAGIC.to_vec();
+        bytes.extend(bincode::serialize(self).expect("serializing to memory cannot fail"));
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let manifest: Self = bincode::deserialize(bytes.strip_prefix(MANIFEST_MAGIC)?).ok()?;
+        let total: u64 = manifest.chunks.iter().map(|c| c.len as u64).sum();
+        (total == manifest.size).then_some(manifest)
+    }
+
+    fn read(path: &Path) -> io::Result<Self> {
+        Self::decode(&fs::read(path)?)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Corrupt file manifest"))
+    }
+}
+
+/// Reads ranges of a delta's base file, loading each chunk at most once per
+/// delta. The chunks loaded are capped relative to the data being rebuilt, so
+/// many scattered copies cannot make the server read far more than it writes.
+struct BaseReader<'a> {
+    storage: &'a Storage,
+    manifest: &'a Manifest,
+    offsets: Vec<u64>,
+    chunks: HashMap<usize, Vec<u8>>,
+    budget: usize,
+}
+
+impl<'a> BaseReader<'a> {
+    fn new(storage: &'a Storage, manifest: &'a Manifest, target_len: usize) -> Self {
+        let sizes: Vec<u32> = manifest.chunks.iter().map(|chunk| chunk.len).collect();
+        let largest = sizes.iter().max().copied().unwrap_or(0) as usize;
         Self {
+            storage,
+            manifest,
+            offsets: chunk_offsets(&sizes),
             chunks: HashMap::new(),
-            total_chunks,
+            // Room for the copied bytes plus partly used chunks around them
+            budget: 2 * (target_len + largest),
+        }
+    }
+
+    /// Bytes of the base file from `offset`, cut short at its end
+    fn read(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let end = offset.saturating_add(len as u64).min(self.manifest.size);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        let mut data = Vec::with_capacity((end - offset) as usize);
+        let mut index = self.offsets.partition_point(|&start| start <= offset) - 1;
+        while self.offsets[index] < end {
+            let start = self.offsets[index];
+            let chunk = self.chunk(index)?;
+            let from = offset.saturating_sub(start) as usize;
+            let to = (end - start).min(chunk.len() as u64) as usize;
+            data.extend_from_slice(&chunk[from..to]);
+            index += 1;
+        }
+        Ok(data)
+    }
+
+    fn chunk(&mut self, index: usize) -> io::Result<&[u8]> {
+        if !self.chunks.contains_key(&index) {
+            let chunk = &self.manifest.chunks[index];
+            self.budget = self.budget.checked_sub(chunk.len as usize).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "Delta reads too much of its base file",
+                )
+            })?;
+            let data = self.storage.read_chunk(chunk)?;
+            self.chunks.insert(index, data);
+        }
+        Ok(&self.chunks[&index])
+    }
+}
+
+/// Normalise a client-supplied path to its canonical "a/b/c" form. Empty and
+/// "." components are dropped; anything that could climb out of the storage
+/// root or reach server-internal state is rejected. The root itself is "".
+pub fn normalize_path(path: &str) -> io::Result<String> {
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Path must not contain '..'",
+                ))
+            }
+            p if p.contains('\\') || p.contains('\0') => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Path contains invalid characters",
+                ))
+            }
+            p => parts.push(p),
+        }
+    }
+    if parts.first() == Some(&INTERNAL_DIR) {
+        return Err(Error::new(ErrorKind::InvalidInput, "Path is reserved"));
+    }
+    Ok(parts.join("/"))
+}
+
+/// One bit per chunk, set once the chunk has been written
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkBitmap {
+    bits: Vec<u8>,
+    len: u32,
+    count: u32,
+}
+
+impl ChunkBitmap {
+    pub fn new(len: u32) -> Self {
+        Self {
+            bits: vec![0u8; (len as usize).div_ceil(8)],
+            len,
+            count: 0,
+        }
+    }
+
+    pub fn get(&self, index: u32) -> bool {
+        index < self.len && self.bits[(index / 8) as usize] & (1 << (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: u32) {
+        if index < self.len && !self.get(index) {
+            self.bits[(index / 8) as usize] |= 1 << (index % 8);
+            self.count += 1;
         }
     }
 
-    fn add_chunk(&mut self, chunk_number: u32, data: Vec<u8>) {
-        self.chunks.insert(chunk_number, data);
+    pub fn is_complete(&self) -> bool {
+        self.count == self.len
+    }
+
+    /// Chunks not received yet, as sorted half-open ranges, so a fresh
+    /// session of any size is described in a few bytes
+    pub fn missing(&self) -> Vec<Range<u32>> {
+        let mut ranges: Vec<Range<u32>> = Vec::new();
+        for i in (0..self.len).filter(|&i| !self.get(i)) {
+            match ranges.last_mut() {
+                Some(last) if last.end == i => last.end += 1,
+                _ => ranges.push(i..i + 1),
+            }
+        }
+        ranges
     }
+}
+
+// Upload sessions nobody has touched for this long are dropped on startup
+const STALE_UPLOAD_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Upload sessions one storage namespace keeps open; starting another drops
+/// the one idle longest
+const MAX_PENDING_UPLOADS: usize = 64;
+
+/// Default for the largest file a client may upload: 1 TiB
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 1 << 40;
+
+// The received-chunk bitmap is persisted after this many chunks or this long,
+// whichever comes first; a crash costs at most that much re-sent data
+const STATE_SAVE_CHUNKS: u32 = 64;
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(5);
 
-    fn is_complete(&self) -> bool {
-        self.chunks.len() == self.total_chunks as usize
+/// Persisted description of an upload session, stored next to its staging file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct UploadState {
+    filename: String,
+    total_size: u64,
+    total_chunks: u32,
+    checksum: [u8; 32],
+    received: ChunkBitmap,
+}
+
+/// An in-progress upload, written chunk by chunk into a staging file
+struct ChunkedUpload {
+    state: UploadState,
+    staging_path: PathBuf,
+    state_path: PathBuf,
+    file: File,
+    /// Length of every chunk when the client chose the boundaries; empty for
+    /// fixed `CHUNK_SIZE` chunks. Kept in its own file since it never changes.
+    chunk_sizes: Vec<u32>,
+    /// Start of each chunk followed by the end of the file, for `chunk_sizes`
+    offsets: Vec<u64>,
+    /// Chunks written since the bitmap was last persisted, and when that was
+    unsaved: u32,
+    saved_at: Instant,
+}
+
+impl ChunkedUpload {
+    fn create(
+        staging_dir: &Path,
+        upload_id: &str,
+        state: UploadState,
+        chunk_sizes: Vec<u32>,
+    ) -> io::Result<Self> {
+        let staging_path = staging_dir.join(format!("{}.part", upload_id));
+        let state_path = staging_dir.join(format!("{}.state", upload_id));
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&staging_path)?;
+        file.set_len(state.total_size)?;
+
+        if !chunk_sizes.is_empty() {
+            let bytes = bincode::serialize(&chunk_sizes)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let mut layout = File::create(state_path.with_extension("layout"))?;
+            layout.write_all(&bytes)?;
+            layout.sync_all()?;
+        }
+
+        let upload = Self {
+            state,
+            staging_path,
+            state_path,
+            file,
+            offsets: chunk_offsets(&chunk_sizes),
+            chunk_sizes,
+            unsaved: 0,
+            saved_at: Instant::now(),
+        };
+        upload.save_state()?;
+        Ok(upload)
     }
 
-    fn assemble(&self) -> io::Result<Vec<u8>> {
-        if !self.is_complete() {
+    /// Reopen a session persisted by a previous server run
+    fn open(state_path: &Path) -> io::Result<Self> {
+        let state: UploadState = bincode::deserialize(&fs::read(state_path)?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let staging_path = state_path.with_extension("part");
+        let file = OpenOptions::new().write(true).open(&staging_path)?;
+        if file.metadata()?.len() != state.total_size {
             return Err(Error::new(
                 ErrorKind::InvalidData,
-                "Not all chunks received",
+                "Staging file size does not match upload state",
             ));
         }
 
-        let mut result = Vec::new();
-        for i in 0..self.total_chunks {
-            if let Some(chunk) = self.chunks.get(&i) {
-                result.extend_from_slice(chunk);
-            } else {
-                return Err(Error::new(ErrorKind::InvalidData, "Missing chunk"));
+        let chunk_sizes: Vec<u32> = match fs::read(state_path.with_extension("layout")) {
+            Ok(bytes) => {
+                bincode::deserialize(&bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let offsets = chunk_offsets(&chunk_sizes);
+        if !chunk_sizes.is_empty()
+            && (chunk_sizes.len() != state.total_chunks as usize
+                || offsets.last() != Some(&state.total_size))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Chunk layout does not match upload state",
+            ));
+        }
+
+        Ok(Self {
+            state,
+            staging_path,
+            state_path: state_path.to_path_buf(),
+            file,
+            chunk_sizes,
+            offsets,
+            unsaved: 0,
+            saved_at: Instant::now(),
+        })
+    }
+
+    fn save_state(&self) -> io::Result<()> {
+        let bytes =
+            bincode::serialize(&self.state).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let tmp_path = self.state_path.with_extension("state.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.state_path)
+    }
+
+    /// Flush the staging file, then persist the bitmap. In this order a
+    /// chunk marked as received after a crash is always on disk.
+    fn checkpoint(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.save_state()?;
+        self.unsaved = 0;
+        self.saved_at = Instant::now();
+        Ok(())
+    }
+
+    fn remove_files(&mut self) {
+        self.unsaved = 0;
+        let _ = fs::remove_file(&self.staging_path);
+        let _ = fs::remove_file(&self.state_path);
+        let _ = fs::remove_file(self.state_path.with_extension("layout"));
+    }
+
+    /// Offset and length of a chunk within the file
+    fn chunk_range(&self, chunk_number: u32) -> (u64, u64) {
+        let index = chunk_number as usize;
+        match self.offsets.get(index + 1) {
+            Some(&end) => (self.offsets[index], end - self.offsets[index]),
+            None => {
+                let offset = chunk_number as u64 * CHUNK_SIZE as u64;
+                (
+                    offset,
+                    self.state
+                        .total_size
+                        .saturating_sub(offset)
+                        .min(CHUNK_SIZE as u64),
+                )
+            }
+        }
+    }
+
+    fn write_chunk(&mut self, chunk_number: u32, data: &[u8]) -> io::Result<()> {
+        if chunk_number >= self.state.total_chunks {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Chunk number out of range",
+            ));
+        }
+        // Every chunk must exactly fill its slot, otherwise the file would have holes
+        let (offset, expected_len) = self.chunk_range(chunk_number);
+        if data.len() as u64 != expected_len {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid chunk size"));
+        }
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        self.state.received.set(chunk_number);
+        self.unsaved += 1;
+        if self.unsaved >= STATE_SAVE_CHUNKS || self.saved_at.elapsed() >= STATE_SAVE_INTERVAL {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Check the staging file against the size and SHA-256 the client sent
+    fn verify(&self, expected_size: u64, expected_checksum: &[u8; 32]) -> io::Result<()> {
+        if self.state.total_size != expected_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Size mismatch: received {} bytes, expected {}",
+                    self.state.total_size, expected_size
+                ),
+            ));
+        }
+        let mut file = File::open(&self.staging_path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                break;
             }
+            hasher.update(&buffer[..n]);
+        }
+        let checksum: [u8; 32] = hasher.finalize().into();
+        if &checksum != expected_checksum {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Checksum mismatch: reassembled file does not match the client's SHA-256",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the staging file is complete and flushed to disk
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.state.received.is_complete() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Not all chunks received",
+            ));
+        }
+        self.file.sync_all()?;
+        if self.file.metadata()?.len() != self.state.total_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Staging file size does not match upload",
+            ));
         }
+        Ok(())
+    }
+}
 
-        Ok(result)
+impl Drop for ChunkedUpload {
+    fn drop(&mut self) {
+        if self.unsaved > 0 {
+            let _ = self.checkpoint();
+        }
     }
 }
 
 impl Storage {
+    #[cfg(test)]
     pub fn new(root_dir: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_retention(root_dir, VersionRetention::default())
+    }
+
+    pub fn with_retention(
+        root_dir: impl AsRef<Path>,
+        retention: VersionRetention,
+    ) -> io::Result<Self> {
         let root = root_dir.as_ref().to_path_buf();
 
         // Create storage directory if it doesn't exist
         if !root.exists() {
             fs::create_dir_all(&root)?;
         }
+        // Resolved paths are checked against the canonical root
+        let root = fs::canonicalize(&root)?;
 
-        Ok(Self {
+        let staging_dir = root.join(INTERNAL_DIR).join("staging");
+        fs::create_dir_all(&staging_dir)?;
+        let pending = Self::load_pending_uploads(&staging_dir)?;
+        if !pending.is_empty() {
+            println!("Resumable uploads pending: {}", pending.len());
+        }
+
+        // Anything left in tmp/ is a write that never reached its rename
+        let tmp_dir = root.join(INTERNAL_DIR).join("tmp");
+        fs::create_dir_all(&tmp_dir)?;
+        let mut stale = 0;
+        for entry in fs::read_dir(&tmp_dir)? {
+            let path = entry?.path();
+            if path.is_file() && fs::remove_file(&path).is_ok() {
+                stale += 1;
+            }
+        }
+        if stale > 0 {
+            println!(
+                "Removed {} stale temp file(s) from interrupted writes",
+                stale
+            );
+        }
+
+        let versions_dir = root.join(INTERNAL_DIR).join("versions");
+        fs::create_dir_all(&versions_dir)?;
+        let chunks_dir = root.join(INTERNAL_DIR).join("chunks");
+        fs::create_dir_all(&chunks_dir)?;
+
+        let index = MetadataIndex::open(&root.join(INTERNAL_DIR))?;
+
+        let storage = Self {
             root_dir: root,
-            pending_chunks: Mutex::new(HashMap::new()),
-        })
+            staging_dir,
+            tmp_dir,
+            versions_dir,
+            chunks_dir,
+            retention,
+            compress_chunks: false,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            pending_chunks: Mutex::new(pending),
+            chunk_refs: Mutex::new(HashMap::new()),
+            stored_bytes: AtomicU64::new(0),
+            index,
+        };
+        storage.load_chunk_refs()?;
+        storage.reconcile_index()?;
+        Ok(storage)
     }
 
-    pub fn root_dir(&self) -> &std::path::PathBuf {
-        &self.root_dir
+    /// Store chunks written from now on compressed where that saves space.
+    /// Chunks already in the store are read either way.
+    pub fn with_compression(mut self, compress_chunks: bool) -> Self {
+        self.compress_chunks = compress_chunks;
+        self
     }
 
-    pub fn store_chunk(
-        &self,
-        filename: &str,
-        chunk_number: u32,
-        total_chunks: u32,
-        data: Vec<u8>,
-    ) -> io::Result<bool> {
-        // Validate filename
-        if filename.contains("..") || filename.contains('/') || filename.contains('\\') {
-            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filename"));
+    /// Refuse uploads of files larger than `max_upload_size` bytes
+    pub fn with_max_upload_size(mut self, max_upload_size: u64) -> Self {
+        self.max_upload_size = max_upload_size;
+        self
+    }
+
+    /// Record a file's listing metadata after its manifest was written. The
+    /// index is only a cache, so failing to update it is not an error.
+    fn index_file(&self, filename: &str, path: &Path, manifest: &Manifest) {
+        let result = normalize_path(filename).and_then(|key| {
+            let entry = index_entry(&fs::metadata(path)?, manifest);
+            self.index.put(&key, entry)
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to update metadata index: {}", e);
         }
+    }
 
-        let mut pending = self.pending_chunks.lock().unwrap();
+    /// Bring the metadata index in line with the files on disk: pick up
+    /// files added or replaced while the server was not running and forget
+    /// those that are gone. Only files whose manifest changed are read.
+    fn reconcile_index(&self) -> io::Result<()> {
+        let mut seen = HashSet::new();
+        let mut refreshed = 0;
+        let mut pending = vec![(self.root_dir.clone(), String::new())];
+        while let Some((dir, prefix)) = pending.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if prefix.is_empty() && name == INTERNAL_DIR {
+                    continue;
+                }
+                let key = if prefix.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", prefix, name)
+                };
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    pending.push((entry.path(), key));
+                } else if metadata.is_file() {
+                    if !self
+                        .index
+                        .get(&key)
+                        .is_some_and(|entry| is_current(&entry, &metadata))
+                    {
+                        match Manifest::read(&entry.path()) {
+                            Ok(manifest) => {
+                                self.index.put(&key, index_entry(&metadata, &manifest))?;
+                                refreshed += 1;
+                            }
+                            Err(e) => eprintln!("Skipping {}: {}", key, e),
+                        }
+                    }
+                    seen.insert(key);
+                }
+            }
+        }
+
+        let mut dropped = 0;
+        for key in self.index.paths() {
+            if !seen.contains(&key) {
+                self.index.remove(&key)?;
+                dropped += 1;
+            }
+        }
+        if refreshed > 0 || dropped > 0 {
+            println!(
+                "Metadata index: {} file(s) refreshed, {} removed",
+                refreshed, dropped
+            );
+        }
+        self.index.compact()
+    }
+
+    /// Count the chunk references of every manifest, current files and old
+    /// versions alike. Plain files left by older releases are converted to
+    /// manifests on the way, and chunks nothing refers to (from a crash
+    /// between writing chunks and their manifest) are removed.
+    fn load_chunk_refs(&self) -> io::Result<()> {
+        let mut files = Vec::new();
+        let mut pending = vec![self.root_dir.clone(), self.versions_dir.clone()];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+                if dir == self.root_dir && entry.file_name() == INTERNAL_DIR {
+                    continue;
+                }
+                if file_type.is_dir() {
+                    pending.push(entry.path());
+                } else if file_type.is_file() {
+                    files.push(entry.path());
+                }
+            }
+        }
 
-        let upload = pending
-            .entry(filename.to_string())
-            .or_insert_with(|| ChunkedUpload::new(total_chunks));
+        let mut converted = 0;
+        for path in files {
+            let mut file = File::open(&path)?;
+            let mut magic = [0u8; 8];
+            if read_full(&mut file, &mut magic)? == magic.len() && &magic == MANIFEST_MAGIC {
+                match Manifest::read(&path) {
+                    Ok(manifest) => self.retain_chunks(&manifest),
+                    Err(e) => eprintln!("Skipping {}: {}", path.display(), e),
+                }
+                continue;
+            }
 
-        upload.add_chunk(chunk_number, data);
+            // Keep the modification time, it doubles as the version's timestamp
+            let modified = file.metadata()?.modified()?;
+            file.seek(SeekFrom::Start(0))?;
+            let manifest = self.ingest(&mut file, &[])?;
+            let written = self.write_atomic(&path, |out| {
+                out.write_all(&manifest.encode())?;
+                out.set_modified(modified)
+            });
+            if let Err(e) = written {
+                self.release_chunks(&manifest);
+                return Err(e);
+            }
+            converted += 1;
+        }
+        if converted > 0 {
+            println!("Converted {} file(s) to the chunk store", converted);
+        }
 
-        Ok(upload.is_complete())
+        let refs = self.chunk_refs.lock().unwrap();
+        let referenced: HashSet<String> = refs.keys().map(|hash| hex::encode(hash)).collect();
+        let mut orphans = 0;
+        for entry in fs::read_dir(&self.chunks_dir)? {
+            let dir = entry?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                let hash = name.strip_suffix(".lz4").unwrap_or(&name);
+                if !referenced.contains(hash) && fs::remove_file(entry.path()).is_ok() {
+                    orphans += 1;
+                }
+            }
+        }
+        if orphans > 0 {
+            println!("Removed {} unreferenced chunk(s)", orphans);
+        }
+        Ok(())
     }
 
-    pub fn complete_chunked_upload(&self, filename: &str) -> io::Result<()> {
-        let mut pending = self.pending_chunks.lock().unwrap();
+    fn chunk_path(&self, hash: &[u8; 32]) -> PathBuf {
+        let name = hex::encode(hash);
+        self.chunks_dir.join(&name[..2]).join(name)
+    }
 
-        if let Some(upload) = pending.remove(filename) {
-            let data = upload.assemble()?;
-            drop(pending); // Release lock before file I/O
+    /// Where a chunk stored lz4-compressed lives: next to the raw path
+    fn compressed_chunk_path(&self, hash: &[u8; 32]) -> PathBuf {
+        self.chunk_path(hash).with_extension("lz4")
+    }
 
-            self.store(filename, &data)?;
-            Ok(())
-        } else {
-            Err(Error::new(
-                ErrorKind::NotFound,
-                "No pending upload for this file",
-            ))
+    /// Add a reference to a chunk, writing it to the store if it is new
+    fn put_chunk(&self, data: &[u8]) -> io::Result<ChunkRef> {
+        let hash: [u8; 32] = Sha256::digest(data).into();
+        let mut refs = self.chunk_refs.lock().unwrap();
+        match refs.get_mut(&hash) {
+            Some(count) => *count += 1,
+            None => {
+                let path = self.chunk_path(&hash);
+                let compressed_path = self.compressed_chunk_path(&hash);
+                if !path.is_file() && !compressed_path.is_file() {
+                    fs::create_dir_all(path.parent().unwrap_or(&self.chunks_dir))?;
+                    let compressed = self
+                        .compress_chunks
+                        .then(|| compression::compress(data))
+                        .filter(|(encoding, _)| *encoding == Encoding::Lz4);
+                    match compressed {
+                        Some((_, bytes)) => {
+                            self.write_atomic(&compressed_path, |file| file.write_all(&bytes))?
+                        }
+                        None => self.write_atomic(&path, |file| file.write_all(data))?,
+                    }
+                }
+                refs.insert(hash, 1);
+                self.stored_bytes
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
         }
+        Ok(ChunkRef {
+            hash,
+            len: data.len() as u32,
+        })
     }
 
-    pub fn store(&self, filename: &str, data: &[u8]) -> io::Result<()> {
-        // Validate filename (prevent path traversal)
-        if filename.contains("..") || filename.contains('/') || filename.contains('\\') {
-            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filename"));
+    /// Add a reference to every chunk of a manifest that got a new path
+    fn retain_chunks(&self, manifest: &Manifest) {
+        let mut refs = self.chunk_refs.lock().unwrap();
+        for chunk in &manifest.chunks {
+            let count = refs.entry(chunk.hash).or_insert(0);
+            if *count == 0 {
+                self.stored_bytes
+                    .fetch_add(chunk.len as u64, Ordering::Relaxed);
+            }
+            *count += 1;
         }
+    }
 
-        let file_path = self.root_dir.join(filename);
-        fs::write(file_path, data)?;
-        Ok(())
+    /// Drop a reference to every chunk of a manifest that lost its path,
+    /// deleting chunks no manifest uses any more
+    fn release_chunks(&self, manifest: &Manifest) {
+        let mut refs = self.chunk_refs.lock().unwrap();
+        for chunk in &manifest.chunks {
+            if let Some(count) = refs.get_mut(&chunk.hash) {
+                *count -= 1;
+                if *count == 0 {
+                    refs.remove(&chunk.hash);
+                    self.stored_bytes
+                        .fetch_sub(chunk.len as u64, Ordering::Relaxed);
+                    let _ = fs::remove_file(self.chunk_path(&chunk.hash));
+                    let _ = fs::remove_file(self.compressed_chunk_path(&chunk.hash));
+                }
+            }
+        }
     }
 
-    pub fn retrieve(&self, filename: &str) -> io::Result<Vec<u8>> {
-        // Validate filename
-        if filename.contains("..") || filename.contains('/') || filename.contains('\\') {
-            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filename"));
+    /// Split a stream into chunks in the store and describe it as a manifest.
+    /// `chunk_sizes` gives the chunk boundaries; if empty, the stream is cut
+    /// every `CHUNK_SIZE` bytes. The chunks are referenced once on behalf of
+    /// the returned manifest.
+    fn ingest(&self, reader: &mut impl Read, chunk_sizes: &[u32]) -> io::Result<Manifest> {
+        let mut manifest = Manifest::default();
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut sizes = chunk_sizes.iter().map(|&size| size as usize);
+        let result = (|| -> io::Result<()> {
+            loop {
+                let len = match sizes.next() {
+                    Some(len) => len,
+                    None if chunk_sizes.is_empty() => CHUNK_SIZE,
+                    None => return Ok(()),
+                };
+                buf.resize(len, 0);
+                let n = read_full(reader, &mut buf)?;
+                if n == 0 {
+                    return Ok(());
+                }
+                hasher.update(&buf[..n]);
+                manifest.chunks.push(self.put_chunk(&buf[..n])?);
+                manifest.size += n as u64;
+            }
+        })();
+        if let Err(e) = result {
+            self.release_chunks(&manifest);
+            return Err(e);
         }
+        manifest.checksum = hasher.finalize().into();
+        Ok(manifest)
+    }
 
-        let file_path = self.root_dir.join(filename);
+    /// Make `manifest` the contents of `filename`, archiving what was there.
+    /// Its chunks must already be referenced; that reference is dropped again
+    /// if the commit fails.
+    fn commit(
+        &self,
+        filename: &str,
+        dest: &Path,
+        manifest: &Manifest,
+        overwrite: Overwrite,
+    ) -> io::Result<()> {
+        let result = (|| {
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let previous = if dest.is_file() {
+                Some(Manifest::read(dest)?)
+            } else {
+                None
+            };
+            if overwrite == Overwrite::Replace {
+                self.archive_current(filename, dest)?;
+            }
+            self.install(dest, overwrite, |file| file.write_all(&manifest.encode()))?;
+            Ok(previous)
+        })();
 
-        if !file_path.exists() {
-            return Err(Error::new(ErrorKind::NotFound, "File not found"));
+        match result {
+            Ok(previous) => {
+                if let Some(previous) = previous {
+                    self.release_chunks(&previous);
+                }
+                self.index_file(filename, dest, manifest);
+                Ok(())
+            }
+            Err(e) => {
+                self.release_chunks(manifest);
+                Err(e)
+            }
         }
-
-        fs::read(file_path)
     }
 
-    pub fn delete(&self, filename: &str) -> io::Result<()> {
-        // Validate filename
-        if filename.contains("..") || filename.contains('/') || filename.contains('\\') {
-            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filename"));
+    /// Load a chunk, checking it still matches its hash
+    fn read_chunk(&self, chunk: &ChunkRef) -> io::Result<Vec<u8>> {
+        let data = match fs::read(self.chunk_path(&chunk.hash)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => compression::decompress(
+                Encoding::Lz4,
+                &fs::read(self.compressed_chunk_path(&chunk.hash))?,
+                chunk.len as usize,
+            )?,
+            other => other?,
+        };
+        if data.len() != chunk.len as usize || Sha256::digest(&data).as_slice() != chunk.hash {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Stored chunk is corrupt",
+            ));
         }
+        Ok(data)
+    }
 
-        let file_path = self.root_dir.join(filename);
-
-        if !file_path.exists() {
-            return Err(Error::new(ErrorKind::NotFound, "File not found"));
+    /// Up to `len` bytes of a file's contents starting at `offset`
+    fn read_range(&self, manifest: &Manifest, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let end = offset.saturating_add(len as u64).min(manifest.size);
+        let mut data = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        let mut start = 0u64;
+        for chunk in &manifest.chunks {
+            let chunk_end = start + chunk.len as u64;
+            if start >= end {
+                break;
+            }
+            if chunk_end > offset {
+                let bytes = self.read_chunk(chunk)?;
+                let from = offset.saturating_sub(start) as usize;
+                let to = (end - start).min(chunk.len as u64) as usize;
+                data.extend_from_slice(&bytes[from..to]);
+            }
+            start = chunk_end;
         }
-
-        fs::remove_file(file_path)
+        Ok(data)
     }
 
-    pub fn list(&self) -> io::Result<Vec<FileMetadata>> {
-        let mut result = Vec::new();
+    /// Reload upload sessions left by a previous run, discarding stale or broken ones
+    fn load_pending_uploads(
+        staging_dir: &Path,
+    ) -> io::Result<HashMap<String, Arc<Mutex<ChunkedUpload>>>> {
+        let mut pending = HashMap::new();
 
-        for entry in fs::read_dir(&self.root_dir)? {
-            let entry = entry?;
-            let path = entry.path();
+        for entry in fs::read_dir(staging_dir)? {
+            let path = entry?.path();
+            let upload_id = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
 
-            if path.is_file() {
-                // Filename
-                let filename = path
-                    .file_name()
-                    .and_then(|n| n.to_str())
-                    .unwrap_or("")
-                    .to_string();
+            let stale = fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.elapsed().ok())
+                .map(|age| age.as_secs() > STALE_UPLOAD_SECS)
+                .unwrap_or(true);
 
-                // File size and modified time
-                let metadata = entry.metadata()?;
-                let size = metadata.len();
-
-                let time = metadata
-                    .modified()
-                    .unwrap_or_else(|_| std::time::SystemTime::UNIX_EPOCH);
-                let datetime: chrono::DateTime<chrono::Utc> = time.into();
-                let last_modified = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
-
-                // SHA256 checksum
-                let mut file = fs::File::open(&path)?;
-                let mut hasher = Sha256::new();
-                let mut buffer = [0; 8192];
-                loop {
-                    let n = file.read(&mut buffer)?;
-                    if n == 0 {
-                        break;
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("state") if !stale => match ChunkedUpload::open(&path) {
+                    Ok(upload) => {
+                        pending.insert(upload_id, Arc::new(Mutex::new(upload)));
+                    }
+                    Err(e) => {
+                        eprintln!("Discarding broken upload {}: {}", upload_id, e);
+                        let _ = fs::remove_file(&path);
+                        let _ = fs::remove_file(path.with_extension("part"));
                     }
-                    hasher.update(&buffer[..n]);
+                },
+                Some("state") => {
+                    let _ = fs::remove_file(&path);
+                    let _ = fs::remove_file(path.with_extension("part"));
                 }
-                let checksum = format!("{:x}", hasher.finalize());
+                _ => {}
+            }
+        }
 
-                result.push(FileMetadata {
-                    filename,
-                    size,
-                    last_modified,
-                    checksum,
-                });
+        // Staging files without a session are orphans
+        for entry in fs::read_dir(staging_dir)? {
+            let path = entry?.path();
+            let has_session = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .map(|id| pending.contains_key(id))
+                .unwrap_or(false);
+            if !has_session {
+                let _ = fs::remove_file(&path);
             }
         }
 
-        result.sort_by(|a, b| a.filename.cmp(&b.filename));
-        Ok(result)
+        Ok(pending)
     }
 
-    pub fn retrieve_chunk(
-        &self,
-        filename: &str,
-        chunk_number: u32,
-        chunk_size: usize,
-    ) -> io::Result<Vec<u8>> {
-        if filename.contains("..") || filename.contains('/') || filename.contains('\\') {
-            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filename"));
-        }
-        let file_path = self.root_dir.join(filename);
-        let mut file = File::open(&file_path)?;
+    /// Map a client path onto the filesystem. Besides the lexical checks in
+    /// `normalize_path`, the deepest existing ancestor is canonicalised so a
+    /// symlink inside the root cannot lead outside it.
+    pub fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let normalized = normalize_path(path)?;
+        let full = self.root_dir.join(&normalized);
+
+        let mut existing = full.as_path();
+        while !existing.exists() {
+            match existing.parent() {
+                Some(parent) => existing = parent,
+                None => break,
+            }
+        }
+        if !fs::canonicalize(existing)?.starts_with(&self.root_dir) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Path escapes the storage root",
+            ));
+        }
+        Ok(full)
+    }
+
+    /// Like `resolve`, but the path must name something below the root
+    fn resolve_file(&self, path: &str) -> io::Result<PathBuf> {
+        if normalize_path(path)?.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filename"));
+        }
+        self.resolve(path)
+    }
+
+    fn get_upload(&self, upload_id: &str) -> io::Result<Arc<Mutex<ChunkedUpload>>> {
+        self.pending_chunks
+            .lock()
+            .unwrap()
+            .get(upload_id)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No such upload session"))
+    }
+
+    /// Start an upload session, or resume the pending one for the same file
+    /// contents. `chunk_sizes` is the client's chunk layout, or empty for
+    /// fixed `CHUNK_SIZE` chunks. Returns the session ID and the chunks still
+    /// missing.
+    pub fn begin_upload(
+        &self,
+        filename: &str,
+        total_size: u64,
+        total_chunks: u32,
+        checksum: [u8; 32],
+        chunk_sizes: Vec<u32>,
+    ) -> io::Result<(String, Vec<Range<u32>>)> {
+        self.resolve_file(filename)?;
+        let filename = normalize_path(filename)?;
+        // The staging file, bitmap and missing list all grow with the size
+        if total_size > self.max_upload_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Upload of {} bytes exceeds the server's limit of {} bytes",
+                    total_size, self.max_upload_size
+                ),
+            ));
+        }
+        if chunk_sizes.is_empty() {
+            if total_size.div_ceil(CHUNK_SIZE as u64) != total_chunks as u64 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Chunk count does not match file size",
+                ));
+            }
+        } else if chunk_sizes.len() != total_chunks as usize
+            || chunk_sizes.iter().map(|&size| size as u64).sum::<u64>() != total_size
+            || chunk_sizes
+                .iter()
+                .any(|&size| size == 0 || size as usize > MAX_CHUNK_SIZE)
+            // Only the last chunk may be cut short by the end of the file
+            || chunk_sizes[..chunk_sizes.len() - 1]
+                .iter()
+                .any(|&size| (size as usize) < MIN_CHUNK_SIZE)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Chunk layout does not match file size",
+            ));
+        }
+
+        // Sessions are locked with the map released: completing an upload
+        // takes its session lock first and the map lock second
+        let sessions: Vec<(String, Arc<Mutex<ChunkedUpload>>)> = self
+            .pending_chunks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, upload)| (id.clone(), upload.clone()))
+            .collect();
+        for (upload_id, upload) in sessions {
+            let upload = upload.lock().unwrap();
+            let state = &upload.state;
+            if state.filename == filename
+                && state.total_size == total_size
+                && state.checksum == checksum
+                && upload.chunk_sizes == chunk_sizes
+            {
+                return Ok((upload_id, state.received.missing()));
+            }
+        }
+
+        let upload_id = hex::encode(&crate::auth::generate_nonce()[..16]);
+        let state = UploadState {
+            filename: filename.clone(),
+            total_size,
+            total_chunks,
+            checksum,
+            received: ChunkBitmap::new(total_chunks),
+        };
+        let mut upload = ChunkedUpload::create(&self.staging_dir, &upload_id, state, chunk_sizes)?;
+        let missing = upload.state.received.missing();
+
+        let mut pending = self.pending_chunks.lock().unwrap();
+        let evicted = if pending.len() >= MAX_PENDING_UPLOADS {
+            match Self::evict_idle_upload(&mut pending) {
+                Ok(evicted) => Some(evicted),
+                Err(e) => {
+                    upload.remove_files();
+                    return Err(e);
+                }
+            }
+        } else {
+            None
+        };
+        pending.insert(upload_id.clone(), Arc::new(Mutex::new(upload)));
+        drop(pending);
+
+        if let Some(evicted) = evicted {
+            evicted.lock().unwrap().remove_files();
+        }
+        Ok((upload_id, missing))
+    }
+
+    /// Make room for a new upload session by dropping the one that has gone
+    /// longest without a checkpoint. Sessions busy right now are left alone.
+    /// Returns the dropped session, whose files the caller removes once it
+    /// has released the map.
+    fn evict_idle_upload(
+        pending: &mut HashMap<String, Arc<Mutex<ChunkedUpload>>>,
+    ) -> io::Result<Arc<Mutex<ChunkedUpload>>> {
+        let idle = pending
+            .iter()
+            .filter_map(|(id, upload)| Some((upload.try_lock().ok()?.saved_at, id.clone())))
+            .min();
+        let Some((_, upload_id)) = idle else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Too many uploads in progress",
+            ));
+        };
+        println!("Dropping idle upload session {}", upload_id);
+        Ok(pending
+            .remove(&upload_id)
+            .expect("session found in the map just above"))
+    }
+
+    /// Chunks an upload session has not received yet
+    pub fn upload_status(&self, upload_id: &str) -> io::Result<Vec<Range<u32>>> {
+        let upload = self.get_upload(upload_id)?;
+        let upload = upload.lock().unwrap();
+        Ok(upload.state.received.missing())
+    }
+
+    /// Fill an upload session from chunks the store already holds. `hashes`
+    /// are the SHA-256 of the session's chunks from `first_chunk` on; returns
+    /// the chunks the client still has to send.
+    pub fn offer_chunks(
+        &self,
+        upload_id: &str,
+        first_chunk: u32,
+        hashes: &[[u8; 32]],
+    ) -> io::Result<Vec<Range<u32>>> {
+        let upload = self.get_upload(upload_id)?;
+        let mut upload = upload.lock().unwrap();
+        if first_chunk as u64 + hashes.len() as u64 > upload.state.total_chunks as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Chunk number out of range",
+            ));
+        }
+
+        for (chunk_number, hash) in (first_chunk..).zip(hashes) {
+            if upload.state.received.get(chunk_number)
+                || !self.chunk_refs.lock().unwrap().contains_key(hash)
+            {
+                continue;
+            }
+            // A chunk released since the check, or one that does not fit this
+            // slot, is simply left for the client to send
+            let len = upload.chunk_range(chunk_number).1 as u32;
+            let Ok(data) = self.read_chunk(&ChunkRef { hash: *hash, len }) else {
+                continue;
+            };
+            upload.write_chunk(chunk_number, &data)?;
+        }
+        Ok(upload.state.received.missing())
+    }
+
+    pub fn store_chunk(
+        &self,
+        upload_id: &str,
+        chunk_number: u32,
+        total_chunks: u32,
+        data: Vec<u8>,
+    ) -> io::Result<bool> {
+        let upload = self.get_upload(upload_id)?;
+
+        // Only this upload is locked while writing, other uploads proceed in parallel
+        let mut upload = upload.lock().unwrap();
+        if upload.state.total_chunks != total_chunks {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Chunk count does not match upload in progress",
+            ));
+        }
+        upload.write_chunk(chunk_number, &data)?;
+
+        Ok(upload.state.received.is_complete())
+    }
+
+    /// Write one chunk of an upload session from a delta against the current
+    /// file, which must still have the SHA-256 the client took signatures of
+    pub fn store_delta(
+        &self,
+        upload_id: &str,
+        chunk_number: u32,
+        total_chunks: u32,
+        base_checksum: &[u8; 32],
+        instructions: &[DeltaInstruction],
+    ) -> io::Result<bool> {
+        let upload = self.get_upload(upload_id)?;
+        let mut upload = upload.lock().unwrap();
+        if upload.state.total_chunks != total_chunks {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Chunk count does not match upload in progress",
+            ));
+        }
+
+        let base_path = self.resolve_file(&upload.state.filename)?;
+        if !base_path.is_file() {
+            return Err(Error::new(ErrorKind::NotFound, "No base file for delta"));
+        }
+        let base = Manifest::read(&base_path)?;
+        if &base.checksum != base_checksum {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Base file has changed since its signatures were taken",
+            ));
+        }
+
+        let (_, len) = upload.chunk_range(chunk_number);
+        let mut reader = BaseReader::new(self, &base, len as usize);
+        let data = delta::apply(instructions, len as usize, |offset, len| {
+            reader.read(offset, len)
+        })?;
+        upload.write_chunk(chunk_number, &data)?;
+
+        Ok(upload.state.received.is_complete())
+    }
+
+    /// Commit a finished upload session, returning the filename it was stored under.
+    /// The reassembled file must match the size and SHA-256 the client expects.
+    pub fn complete_chunked_upload(
+        &self,
+        upload_id: &str,
+        expected_size: u64,
+        expected_checksum: &[u8; 32],
+        overwrite: Overwrite,
+    ) -> io::Result<String> {
+        let upload = self.get_upload(upload_id)?;
+        let mut upload = upload.lock().unwrap();
+
+        // An incomplete session stays pending so the client can send what is missing
+        upload.finish()?;
+
+        // A corrupt reassembly is discarded; resending into it would not help
+        if let Err(e) = upload.verify(expected_size, expected_checksum) {
+            upload.remove_files();
+            self.pending_chunks.lock().unwrap().remove(upload_id);
+            return Err(e);
+        }
+
+        let filename = upload.state.filename.clone();
+        let result = self.resolve_file(&filename).and_then(|dest| {
+            let staged = &mut File::open(&upload.staging_path)?;
+            let manifest = self.ingest(staged, &upload.chunk_sizes)?;
+            self.commit(&filename, &dest, &manifest, overwrite)
+        });
+        upload.remove_files();
+        self.pending_chunks.lock().unwrap().remove(upload_id);
+        result.map(|_| filename)
+    }
+
+    /// Write `dest` so that readers only ever see the old or the new contents.
+    /// Data goes to a temp file first, is fsynced, then renamed over `dest`,
+    /// and the directory is fsynced so the rename itself survives a crash.
+    fn write_atomic<F>(&self, dest: &Path, write: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut File) -> io::Result<()>,
+    {
+        self.install(dest, Overwrite::Replace, write)
+    }
+
+    /// `write_atomic`, except that with `Overwrite::Refuse` the temp file is
+    /// hard-linked into place, which fails if `dest` already exists
+    fn install<F>(&self, dest: &Path, overwrite: Overwrite, write: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut File) -> io::Result<()>,
+    {
+        let nonce = hex::encode(&crate::auth::generate_nonce()[..8]);
+        let tmp_path = self.tmp_dir.join(format!("{}.tmp", nonce));
+
+        let result = (|| {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&tmp_path)?;
+            write(&mut file)?;
+            file.sync_all()?;
+            match overwrite {
+                Overwrite::Replace => fs::rename(&tmp_path, dest),
+                Overwrite::Refuse => match fs::hard_link(&tmp_path, dest) {
+                    Ok(()) => fs::remove_file(&tmp_path),
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                        Err(Error::new(ErrorKind::AlreadyExists, "File already exists"))
+                    }
+                    // Without hard links the check and the rename can race
+                    Err(_) if dest.exists() => {
+                        Err(Error::new(ErrorKind::AlreadyExists, "File already exists"))
+                    }
+                    Err(_) => fs::rename(&tmp_path, dest),
+                },
+            }
+        })();
+
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        sync_dir(dest.parent().unwrap_or(&self.root_dir))
+    }
+
+    #[cfg(test)]
+    pub fn store(&self, filename: &str, data: &[u8]) -> io::Result<()> {
+        self.store_with(filename, data, Overwrite::Replace)
+    }
+
+    pub fn store_with(
+        &self,
+        filename: &str,
+        mut data: &[u8],
+        overwrite: Overwrite,
+    ) -> io::Result<()> {
+        let file_path = self.resolve_file(filename)?;
+        let manifest = self.ingest(&mut data, &[])?;
+        self.commit(filename, &file_path, &manifest, overwrite)
+    }
+
+    pub fn retrieve(&self, filename: &str) -> io::Result<Vec<u8>> {
+        let file_path = self.resolve_file(filename)?;
+
+        if !file_path.is_file() {
+            return Err(Error::new(ErrorKind::NotFound, "File not found"));
+        }
+
+        let manifest = Manifest::read(&file_path)?;
+        self.read_range(&manifest, 0, manifest.size as usize)
+    }
+
+    pub fn delete(&self, filename: &str) -> io::Result<()> {
+        let file_path = self.resolve_file(filename)?;
+
+        if !file_path.is_file() {
+            return Err(Error::new(ErrorKind::NotFound, "File not found"));
+        }
+
+        // A deleted file stays retrievable through its version history
+        let manifest = Manifest::read(&file_path)?;
+        self.archive_current(filename, &file_path)?;
+        fs::remove_file(file_path)?;
+        self.release_chunks(&manifest);
+        if let Err(e) = self.index.remove(&normalize_path(filename)?) {
+            eprintln!("Failed to update metadata index: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Check the two paths of a rename or copy, returning them normalised
+    /// with `from` resolved. Files may be moved over an existing file, which
+    /// is kept as a version; anything else must not exist at `to`.
+    fn move_paths(&self, from: &str, to: &str) -> io::Result<(String, String, PathBuf, PathBuf)> {
+        let (from, to) = (normalize_path(from)?, normalize_path(to)?);
+        let (src, dest) = (self.resolve_file(&from)?, self.resolve_file(&to)?);
+        if !src.exists() {
+            return Err(Error::new(ErrorKind::NotFound, "File not found"));
+        }
+        if from == to || to.starts_with(&format!("{}/", from)) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Cannot move or copy a path onto itself or into itself",
+            ));
+        }
+        if dest.exists() && !(src.is_file() && dest.is_file()) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                "Destination already exists",
+            ));
+        }
+        Ok((from, to, src, dest))
+    }
+
+    /// Move a file or directory to a new path in one atomic rename. Old
+    /// versions follow it, so its history stays with the contents.
+    pub fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to, src, dest) = self.move_paths(from, to)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let previous = if dest.is_file() {
+            Some(Manifest::read(&dest)?)
+        } else {
+            None
+        };
+        self.archive_current(&to, &dest)?;
+        fs::rename(&src, &dest)?;
+        sync_dir(dest.parent().unwrap_or(&self.root_dir))?;
+        sync_dir(src.parent().unwrap_or(&self.root_dir))?;
+        if let Some(previous) = previous {
+            self.release_chunks(&previous);
+        }
+
+        if let Err(e) = self
+            .index
+            .remove(&to)
+            .and_then(|_| self.index.rename(&from, &to))
+        {
+            eprintln!("Failed to update metadata index: {}", e);
+        }
+        self.move_history(&from, &to, dest.is_dir())
+    }
+
+    /// Copy a file, or a directory with everything in it. Copies are new
+    /// manifests referring to the same chunks, so no file data is duplicated.
+    pub fn copy(&self, from: &str, to: &str, overwrite: Overwrite) -> io::Result<()> {
+        let (_, to, src, dest) = self.move_paths(from, to)?;
+        if src.is_file() {
+            return self.copy_file(&to, &src, &dest, overwrite);
+        }
+
+        let mut pending = vec![(src, dest, to)];
+        while let Some((src_dir, dest_dir, prefix)) = pending.pop() {
+            fs::create_dir_all(&dest_dir)?;
+            for entry in fs::read_dir(&src_dir)? {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                let dest_path = dest_dir.join(&name);
+                let filename = format!("{}/{}", prefix, name);
+                if file_type.is_dir() {
+                    pending.push((entry.path(), dest_path, filename));
+                } else if file_type.is_file() {
+                    self.copy_file(&filename, &entry.path(), &dest_path, overwrite)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_file(
+        &self,
+        filename: &str,
+        src: &Path,
+        dest: &Path,
+        overwrite: Overwrite,
+    ) -> io::Result<()> {
+        let manifest = Manifest::read(src)?;
+        self.retain_chunks(&manifest);
+        self.commit(filename, dest, &manifest, overwrite)
+    }
+
+    /// Hand the old versions kept for `from` over to `to`. With `subtree`,
+    /// the histories of files below `from` move as well. Version IDs already
+    /// taken at `to` are bumped, so no version is ever overwritten.
+    fn move_history(&self, from: &str, to: &str, subtree: bool) -> io::Result<()> {
+        let (from_dir, to_dir) = (self.history_dir(from)?, self.history_dir(to)?);
+        if !from_dir.is_dir() {
+            return Ok(());
+        }
+        if subtree && !to_dir.exists() {
+            fs::create_dir_all(to_dir.parent().unwrap_or(&self.versions_dir))?;
+            fs::rename(&from_dir, &to_dir)?;
+            return sync_dir(to_dir.parent().unwrap_or(&self.versions_dir));
+        }
+
+        let mut moved = false;
+        for entry in fs::read_dir(&from_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_dir() {
+                if subtree {
+                    let (from, to) = (format!("{}/{}", from, name), format!("{}/{}", to, name));
+                    self.move_history(&from, &to, true)?;
+                }
+                continue;
+            }
+            let Some((version, superseded)) = parse_version_name(&name) else {
+                continue;
+            };
+
+            let taken: Vec<u64> = self
+                .archived_versions(to)?
+                .iter()
+                .map(|(v, _, _)| *v)
+                .collect();
+            let mut version = version;
+            while taken.contains(&version) {
+                version += 1;
+            }
+            fs::create_dir_all(&to_dir)?;
+            fs::rename(
+                entry.path(),
+                to_dir.join(format!("{}-{}", version, superseded)),
+            )?;
+            moved = true;
+        }
+        // Only empty once nothing further down stayed behind
+        let _ = fs::remove_dir(&from_dir);
+        if moved {
+            sync_dir(&to_dir)?;
+            self.prune_versions(to)?;
+        }
+        Ok(())
+    }
+
+    /// Directory holding the old versions of one file
+    fn history_dir(&self, filename: &str) -> io::Result<PathBuf> {
+        let normalized = normalize_path(filename)?;
+        if normalized.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid filename"));
+        }
+        Ok(self.versions_dir.join(normalized))
+    }
+
+    /// Old versions of a file as (version id, superseded at, path), newest first.
+    /// Each is stored as `<version>-<superseded unix secs>`.
+    fn archived_versions(&self, filename: &str) -> io::Result<Vec<(u64, u64, PathBuf)>> {
+        let dir = self.history_dir(filename)?;
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut versions = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue; // history of a file further down the tree
+            }
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some((version, superseded)) = parse_version_name(&name) {
+                versions.push((version, superseded, entry.path()));
+            }
+        }
+        versions.sort_by_key(|v| std::cmp::Reverse(v.0));
+        Ok(versions)
+    }
+
+    /// Keep the file currently at `path` as an old version before it is
+    /// replaced or deleted. Its manifest is hard-linked, so the live file is
+    /// never touched and a crash at worst leaves one extra version behind.
+    fn archive_current(&self, filename: &str, path: &Path) -> io::Result<()> {
+        if !path.is_file() {
+            return Ok(());
+        }
+        let dir = self.history_dir(filename)?;
+        fs::create_dir_all(&dir)?;
+
+        let mut version = unix_micros(fs::metadata(path)?.modified()?);
+        let now = unix_micros(std::time::SystemTime::now()) / 1_000_000;
+        let taken: Vec<u64> = self
+            .archived_versions(filename)?
+            .iter()
+            .map(|(v, _, _)| *v)
+            .collect();
+        while version == 0 || taken.contains(&version) {
+            version += 1;
+        }
+
+        let manifest = Manifest::read(path)?;
+        let archived = dir.join(format!("{}-{}", version, now));
+        if fs::hard_link(path, &archived).is_err() {
+            fs::copy(path, &archived)?;
+        }
+        self.retain_chunks(&manifest);
+        sync_dir(&dir)?;
+        self.prune_versions(filename)
+    }
+
+    /// Apply the retention policy to one file's history
+    fn prune_versions(&self, filename: &str) -> io::Result<()> {
+        let now = unix_micros(std::time::SystemTime::now()) / 1_000_000;
+        let max_age_secs = self.retention.max_age_days * 24 * 60 * 60;
+
+        for (index, (_, superseded, path)) in
+            self.archived_versions(filename)?.into_iter().enumerate()
+        {
+            let too_many = self.retention.max_versions > 0 && index >= self.retention.max_versions;
+            let too_old =
+                self.retention.max_age_days > 0 && now.saturating_sub(superseded) > max_age_secs;
+            if too_many || too_old {
+                let manifest = Manifest::read(&path);
+                fs::remove_file(&path)?;
+                if let Ok(manifest) = manifest {
+                    self.release_chunks(&manifest);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Current and previous versions of a file, newest first
+    pub fn list_versions(&self, filename: &str) -> io::Result<Vec<VersionInfo>> {
+        let mut versions = Vec::new();
+
+        let current = self.resolve_file(filename)?;
+        if current.is_file() {
+            let manifest = Manifest::read(&current)?;
+            versions.push(VersionInfo {
+                version: 0,
+                size: manifest.size,
+                stored_at: unix_micros(fs::metadata(&current)?.modified()?),
+                checksum: hex::encode(&manifest.checksum),
+            });
+        }
+        for (version, _, path) in self.archived_versions(filename)? {
+            let manifest = Manifest::read(&path)?;
+            versions.push(VersionInfo {
+                version,
+                size: manifest.size,
+                stored_at: version,
+                checksum: hex::encode(&manifest.checksum),
+            });
+        }
+
+        if versions.is_empty() {
+            return Err(Error::new(ErrorKind::NotFound, "File not found"));
+        }
+        Ok(versions)
+    }
+
+    /// Metadata of one file or directory, with version and chunk counts for files
+    pub fn stat(&self, path: &str) -> io::Result<FileStat> {
+        let filename = normalize_path(path)?;
+        let full = self.resolve_file(&filename)?;
+        let metadata = match fs::metadata(&full) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(Error::new(ErrorKind::NotFound, "File not found"))
+            }
+            Err(e) => return Err(e),
+        };
+
+        if metadata.is_dir() {
+            return Ok(FileStat {
+                metadata: FileMetadata {
+                    filename,
+                    size: 0,
+                    last_modified: format_modified(&metadata),
+                    checksum: String::new(),
+                    entry_type: EntryType::Directory,
+                },
+                versions: 0,
+                chunks: 0,
+                shared_chunks: 0,
+            });
+        }
+
+        let manifest = Manifest::read(&full)?;
+        if let Err(e) = self.index.put(&filename, index_entry(&metadata, &manifest)) {
+            eprintln!("Failed to update metadata index: {}", e);
+        }
+        let shared_chunks = {
+            let refs = self.chunk_refs.lock().unwrap();
+            manifest
+                .chunks
+                .iter()
+                .filter(|chunk| refs.get(&chunk.hash).is_some_and(|count| *count > 1))
+                .count() as u32
+        };
+        Ok(FileStat {
+            versions: self.archived_versions(&filename)?.len() as u32,
+            chunks: manifest.chunks.len() as u32,
+            shared_chunks,
+            metadata: FileMetadata {
+                filename,
+                size: manifest.size,
+                last_modified: format_modified(&metadata),
+                checksum: hex::encode(&manifest.checksum),
+                entry_type: EntryType::File,
+            },
+        })
+    }
+
+    /// Path holding a given version of a file (0 = current)
+    pub fn version_path(&self, filename: &str, version: u64) -> io::Result<PathBuf> {
+        if version == 0 {
+            return self.resolve_file(filename);
+        }
+        self.archived_versions(filename)?
+            .into_iter()
+            .find(|(v, _, _)| *v == version)
+            .map(|(_, _, path)| path)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No such version"))
+    }
+
+    /// Whether a file or directory is stored at `path`
+    pub fn exists(&self, path: &str) -> io::Result<bool> {
+        Ok(self.resolve_file(path)?.exists())
+    }
+
+    /// Size in bytes of a given version of a file (0 = current)
+    pub fn file_size(&self, filename: &str, version: u64) -> io::Result<u64> {
+        Ok(Manifest::read(&self.version_path(filename, version)?)?.size)
+    }
+
+    /// Create a directory, including any missing parents
+    pub fn mkdir(&self, path: &str) -> io::Result<()> {
+        let dir_path = self.resolve_file(path)?;
+        fs::create_dir_all(&dir_path)?;
+        sync_dir(dir_path.parent().unwrap_or(&self.root_dir))
+    }
+
+    /// Remove an empty directory
+    pub fn rmdir(&self, path: &str) -> io::Result<()> {
+        let dir_path = self.resolve_file(path)?;
+
+        if !dir_path.is_dir() {
+            return Err(Error::new(ErrorKind::NotFound, "Directory not found"));
+        }
+        if fs::read_dir(&dir_path)?.next().is_some() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Directory not empty"));
+        }
+
+        fs::remove_dir(&dir_path)?;
+        sync_dir(dir_path.parent().unwrap_or(&self.root_dir))
+    }
+
+    /// List the entries of a directory ("" for the root), optionally
+    /// descending into subdirectories. File sizes and checksums come from
+    /// the metadata index, so only manifests changed behind its back are
+    /// read. The totals are the listed files' combined size and what the
+    /// whole chunk store holds once shared chunks are counted only once.
+    pub fn list(&self, path: &str, recursive: bool) -> io::Result<Listing> {
+        let prefix = normalize_path(path)?;
+        let dir_path = self.resolve(path)?;
+        if !dir_path.is_dir() {
+            return Err(Error::new(ErrorKind::NotFound, "Directory not found"));
+        }
+
+        let mut result = Vec::new();
+        let mut pending = vec![(dir_path, prefix)];
+
+        while let Some((dir, prefix)) = pending.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                let name = entry.file_name().to_string_lossy().to_string();
+
+                // Server-internal state is not part of the namespace
+                if prefix.is_empty() && name == INTERNAL_DIR {
+                    continue;
+                }
+                let filename = if prefix.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", prefix, name)
+                };
+
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() && recursive {
+                    pending.push((path.clone(), filename.clone()));
+                }
+                if let Some(listed) = self.list_entry(&path, filename, &metadata)? {
+                    result.push(listed);
+                }
+            }
+        }
+
+        result.sort_by(|a, b| a.filename.cmp(&b.filename));
+        Ok(Listing {
+            total_entries: result.len() as u64,
+            logical_size: result.iter().map(|m| m.size).sum(),
+            physical_size: self.stored_bytes.load(Ordering::Relaxed),
+            entries: result,
+            next_cursor: None,
+        })
+    }
+
+    /// Listing entry for one directory or file, or None for anything else.
+    /// File sizes and checksums come from the metadata index, refreshed from
+    /// the manifest when it is out of date.
+    fn list_entry(
+        &self,
+        path: &Path,
+        filename: String,
+        metadata: &fs::Metadata,
+    ) -> io::Result<Option<FileMetadata>> {
+        let last_modified = format_modified(metadata);
+        if metadata.is_dir() {
+            return Ok(Some(FileMetadata {
+                filename,
+                size: 0,
+                last_modified,
+                checksum: String::new(),
+                entry_type: EntryType::Directory,
+            }));
+        }
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+        let indexed = match self.index.get(&filename) {
+            Some(entry) if is_current(&entry, metadata) => entry,
+            _ => {
+                let entry = index_entry(metadata, &Manifest::read(path)?);
+                if let Err(e) = self.index.put(&filename, entry.clone()) {
+                    eprintln!("Failed to update metadata index: {}", e);
+                }
+                entry
+            }
+        };
+        Ok(Some(FileMetadata {
+            filename,
+            size: indexed.size,
+            last_modified,
+            checksum: hex::encode(&indexed.checksum),
+            entry_type: EntryType::File,
+        }))
+    }
+
+    /// Visit the entries below `dir` in full-path order, skipping those up
+    /// to and including `after`, until `visit` returns false. A directory's
+    /// contents sort as "name/...", so they come between the siblings
+    /// ordered before and after that key, not straight after the directory
+    /// itself. Subtrees that lie wholly at or before `after` are not read.
+    /// Returns false once `visit` has asked to stop.
+    fn walk_after(
+        &self,
+        dir: &Path,
+        prefix: &str,
+        recursive: bool,
+        after: Option<&str>,
+        visit: &mut dyn FnMut(FileMetadata) -> bool,
+    ) -> io::Result<bool> {
+        let mut items = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if prefix.is_empty() && name == INTERNAL_DIR {
+                continue;
+            }
+            let filename = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() && recursive {
+                items.push((format!("{}/", filename), entry.path(), None));
+            }
+            items.push((filename, entry.path(), Some(metadata)));
+        }
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (key, path, metadata) in items {
+            match metadata {
+                Some(metadata) => {
+                    if after.is_some_and(|after| key.as_str() <= after) {
+                        continue;
+                    }
+                    if let Some(listed) = self.list_entry(&path, key, &metadata)? {
+                        if !visit(listed) {
+                            return Ok(false);
+                        }
+                    }
+                }
+                None => {
+                    // Every path in the subtree starts with `key`
+                    let wanted =
+                        after.is_none_or(|after| after < key.as_str() || after.starts_with(&key));
+                    if wanted
+                        && !self.walk_after(
+                            &path,
+                            key.trim_end_matches('/'),
+                            recursive,
+                            after,
+                            visit,
+                        )?
+                    {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// One page of a filtered, sorted listing. The cursor names the last
+    /// entry of the previous page, so paging needs no state on the server
+    /// and entries added or removed in between do not shift later pages.
+    ///
+    /// Totals for the whole listing are only filled in on the first page,
+    /// which walks the full tree to compute them; later pages leave them at
+    /// zero. A later page in name order walks from the cursor and stops
+    /// once it has `limit` entries, so paging through N entries costs about
+    /// O(N) overall. Size and modified order have no such shortcut and sort
+    /// every matching entry for each page.
+    pub fn list_page(&self, request: &ListRequest) -> io::Result<Listing> {
+        if let Some(filter) = &request.filter {
+            if filter.chars().count() > MAX_LIST_FILTER_LEN
+                || filter.matches(['*', '?', '[']).count() > MAX_LIST_FILTER_WILDCARDS
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "List filter is too long or has too many wildcards",
+                ));
+            }
+        }
+        let limit = match request.limit {
+            0 => MAX_LIST_PAGE,
+            limit => limit.min(MAX_LIST_PAGE),
+        } as usize;
+        let last = match &request.cursor {
+            Some(cursor) => Some(
+                parse_cursor(cursor)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid list cursor"))?,
+            ),
+            None => None,
+        };
+        let matches = |entry: &FileMetadata| {
+            request
+                .filter
+                .as_ref()
+                .is_none_or(|filter| filter_matches(filter, &entry.filename))
+        };
+
+        if let (Some(last), SortKey::Name) = (&last, request.sort) {
+            let prefix = normalize_path(&request.path)?;
+            let dir_path = self.resolve(&request.path)?;
+            if !dir_path.is_dir() {
+                return Err(Error::new(ErrorKind::NotFound, "Directory not found"));
+            }
+            // One entry past the page tells whether another page follows
+            let mut entries = Vec::new();
+            self.walk_after(
+                &dir_path,
+                &prefix,
+                request.recursive,
+                Some(&last.filename),
+                &mut |entry| {
+                    if matches(&entry) {
+                        entries.push(entry);
+                    }
+                    entries.len() <= limit
+                },
+            )?;
+            let next_cursor = (entries.len() > limit).then(|| {
+                entries.truncate(limit);
+                cursor_after(&entries[limit - 1])
+            });
+            return Ok(Listing {
+                entries,
+                total_entries: 0,
+                logical_size: 0,
+                physical_size: self.stored_bytes.load(Ordering::Relaxed),
+                next_cursor,
+            });
+        }
+
+        let mut listing = self.list(&request.path, request.recursive)?;
+        listing.entries.retain(|entry| matches(entry));
+        // `list` already returns name order
+        if request.sort != SortKey::Name {
+            listing.entries.sort_by(|a, b| request.sort.compare(a, b));
+        }
+        let start = match &last {
+            Some(last) => {
+                listing.total_entries = 0;
+                listing.logical_size = 0;
+                listing.entries.partition_point(|entry| {
+                    request.sort.compare(entry, last) != std::cmp::Ordering::Greater
+                })
+            }
+            None => {
+                listing.total_entries = listing.entries.len() as u64;
+                listing.logical_size = listing.entries.iter().map(|m| m.size).sum();
+                0
+            }
+        };
+        let end = listing.entries.len().min(start + limit);
+        if end < listing.entries.len() {
+            listing.next_cursor = Some(cursor_after(&listing.entries[end - 1]));
+        }
+        listing.entries = listing.entries.drain(start..end).collect();
+        Ok(listing)
+    }
+
+    /// SHA-256 of each `chunk_size` block of a stored file version, in order
+    pub fn chunk_hashes(
+        &self,
+        filename: &str,
+        version: u64,
+        chunk_size: usize,
+    ) -> io::Result<Vec<[u8; 32]>> {
+        check_chunk_size(chunk_size)?;
+        let manifest = Manifest::read(&self.version_path(filename, version)?)?;
+        let mut hashes = Vec::new();
+        self.for_each_block(&manifest, chunk_size, |block| {
+            hashes.push(Sha256::digest(block).into())
+        })?;
+        Ok(hashes)
+    }
+
+    /// rsync-style signatures of every whole `block_size` block of the current
+    /// file, with the file's SHA-256 so a later delta can name its base
+    pub fn block_signatures(
+        &self,
+        filename: &str,
+        block_size: usize,
+    ) -> io::Result<([u8; 32], Vec<BlockSignature>)> {
+        let file_path = self.resolve_file(filename)?;
+        if !file_path.is_file() {
+            return Err(Error::new(ErrorKind::NotFound, "File not found"));
+        }
+        if !(MIN_BLOCK_SIZE as usize..=MAX_BLOCK_SIZE as usize).contains(&block_size) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid block size"));
+        }
+        let manifest = Manifest::read(&file_path)?;
+        let mut signatures = Vec::new();
+        self.for_each_block(&manifest, block_size, |block| {
+            // A short final block can never be matched by a full-size window
+            if block.len() == block_size {
+                signatures.push(BlockSignature::of(block));
+            }
+        })?;
+        Ok((manifest.checksum, signatures))
+    }
+
+    /// Pass each `block_size` block of a file's contents to `f`, in order.
+    /// The blocks need not line up with the stored chunks.
+    fn for_each_block<F>(&self, manifest: &Manifest, block_size: usize, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]),
+    {
+        let mut block = Vec::with_capacity(block_size);
+        for chunk in &manifest.chunks {
+            let data = self.read_chunk(chunk)?;
+            let mut rest = &data[..];
+            while !rest.is_empty() {
+                let take = (block_size - block.len()).min(rest.len());
+                block.extend_from_slice(&rest[..take]);
+                rest = &rest[take..];
+                if block.len() == block_size {
+                    f(&block);
+                    block.clear();
+                }
+            }
+        }
+        if !block.is_empty() {
+            f(&block);
+        }
+        Ok(())
+    }
+
+    pub fn retrieve_chunk(
+        &self,
+        filename: &str,
+        version: u64,
+        chunk_number: u32,
+        chunk_size: usize,
+    ) -> io::Result<Vec<u8>> {
+        check_chunk_size(chunk_size)?;
+        let manifest = Manifest::read(&self.version_path(filename, version)?)?;
         let offset = (chunk_number as u64) * (chunk_size as u64);
-        file.seek(SeekFrom::Start(offset))?;
-        let mut buf = vec![0u8; chunk_size];
-        let n = file.read(&mut buf)?;
-        buf.truncate(n);
-        Ok(buf)
+        self.read_range(&manifest, offset, chunk_size)
+    }
+}
+
+/// Chunk sizes chosen by a client are bounded like stored chunks, so a
+/// request cannot make the server allocate an arbitrarily large buffer
+fn check_chunk_size(chunk_size: usize) -> io::Result<()> {
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid chunk size"));
+    }
+    Ok(())
+}
+
+/// Start of each chunk followed by the total size; empty for an empty layout
+fn chunk_offsets(chunk_sizes: &[u32]) -> Vec<u64> {
+    if chunk_sizes.is_empty() {
+        return Vec::new();
+    }
+    let mut offsets = Vec::with_capacity(chunk_sizes.len() + 1);
+    let mut offset = 0u64;
+    offsets.push(offset);
+    for &size in chunk_sizes {
+        offset += size as u64;
+        offsets.push(offset);
+    }
+    offsets
+}
+
+/// A cursor holds what the sort orders compare: size, time and path
+fn cursor_after(entry: &FileMetadata) -> String {
+    format!(
+        "{}\0{}\0{}",
+        entry.size, entry.last_modified, entry.filename
+    )
+}
+
+fn parse_cursor(cursor: &str) -> Option<FileMetadata> {
+    let mut parts = cursor.splitn(3, '\0');
+    Some(FileMetadata {
+        size: parts.next()?.parse().ok()?,
+        last_modified: parts.next()?.to_string(),
+        filename: parts.next()?.to_string(),
+        checksum: String::new(),
+        entry_type: EntryType::File,
+    })
+}
+
+/// Version ID and superseded time from an archived version's
+/// `<version>-<superseded unix secs>` name
+fn parse_version_name(name: &str) -> Option<(u64, u64)> {
+    let (version, superseded) = name.split_once('-')?;
+    Some((version.parse().ok()?, superseded.parse().ok()?))
+}
+
+/// Index entry for a file from its manifest and the manifest's metadata
+fn index_entry(metadata: &fs::Metadata, manifest: &Manifest) -> IndexEntry {
+    IndexEntry {
+        size: manifest.size,
+        checksum: manifest.checksum,
+        modified: modified_micros(metadata),
+        manifest_len: metadata.len(),
+    }
+}
+
+/// Whether an index entry still describes the manifest on disk
+fn is_current(entry: &IndexEntry, metadata: &fs::Metadata) -> bool {
+    entry.manifest_len == metadata.len() && entry.modified == modified_micros(metadata)
+}
+
+fn modified_micros(metadata: &fs::Metadata) -> u64 {
+    metadata.modified().map(unix_micros).unwrap_or(0)
+}
+
+/// Modification time as shown in listings
+fn format_modified(metadata: &fs::Metadata) -> String {
+    let time = metadata
+        .modified()
+        .unwrap_or(std::time::SystemTime::UNIX_EPOCH);
+    let datetime: chrono::DateTime<chrono::Utc> = time.into();
+    datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// Microseconds since the Unix epoch, or 0 for times before it
+fn unix_micros(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Flush a directory entry change (create, rename) to disk
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Read until the buffer is full or EOF, returning the number of bytes read
+fn read_full(file: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    fn sha(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    #[test]
+    fn test_chunks_out_of_order_are_staged_on_disk() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        let first = vec![1u8; CHUNK_SIZE];
+        let last = vec![2u8; 10];
+        let size = (CHUNK_SIZE + 10) as u64;
+        let whole = [first.clone(), last.clone()].concat();
+
+        let (id, missing) = storage
+            .begin_upload("f.bin", size, 2, sha(&whole), Vec::new())
+            .unwrap();
+        assert_eq!(missing, vec![0..2]);
+        assert!(!storage.store_chunk(&id, 1, 2, last).unwrap());
+        assert!(storage.store_chunk(&id, 0, 2, first).unwrap());
+
+        assert_eq!(
+            storage
+                .complete_chunked_upload(&id, size, &sha(&whole), Overwrite::Replace)
+                .unwrap(),
+            "f.bin"
+        );
+        assert_eq!(fs::read_dir(&storage.staging_dir).unwrap().count(), 0);
+        assert_eq!(storage.retrieve("f.bin").unwrap(), whole);
+    }
+
+    #[test]
+    fn test_incomplete_upload_is_not_committed() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        let size = 2 * CHUNK_SIZE as u64;
+        let (id, _) = storage
+            .begin_upload("g.bin", size, 2, [0u8; 32], Vec::new())
+            .unwrap();
+        storage
+            .store_chunk(&id, 0, 2, vec![0u8; CHUNK_SIZE])
+            .unwrap();
+        assert!(storage
+            .store_chunk(&id, 1, 2, vec![0u8; CHUNK_SIZE + 1])
+            .is_err());
+
+        assert!(storage
+            .complete_chunked_upload(&id, size, &[0u8; 32], Overwrite::Replace)
+            .is_err());
+        assert!(storage.retrieve("g.bin").is_err());
+        assert_eq!(storage.upload_status(&id).unwrap(), vec![1..2]);
+    }
+
+    #[test]
+    fn test_upload_size_and_session_count_limited() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root)
+            .unwrap()
+            .with_max_upload_size(CHUNK_SIZE as u64);
+        let err = storage
+            .begin_upload(
+                "big.bin",
+                u32::MAX as u64 * CHUNK_SIZE as u64,
+                u32::MAX,
+                [0; 32],
+                Vec::new(),
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs::read_dir(&storage.staging_dir).unwrap().count(), 0);
+
+        // Opening one session too many drops the one idle longest
+        let ids: Vec<String> = (0..=MAX_PENDING_UPLOADS)
+            .map(|i| {
+                let name = format!("{}.bin", i);
+                storage
+                    .begin_upload(&name, 1, 1, [0; 32], Vec::new())
+                    .unwrap()
+                    .0
+            })
+            .collect();
+        assert_eq!(
+            storage.pending_chunks.lock().unwrap().len(),
+            MAX_PENDING_UPLOADS
+        );
+        assert!(storage.upload_status(&ids[0]).is_err());
+        assert_eq!(storage.upload_status(&ids[1]).unwrap(), vec![0..1]);
+    }
+
+    #[test]
+    fn test_upload_begin_and_complete_run_concurrently() {
+        let root = TestDir::new();
+        let storage = Arc::new(Storage::new(&root).unwrap());
+        let (done, finished) = std::sync::mpsc::channel();
+
+        let beginner = {
+            let storage = storage.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    storage
+                        .begin_upload(&format!("b{}.bin", i % 8), 1, 1, [0; 32], Vec::new())
+                        .unwrap();
+                }
+                done.send(()).unwrap();
+            })
+        };
+        let completer = std::thread::spawn(move || {
+            for i in 0..200 {
+                let data = vec![i as u8];
+                let (id, _) = storage
+                    .begin_upload("c.bin", 1, 1, sha(&data), Vec::new())
+                    .unwrap();
+                storage.store_chunk(&id, 0, 1, data.clone()).unwrap();
+                storage
+                    .complete_chunked_upload(&id, 1, &sha(&data), Overwrite::Replace)
+                    .unwrap();
+            }
+            done.send(()).unwrap();
+        });
+
+        // A lock order inversion would hang both threads rather than fail
+        for _ in 0..2 {
+            finished
+                .recv_timeout(std::time::Duration::from_secs(60))
+                .expect("upload sessions deadlocked");
+        }
+        beginner.join().unwrap();
+        completer.join().unwrap();
+    }
+
+    #[test]
+    fn test_upload_resumes_after_restart() {
+        let root = TestDir::new();
+        let size = (2 * CHUNK_SIZE + 5) as u64;
+        let checksum = [9u8; 32];
+
+        let id = {
+            let storage = Storage::new(&root).unwrap();
+            let (id, _) = storage
+                .begin_upload("r.bin", size, 3, checksum, Vec::new())
+                .unwrap();
+            storage
+                .store_chunk(&id, 0, 3, vec![1u8; CHUNK_SIZE])
+                .unwrap();
+            storage.store_chunk(&id, 2, 3, vec![3u8; 5]).unwrap();
+            id
+        };
+
+        // Same file after a restart resumes the same session
+        let storage = Storage::new(&root).unwrap();
+        let (resumed, missing) = storage
+            .begin_upload("r.bin", size, 3, checksum, Vec::new())
+            .unwrap();
+        assert_eq!(resumed, id);
+        assert_eq!(missing, vec![1..2]);
+
+        // Different contents start a fresh session
+        let (other, missing) = storage
+            .begin_upload("r.bin", size, 3, [0u8; 32], Vec::new())
+            .unwrap();
+        assert_ne!(other, id);
+        assert_eq!(missing, vec![0..3]);
+
+        storage
+            .store_chunk(&id, 1, 3, vec![2u8; CHUNK_SIZE])
+            .unwrap();
+        let expected = [vec![1u8; CHUNK_SIZE], vec![2u8; CHUNK_SIZE], vec![3u8; 5]].concat();
+        storage
+            .complete_chunked_upload(&id, size, &sha(&expected), Overwrite::Replace)
+            .unwrap();
+        assert_eq!(storage.retrieve("r.bin").unwrap(), expected);
+    }
+
+    #[test]
+    fn test_upload_state_checkpointed_in_batches() {
+        let root = TestDir::new();
+        let total = STATE_SAVE_CHUNKS + 1;
+        let size = total as u64 * CHUNK_SIZE as u64;
+        let storage = Storage::new(&root).unwrap();
+        let (id, _) = storage
+            .begin_upload("batch.bin", size, total, [1u8; 32], Vec::new())
+            .unwrap();
+        for chunk in 0..total {
+            storage
+                .store_chunk(&id, chunk, total, vec![chunk as u8; CHUNK_SIZE])
+                .unwrap();
+        }
+        // A crash skips the final checkpoint; only the batch persisted so far survives
+        std::mem::forget(storage);
+
+        let storage = Storage::new(&root).unwrap();
+        assert_eq!(
+            storage.upload_status(&id).unwrap(),
+            vec![STATE_SAVE_CHUNKS..total]
+        );
+    }
+
+    #[test]
+    fn test_corrupt_reassembly_never_replaces_good_file() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        storage.store("h.bin", b"good copy").unwrap();
+
+        let intended = vec![5u8; 100];
+        let (id, _) = storage
+            .begin_upload("h.bin", 100, 1, sha(&intended), Vec::new())
+            .unwrap();
+        // Chunk arrives intact on the wire but differs from what the client hashed
+        storage.store_chunk(&id, 0, 1, vec![6u8; 100]).unwrap();
+
+        let err = storage
+            .complete_chunked_upload(&id, 100, &sha(&intended), Overwrite::Replace)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(storage.retrieve("h.bin").unwrap(), b"good copy");
+        assert!(storage.upload_status(&id).is_err());
+
+        // Size disagreement is refused as well
+        let (id, _) = storage
+            .begin_upload("h.bin", 100, 1, sha(&intended), Vec::new())
+            .unwrap();
+        storage.store_chunk(&id, 0, 1, intended.clone()).unwrap();
+        assert!(storage
+            .complete_chunked_upload(&id, 99, &sha(&intended), Overwrite::Replace)
+            .is_err());
+        assert_eq!(storage.retrieve("h.bin").unwrap(), b"good copy");
+    }
+
+    #[test]
+    fn test_interrupted_write_keeps_previous_copy() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        storage.store("a.txt", b"old contents").unwrap();
+
+        // Disk fills up half way through the new contents
+        let dest = storage.root_dir.join("a.txt");
+        let err = storage
+            .write_atomic(&dest, |file| {
+                file.write_all(b"new con")?;
+                Err(Error::new(ErrorKind::StorageFull, "disk full"))
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+
+        assert_eq!(storage.retrieve("a.txt").unwrap(), b"old contents");
+        assert_eq!(fs::read_dir(&storage.tmp_dir).unwrap().count(), 0);
+
+        storage.store("a.txt", b"new contents").unwrap();
+        assert_eq!(storage.retrieve("a.txt").unwrap(), b"new contents");
+    }
+
+    #[test]
+    fn test_stale_temp_files_removed_on_startup() {
+        let root = TestDir::new();
+        {
+            let storage = Storage::new(&root).unwrap();
+            storage.store("b.txt", b"committed").unwrap();
+            // A crash before the rename leaves only the temp file behind
+            fs::write(storage.tmp_dir.join("deadbeef.tmp"), b"half writ").unwrap();
+        }
+
+        let storage = Storage::new(&root).unwrap();
+        assert_eq!(fs::read_dir(&storage.tmp_dir).unwrap().count(), 0);
+        assert_eq!(storage.retrieve("b.txt").unwrap(), b"committed");
+        let names: Vec<_> = storage
+            .list("", false)
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|m| m.filename)
+            .collect();
+        assert_eq!(names, vec!["b.txt"]);
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/docs//./a.txt").unwrap(), "docs/a.txt");
+        assert_eq!(normalize_path("").unwrap(), "");
+        for bad in [
+            "../x",
+            "a/../../x",
+            "a/..",
+            "a\\..\\b",
+            ".netbackup/staging/x",
+        ] {
+            assert!(normalize_path(bad).is_err(), "{} should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn test_nested_paths_and_directories() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        storage.store("docs/2024/report.txt", b"q1").unwrap();
+        storage.mkdir("empty").unwrap();
+        assert_eq!(storage.retrieve("/docs/2024/report.txt").unwrap(), b"q1");
+
+        let top = storage.list("", false).unwrap().entries;
+        let names: Vec<_> = top.iter().map(|m| m.filename.as_str()).collect();
+        assert_eq!(names, vec!["docs", "empty"]);
+        assert!(top.iter().all(|m| m.entry_type == EntryType::Directory));
+
+        let all = storage.list("docs", true).unwrap().entries;
+        let names: Vec<_> = all.iter().map(|m| m.filename.as_str()).collect();
+        assert_eq!(names, vec!["docs/2024", "docs/2024/report.txt"]);
+        assert_eq!(all[1].entry_type, EntryType::File);
+
+        // Only empty directories can be removed
+        assert!(storage.rmdir("docs/2024").is_err());
+        storage.delete("docs/2024/report.txt").unwrap();
+        storage.rmdir("docs/2024").unwrap();
+        storage.rmdir("empty").unwrap();
+        assert_eq!(
+            storage.rmdir("empty").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_cannot_escape_root() {
+        let outside = TestDir::new();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret"), b"nope").unwrap();
+
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        std::os::unix::fs::symlink(&outside, storage.root_dir.join("link")).unwrap();
+
+        assert!(storage.retrieve("link/secret").is_err());
+        assert!(storage.store("link/new.txt", b"x").is_err());
+        assert!(!outside.join("new.txt").exists());
+    }
+
+    #[test]
+    fn test_overwrite_and_delete_keep_versions() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        storage.store("v.txt", b"first").unwrap();
+        storage.store("v.txt", b"second").unwrap();
+
+        let versions = storage.list_versions("v.txt").unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].version, 0);
+        assert_eq!(versions[0].size, 6);
+        assert!(versions[1].stored_at <= versions[0].stored_at);
+
+        let old = versions[1].version;
+        assert_eq!(
+            storage.retrieve_chunk("v.txt", old, 0, 1024).unwrap(),
+            b"first"
+        );
+        assert_eq!(
+            storage.retrieve_chunk("v.txt", 0, 0, 1024).unwrap(),
+            b"second"
+        );
+
+        // Deleting keeps the last copy in the history
+        storage.delete("v.txt").unwrap();
+        let versions = storage.list_versions("v.txt").unwrap();
+        assert_eq!(versions.len(), 2);
+        assert!(versions.iter().all(|v| v.version != 0));
+        assert!(storage.list("", false).unwrap().entries.is_empty());
+        assert_eq!(
+            storage.list_versions("never.txt").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_version_retention_limit() {
+        let retention = VersionRetention {
+            max_versions: 2,
+            max_age_days: 0,
+        };
+        let root = TestDir::new();
+        let storage = Storage::with_retention(&root, retention).unwrap();
+        for i in 0..5u8 {
+            storage.store("r.txt", &[i]).unwrap();
+        }
+
+        let versions = storage.list_versions("r.txt").unwrap();
+        assert_eq!(versions.len(), 3); // current + 2 kept
+        let newest_old = storage
+            .retrieve_chunk("r.txt", versions[1].version, 0, 16)
+            .unwrap();
+        assert_eq!(newest_old, vec![3]);
+    }
+
+    fn stored_chunks(storage: &Storage) -> usize {
+        fs::read_dir(&storage.chunks_dir)
+            .unwrap()
+            .map(|dir| fs::read_dir(dir.unwrap().path()).unwrap().count())
+            .sum()
+    }
+
+    #[test]
+    fn test_list_uses_index_and_reconciles() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        storage.store("a.txt", b"alpha").unwrap();
+        storage.store("docs/b.txt", b"bravo").unwrap();
+        storage.store("docs/c.txt", b"charlie!").unwrap();
+        storage.delete("docs/c.txt").unwrap();
+
+        // A manifest garbled in place, same length and time, is not re-read
+        let path = root.join("a.txt");
+        let metadata = fs::metadata(&path).unwrap();
+        let mut garbled = fs::read(&path).unwrap();
+        let last = garbled.len() - 1;
+        garbled[last] ^= 0xff;
+        fs::write(&path, &garbled).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(metadata.modified().unwrap())
+            .unwrap();
+        let listing = storage.list("", true).unwrap();
+        let names: Vec<_> = listing
+            .entries
+            .iter()
+            .map(|e| e.filename.as_str())
+            .collect();
+        assert_eq!(names, ["a.txt", "docs", "docs/b.txt"]);
+        assert_eq!(listing.entries[0].checksum, hex::encode(&sha(b"alpha")));
+        assert_eq!(listing.logical_size, 10);
+        drop(storage);
+
+        // Changes made while the server was down are picked up on startup
+        fs::copy(root.join("docs/b.txt"), &path).unwrap();
+        fs::remove_file(root.join("docs/b.txt")).unwrap();
+        let storage = Storage::new(&root).unwrap();
+        let listing = storage.list("", true).unwrap();
+        let names: Vec<_> = listing
+            .entries
+            .iter()
+            .map(|e| e.filename.as_str())
+            .collect();
+        assert_eq!(names, ["a.txt", "docs"]);
+        assert_eq!(listing.entries[0].checksum, hex::encode(&sha(b"bravo")));
+        assert_eq!(storage.index.paths(), ["a.txt"]);
+    }
+
+    #[test]
+    fn test_list_pages_filter_and_sort() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        for i in 0..7usize {
+            storage
+                .store(&format!("docs/f{}.txt", i), &vec![b'x'; (i * 3) % 7])
+                .unwrap();
+        }
+        storage.store("docs/notes.md", b"notes").unwrap();
+
+        let page = |sort, cursor: Option<String>| ListRequest {
+            path: "docs".to_string(),
+            filter: Some("*.txt".to_string()),
+            sort,
+            limit: 3,
+            cursor,
+            ..ListRequest::default()
+        };
+        for sort in [SortKey::Name, SortKey::Size, SortKey::Modified] {
+            let mut seen = Vec::new();
+            let mut cursor = None;
+            loop {
+                let first = cursor.is_none();
+                let listing = storage.list_page(&page(sort, cursor)).unwrap();
+                assert!(listing.entries.len() <= 3);
+                // Totals come with the first page only
+                assert_eq!(listing.total_entries, if first { 7 } else { 0 });
+                assert_eq!(listing.logical_size, if first { 21 } else { 0 });
+                seen.extend(listing.entries);
+                cursor = listing.next_cursor;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+            let mut expected = seen.clone();
+            expected.sort_by(|a, b| sort.compare(a, b));
+            let names = |entries: &[FileMetadata]| -> Vec<String> {
+                entries.iter().map(|e| e.filename.clone()).collect()
+            };
+            assert_eq!(names(&seen), names(&expected));
+            assert_eq!(seen.len(), 7);
+        }
+        let sizes: Vec<u64> = storage
+            .list_page(&page(SortKey::Size, None))
+            .unwrap()
+            .entries
+            .iter()
+            .map(|e| e.size)
+            .collect();
+        assert_eq!(sizes, [6, 5, 4]);
+
+        // A page cursor stays valid when entries before it go away
+        let first = storage.list_page(&page(SortKey::Name, None)).unwrap();
+        storage.delete("docs/f0.txt").unwrap();
+        let second = storage
+            .list_page(&page(SortKey::Name, first.next_cursor))
+            .unwrap();
+        assert_eq!(second.entries[0].filename, "docs/f3.txt");
+        assert!(storage
+            .list_page(&page(SortKey::Name, Some("bogus".to_string())))
+            .is_err());
+        for filter in ["*".repeat(MAX_LIST_FILTER_WILDCARDS + 1), "a".repeat(300)] {
+            let err = storage
+                .list_page(&ListRequest {
+                    filter: Some(filter),
+                    ..ListRequest::default()
+                })
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_list_pages_in_name_order_through_subdirectories() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        // "docs-x" and "docs.txt" sort between "docs" and "docs/a"
+        for name in [
+            "docs/a",
+            "docs/sub/b",
+            "docs/sub/c",
+            "docs-x",
+            "docs.txt",
+            "e",
+            "docs/z",
+        ] {
+            storage.store(name, name.as_bytes()).unwrap();
+        }
+        let expected: Vec<String> = storage
+            .list("", true)
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|e| e.filename)
+            .collect();
+
+        for limit in [1, 2, 3] {
+            let mut seen = Vec::new();
+            let mut cursor = None;
+            loop {
+                let listing = storage
+                    .list_page(&ListRequest {
+                        recursive: true,
+                        limit,
+                        cursor,
+                        ..ListRequest::default()
+                    })
+                    .unwrap();
+                seen.extend(listing.entries.into_iter().map(|e| e.filename));
+                cursor = listing.next_cursor;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+            assert_eq!(seen, expected);
+        }
+    }
+
+    #[test]
+    fn test_identical_contents_are_stored_once() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        let data: Vec<u8> = (0..2 * CHUNK_SIZE + 5).map(|i| (i % 251) as u8).collect();
+        storage.store("a.img", &data).unwrap();
+        storage.store("copies/b.img", &data).unwrap();
+        assert_eq!(stored_chunks(&storage), 3);
+
+        let listing = storage.list("", true).unwrap();
+        assert_eq!(listing.logical_size, 2 * data.len() as u64);
+        assert_eq!(listing.physical_size, data.len() as u64);
+        assert_eq!(storage.retrieve("copies/b.img").unwrap(), data);
+        let chunk = storage
+            .retrieve_chunk("a.img", 0, 1, CHUNK_SIZE + 1)
+            .unwrap();
+        assert!(chunk == data[CHUNK_SIZE + 1..2 * CHUNK_SIZE + 2]);
+        assert_eq!(
+            storage.chunk_hashes("a.img", 0, 1000).unwrap().len(),
+            data.len().div_ceil(1000)
+        );
+        for chunk_size in [0, MAX_CHUNK_SIZE + 1, u32::MAX as usize] {
+            let err = storage.chunk_hashes("a.img", 0, chunk_size).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            let err = storage
+                .retrieve_chunk("a.img", 0, 0, chunk_size)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_stat_reports_versions_and_chunks() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        let data: Vec<u8> = (0..2 * CHUNK_SIZE + 5).map(|i| (i % 251) as u8).collect();
+        storage.store("docs/a.img", &data[..CHUNK_SIZE]).unwrap();
+        storage.store("docs/a.img", &data).unwrap();
+
+        let stat = storage.stat("docs/a.img").unwrap();
+        assert_eq!(stat.metadata.filename, "docs/a.img");
+        assert_eq!(stat.metadata.entry_type, EntryType::File);
+        assert_eq!(stat.metadata.size, data.len() as u64);
+        assert_eq!((stat.versions, stat.chunks), (1, 3));
+        // The first chunk is also the whole previous version
+        assert_eq!(stat.shared_chunks, 1);
+
+        let dir = storage.stat("docs").unwrap();
+        assert_eq!(dir.metadata.entry_type, EntryType::Directory);
+        assert_eq!(dir.chunks, 0);
+        assert_eq!(
+            storage.stat("docs/missing").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert!(storage.stat("").is_err());
+    }
+
+    #[test]
+    fn test_rename_moves_history_and_copy_shares_chunks() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        storage.store("a.txt", b"one").unwrap();
+        storage.store("a.txt", b"two").unwrap();
+        storage.store("docs/b.txt", b"bravo").unwrap();
+        storage.store("docs/b.txt", b"bravo 2").unwrap();
+
+        storage.rename("a.txt", "moved/a.txt").unwrap();
+        assert_eq!(
+            storage.retrieve("a.txt").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(storage.retrieve("moved/a.txt").unwrap(), b"two");
+        let versions = storage.list_versions("moved/a.txt").unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(
+            storage
+                .retrieve_chunk("moved/a.txt", versions[1].version, 0, 16)
+                .unwrap(),
+            b"one"
+        );
+
+        // Replacing a file keeps it as a version; directories are never replaced
+        storage.store("c.txt", b"charlie").unwrap();
+        storage.rename("c.txt", "moved/a.txt").unwrap();
+        assert_eq!(storage.retrieve("moved/a.txt").unwrap(), b"charlie");
+        assert_eq!(storage.list_versions("moved/a.txt").unwrap().len(), 3);
+        assert_eq!(
+            storage.rename("docs", "moved").unwrap_err().kind(),
+            ErrorKind::AlreadyExists
+        );
+        assert!(storage.rename("docs", "docs/inner").is_err());
+        assert!(storage.rename("missing", "elsewhere").is_err());
+
+        storage.rename("docs", "archive/docs").unwrap();
+        assert_eq!(storage.retrieve("archive/docs/b.txt").unwrap(), b"bravo 2");
+        assert_eq!(
+            storage.list_versions("archive/docs/b.txt").unwrap().len(),
+            2
+        );
+        assert!(storage.list_versions("docs/b.txt").is_err());
+        let names: Vec<String> = storage
+            .list("", true)
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|e| e.filename)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "archive",
+                "archive/docs",
+                "archive/docs/b.txt",
+                "moved",
+                "moved/a.txt"
+            ]
+        );
+
+        // Copies only add references to the chunks already stored
+        let chunks = stored_chunks(&storage);
+        storage.copy("archive", "copy", Overwrite::Replace).unwrap();
+        storage
+            .copy("moved/a.txt", "copy/a.txt", Overwrite::Replace)
+            .unwrap();
+        assert_eq!(stored_chunks(&storage), chunks);
+        storage.delete("archive/docs/b.txt").unwrap();
+        assert_eq!(storage.retrieve("copy/docs/b.txt").unwrap(), b"bravo 2");
+        assert_eq!(storage.retrieve("copy/a.txt").unwrap(), b"charlie");
+        assert_eq!(storage.list_versions("copy/a.txt").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_compressed_chunks_at_rest() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap().with_compression(true);
+        let text = b"log line that repeats\n".repeat(5000);
+        storage.store("app.log", &text).unwrap();
+        let noise: Vec<u8> = (0..320u32).flat_map(|i| sha(&i.to_be_bytes())).collect();
+        storage.store("noise.bin", &noise).unwrap();
+
+        let files: Vec<PathBuf> = fs::read_dir(&storage.chunks_dir)
+            .unwrap()
+            .flat_map(|dir| fs::read_dir(dir.unwrap().path()).unwrap())
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        let compressed = files.iter().filter(|p| p.extension().is_some()).count();
+        assert_eq!(compressed, 2); // text chunks; the noise stays raw
+        assert_eq!(files.len(), 3);
+        let on_disk: u64 = files.iter().map(|p| fs::metadata(p).unwrap().len()).sum();
+        assert!(on_disk < noise.len() as u64 + text.len() as u64 / 10);
+
+        // Reads work whatever the setting, and sizes stay logical
+        drop(storage);
+        let storage = Storage::new(&root).unwrap();
+        assert!(storage.retrieve("app.log").unwrap() == text);
+        assert_eq!(storage.retrieve("noise.bin").unwrap(), noise);
+        assert_eq!(storage.file_size("app.log", 0).unwrap(), text.len() as u64);
+        storage.delete("app.log").unwrap();
+        storage.store("app.log", b"short").unwrap();
+    }
+
+    #[test]
+    fn test_offered_chunks_fill_upload_from_store() {
+        for compress in [false, true] {
+            let root = TestDir::new();
+            offered_chunks_fill_upload(Storage::new(&root).unwrap().with_compression(compress));
+        }
+    }
+
+    fn offered_chunks_fill_upload(storage: Storage) {
+        let mut data: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        storage.store("vm.img", &data).unwrap();
+
+        // Edit one byte in the middle chunk and upload the new contents
+        data[CHUNK_SIZE + 7] ^= 0xff;
+        let size = data.len() as u64;
+        let hashes: Vec<[u8; 32]> = data.chunks(CHUNK_SIZE).map(sha).collect();
+        let (id, _) = storage
+            .begin_upload("vm.img", size, 3, sha(&data), Vec::new())
+            .unwrap();
+        assert_eq!(
+            storage.offer_chunks(&id, 0, &hashes[..1]).unwrap(),
+            vec![1..3]
+        );
+        assert_eq!(
+            storage.offer_chunks(&id, 1, &hashes[1..]).unwrap(),
+            vec![1..2]
+        );
+        assert!(storage.offer_chunks(&id, 2, &hashes[1..]).is_err());
+
+        let edited = data[CHUNK_SIZE..2 * CHUNK_SIZE].to_vec();
+        assert!(storage.store_chunk(&id, 1, 3, edited).unwrap());
+        storage
+            .complete_chunked_upload(&id, size, &sha(&data), Overwrite::Replace)
+            .unwrap();
+        assert!(storage.retrieve("vm.img").unwrap() == data);
+    }
+
+    #[test]
+    fn test_delta_base_reads_are_bounded() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        let base: Vec<u8> = (0..8 * CHUNK_SIZE).map(|i| (i * 7 % 253) as u8).collect();
+        storage.store("disk.img", &base).unwrap();
+        let checksum = sha(&base);
+
+        // Copies running across two base chunks load each of them once
+        let data = base[CHUNK_SIZE / 2..3 * CHUNK_SIZE / 2].to_vec();
+        let copies: Vec<_> = (0..CHUNK_SIZE / 1024)
+            .map(|i| DeltaInstruction::Copy {
+                offset: (CHUNK_SIZE / 2 + i * 1024) as u64,
+                len: 1024,
+            })
+            .collect();
+        let (id, _) = storage
+            .begin_upload("disk.img", CHUNK_SIZE as u64, 1, sha(&data), Vec::new())
+            .unwrap();
+        assert!(storage.store_delta(&id, 0, 1, &checksum, &copies).unwrap());
+
+        // A block from every base chunk would load far more than it rebuilds
+        let scattered: Vec<_> = (0..8)
+            .map(|i| DeltaInstruction::Copy {
+                offset: (i * CHUNK_SIZE) as u64,
+                len: MIN_BLOCK_SIZE,
+            })
+            .chain([DeltaInstruction::Literal(vec![
+                0u8;
+                CHUNK_SIZE
+                    - 8 * MIN_BLOCK_SIZE
+                        as usize
+            ])])
+            .collect();
+        let (id, _) = storage
+            .begin_upload("disk.img", CHUNK_SIZE as u64, 1, [0u8; 32], Vec::new())
+            .unwrap();
+        let err = storage
+            .store_delta(&id, 0, 1, &checksum, &scattered)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_delta_chunks_rebuild_from_current_version() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        let base: Vec<u8> = (0..CHUNK_SIZE + 4000)
+            .map(|i| (i * 7 % 253) as u8)
+            .collect();
+        storage.store("db.bin", &base).unwrap();
+
+        let (checksum, sigs) = storage.block_signatures("db.bin", 1024).unwrap();
+        assert_eq!(checksum, sha(&base));
+        assert_eq!(sigs.len(), base.len() / 1024);
+        assert!(storage.block_signatures("db.bin", 16).is_err());
+
+        let mut data = base.clone();
+        data.splice(100..100, *b"new bytes");
+        let size = data.len() as u64;
+        let (id, _) = storage
+            .begin_upload("db.bin", size, 2, sha(&data), Vec::new())
+            .unwrap();
+        let index = delta::SignatureIndex::new(1024, &sigs);
+        let chunks: Vec<&[u8]> = data.chunks(CHUNK_SIZE).collect();
+
+        let first = index.delta(chunks[0]);
+        assert!(delta::literal_len(&first) < 2048);
+        assert!(storage.store_delta(&id, 0, 2, &[0u8; 32], &first).is_err());
+        assert!(!storage.store_delta(&id, 0, 2, &checksum, &first).unwrap());
+        let second = index.delta(chunks[1]);
+        assert!(storage.store_delta(&id, 1, 2, &checksum, &second).unwrap());
+        storage
+            .complete_chunked_upload(&id, size, &sha(&data), Overwrite::Replace)
+            .unwrap();
+
+        assert!(storage.retrieve("db.bin").unwrap() == data);
+        let versions = storage.list_versions("db.bin").unwrap();
+        let old = storage
+            .retrieve_chunk("db.bin", versions[1].version, 0, base.len())
+            .unwrap();
+        assert!(old == base);
+    }
+
+    #[test]
+    fn test_client_chunk_layout_is_kept() {
+        let root = TestDir::new();
+        let storage = Storage::new(&root).unwrap();
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 253) as u8).collect();
+        let sizes = vec![1000u32, 700, 1300];
+        let (id, _) = storage
+            .begin_upload("cdc.bin", 3000, 3, sha(&data), sizes.clone())
+            .unwrap();
+        // A different layout for the same contents is a different session
+        let (other, _) = storage
+            .begin_upload("cdc.bin", 3000, 2, sha(&data), vec![1500, 1500])
+            .unwrap();
+        assert_ne!(id, other);
+        assert!(storage
+            .begin_upload("cdc.bin", 3000, 3, sha(&data), vec![1000, 1000, 999])
+            .is_err());
+        // Tiny chunks would bloat the store; only the last one may be short
+        assert!(storage
+            .begin_upload("cdc.bin", 3000, 3, sha(&data), vec![2900, 1, 99])
+            .is_err());
+        assert!(storage
+            .begin_upload("small.bin", 3000, 2, sha(&data), vec![2999, 1])
+            .is_ok());
+
+        assert!(storage.store_chunk(&id, 1, 3, vec![0u8; 1000]).is_err());
+        drop(storage);
+
+        // The layout survives a restart
+        let storage = Storage::new(&root).unwrap();
+        storage
+            .store_chunk(&id, 0, 3, data[..1000].to_vec())
+            .unwrap();
+        storage
+            .store_chunk(&id, 1, 3, data[1000..1700].to_vec())
+            .unwrap();
+        storage
+            .store_chunk(&id, 2, 3, data[1700..].to_vec())
+            .unwrap();
+        storage
+            .complete_chunked_upload(&id, 3000, &sha(&data), Overwrite::Replace)
+            .unwrap();
+        assert_eq!(storage.retrieve("cdc.bin").unwrap(), data);
+
+        // Stored along the client's boundaries, so the same chunks can be offered again
+        let hashes = [
+            sha(&data[..1000]),
+            sha(&data[1000..1700]),
+            sha(&data[1700..]),
+        ];
+        let (again, _) = storage
+            .begin_upload("copy.bin", 3000, 3, sha(&data), sizes)
+            .unwrap();
+        assert!(storage.offer_chunks(&again, 0, &hashes).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unreferenced_chunks_are_freed() {
+        let retention = VersionRetention {
+            max_versions: 1,
+            max_age_days: 0,
+        };
+        let root = TestDir::new();
+        let storage = Storage::with_retention(&root, retention).unwrap();
+        storage.store("x", b"first").unwrap();
+        storage.store("y", b"first").unwrap();
+        storage.store("x", b"second").unwrap();
+        storage.store("x", b"third").unwrap();
+
+        // "first" is still used by y; "second" is x's one kept version
+        assert_eq!(stored_chunks(&storage), 3);
+        storage.delete("y").unwrap();
+        assert_eq!(stored_chunks(&storage), 3);
+        storage.store("y", b"other").unwrap();
+        // y's history now holds "first" only through the archived copy
+        assert_eq!(stored_chunks(&storage), 4);
+        storage.store("y", b"final").unwrap();
+        assert_eq!(stored_chunks(&storage), 4);
+        assert!(!storage.chunk_path(&sha(b"first")).exists());
+
+        // A restart counts the same references
+        let root = storage.root_dir.clone();
+        drop(storage);
+        let storage = Storage::with_retention(&root, retention).unwrap();
+        assert_eq!(stored_chunks(&storage), 4);
+        assert_eq!(storage.retrieve("x").unwrap(), b"third");
+    }
+
+    #[test]
+    fn test_plain_files_converted_on_startup() {
+        let root = TestDir::new();
+        fs::create_dir_all(root.join("old")).unwrap();
+        fs::write(root.join("old/plain.txt"), b"written before chunking").unwrap();
+
+        let storage = Storage::new(&root).unwrap();
+        assert_eq!(
+            storage.retrieve("old/plain.txt").unwrap(),
+            b"written before chunking"
+        );
+        assert!(fs::read(root.join("old/plain.txt"))
+            .unwrap()
+            .starts_with(MANIFEST_MAGIC));
+        assert_eq!(stored_chunks(&storage), 1);
+
+        // Chunks left by a crash before their manifest was written are dropped
+        let orphan = storage.chunk_path(&sha(b"orphan"));
+        fs::create_dir_all(orphan.parent().unwrap()).unwrap();
+        fs::write(&orphan, b"orphan").unwrap();
+        drop(storage);
+        let storage = Storage::new(&root).unwrap();
+        assert!(!orphan.exists());
+        assert_eq!(stored_chunks(&storage), 1);
     }
 }
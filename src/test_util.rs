@@ -0,0 +1,48 @@
+use std::path::{Path, PathBuf};
+
+/// Deterministic xorshift bytes, so tests get varied data without a rand dependency
+pub fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+/// Directory for one test under the system temp dir, removed when the test
+/// drops it, even if an assertion failed first
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// A fresh path; the directory itself is created by whoever uses it
+    pub fn new() -> Self {
+        Self(std::env::temp_dir().join(format!(
+            "netbackup-test-{}",
+            crate::hex::encode(&crate::auth::generate_nonce()[..8])
+        )))
+    }
+}
+
+impl std::ops::Deref for TestDir {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &PathBuf {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
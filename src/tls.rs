@@ -0,0 +1,317 @@
+use crate::config::{ClientConfig, ServerConfig};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Byte stream the protocol can run over: plain TCP or TLS
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// SHA-256 fingerprint of a DER certificate, as lowercase hex
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    crate::hex::encode(&Sha256::digest(cert.as_ref()))
+}
+
+/// Accept both "ab:cd:.." and "abcd.." fingerprint spellings
+fn normalize_fingerprint(fp: &str) -> String {
+    fp.chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, Box<dyn Error>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| format!("No private key found in {}", path).into())
+}
+
+/// Generate a self-signed certificate and key at the given paths
+fn generate_self_signed(cert_path: &str, key_path: &str) -> Result<(), Box<dyn Error>> {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+
+    for path in [cert_path, key_path] {
+        if let Some(parent) = Path::new(path).parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+    }
+    fs::write(cert_path, certified.cert.pem())?;
+    fs::write(key_path, certified.key_pair.serialize_pem())?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(key_path, fs::Permissions::from_mode(0o600))?;
+    }
+
+    println!("[TLS] Generated self-signed certificate at: {}", cert_path);
+    Ok(())
+}
+
+/// Build the server-side acceptor from config, bootstrapping a self-signed
+/// certificate on first start when enabled
+pub fn server_acceptor(config: &ServerConfig) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let cert_path = &config.tls_cert_path;
+    let key_path = &config.tls_key_path;
+
+    if config.tls_self_signed && !Path::new(cert_path).exists() && !Path::new(key_path).exists() {
+        generate_self_signed(cert_path, key_path)?;
+    }
+
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    println!("[TLS] Certificate fingerprint: {}", fingerprint(&certs[0]));
+
+    let tls_config = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
+
+/// Client-side TLS settings resolved for one server address
+pub struct ClientTls {
+    pub connector: TlsConnector,
+    pub server_name: ServerName<'static>,
+}
+
+/// Build the client-side connector from config. The server is trusted either
+/// through a CA bundle or a pinned certificate fingerprint.
+pub fn client_tls(config: &ClientConfig, server_addr: &str) -> Result<ClientTls, Box<dyn Error>> {
+    let builder = rustls::ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?;
+
+    let tls_config = if let Some(fp) = &config.tls_fingerprint {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                fingerprint: normalize_fingerprint(fp),
+                provider: provider(),
+            }))
+            .with_no_client_auth()
+    } else if let Some(ca_path) = &config.tls_ca_path {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca_path)? {
+            roots.add(cert)?;
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        return Err("TLS is enabled but neither tls_ca_path nor tls_fingerprint is set".into());
+    };
+
+    let host = match &config.tls_server_name {
+        Some(name) => name.clone(),
+        None => host_part(server_addr).to_string(),
+    };
+    let server_name = ServerName::try_from(host)?;
+
+    Ok(ClientTls {
+        connector: TlsConnector::from(Arc::new(tls_config)),
+        server_name,
+    })
+}
+
+/// Strip the port from "host:port" or "[v6]:port"
+fn host_part(addr: &str) -> &str {
+    if let Some(rest) = addr.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match addr.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => addr,
+    }
+}
+
+/// Trusts exactly one certificate, identified by its SHA-256 fingerprint.
+/// Used for self-signed servers where there is no CA to chain to.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "Server certificate does not match pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Acceptor for a self-signed certificate bootstrapped into a fresh
+    /// directory, with the certificate's path
+    fn bootstrap_server(dir: &Path) -> (TlsAcceptor, String) {
+        let config = ServerConfig {
+            tls_enabled: true,
+            tls_self_signed: true,
+            tls_cert_path: dir.join("cert.pem").to_string_lossy().to_string(),
+            tls_key_path: dir.join("key.pem").to_string_lossy().to_string(),
+            ..ServerConfig::default()
+        };
+        let acceptor = server_acceptor(&config).unwrap();
+        (acceptor, config.tls_cert_path)
+    }
+
+    /// Send "ping" over TLS to a one-shot server that answers "pong"
+    async fn ping(acceptor: TlsAcceptor, client: ClientTls) -> std::io::Result<Vec<u8>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            if let Ok(mut stream) = acceptor.accept(socket).await {
+                let mut buf = [0u8; 4];
+                if stream.read_exact(&mut buf).await.is_ok() && &buf == b"ping" {
+                    let _ = stream.write_all(b"pong").await;
+                    let _ = stream.shutdown().await;
+                }
+            }
+        });
+
+        let tcp = TcpStream::connect(addr).await?;
+        let mut stream = client.connector.connect(client.server_name, tcp).await?;
+        stream.write_all(b"ping").await?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await?;
+        Ok(reply)
+    }
+
+    #[tokio::test]
+    async fn test_self_signed_round_trip() {
+        let dir = TestDir::new();
+        let (acceptor, cert_path) = bootstrap_server(&dir);
+        let cert = &load_certs(&cert_path).unwrap()[0];
+
+        // Pinned by fingerprint, in the colon-separated spelling
+        let pinned: Vec<String> = fingerprint(cert)
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| String::from_utf8_lossy(pair).to_uppercase())
+            .collect();
+        let config = ClientConfig {
+            tls_enabled: true,
+            tls_fingerprint: Some(pinned.join(":")),
+            ..ClientConfig::default()
+        };
+        let client = client_tls(&config, "127.0.0.1:1").unwrap();
+        assert_eq!(ping(acceptor.clone(), client).await.unwrap(), b"pong");
+
+        // Trusted as a CA, under the name the certificate was issued for
+        let config = ClientConfig {
+            tls_enabled: true,
+            tls_ca_path: Some(cert_path.clone()),
+            tls_server_name: Some("localhost".to_string()),
+            ..ClientConfig::default()
+        };
+        let client = client_tls(&config, "127.0.0.1:1").unwrap();
+        assert_eq!(ping(acceptor, client).await.unwrap(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_pinned_fingerprint_mismatch_rejected() {
+        let dir = TestDir::new();
+        let (acceptor, _) = bootstrap_server(&dir);
+        let config = ClientConfig {
+            tls_enabled: true,
+            tls_fingerprint: Some("00".repeat(32)),
+            ..ClientConfig::default()
+        };
+        let client = client_tls(&config, "127.0.0.1:1").unwrap();
+        let err = ping(acceptor, client).await.unwrap_err();
+        assert!(err.to_string().contains("pinned fingerprint"), "{}", err);
+
+        // Neither trust setting is an error, not an unverified connection
+        let config = ClientConfig {
+            tls_enabled: true,
+            ..ClientConfig::default()
+        };
+        assert!(client_tls(&config, "127.0.0.1:1").is_err());
+    }
+
+    #[test]
+    fn test_host_part() {
+        assert_eq!(host_part("backup.lan:8080"), "backup.lan");
+        assert_eq!(host_part("192.168.1.10:8080"), "192.168.1.10");
+        assert_eq!(host_part("[::1]:8080"), "::1");
+        assert_eq!(host_part("[fe80::1]"), "fe80::1");
+        assert_eq!(host_part("backup.lan"), "backup.lan");
+    }
+}
@@ -0,0 +1,449 @@
+use crate::auth::{self, Credentials};
+use crate::hex;
+use crate::protocol::Operation;
+use crate::storage::INTERNAL_DIR;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const MAX_USERNAME_LEN: usize = 32;
+
+/// Where the user database lives inside a storage directory
+pub fn users_file(storage_path: impl AsRef<Path>) -> PathBuf {
+    storage_path.as_ref().join(INTERNAL_DIR).join("users.toml")
+}
+
+/// Root of one user's files inside a storage directory
+pub fn user_root(storage_path: impl AsRef<Path>, username: &str) -> PathBuf {
+    storage_path
+        .as_ref()
+        .join(INTERNAL_DIR)
+        .join("users")
+        .join(username)
+}
+
+/// Usernames double as directory names, so keep them to a safe alphabet
+pub fn validate_username(username: &str) -> Result<(), String> {
+    if username.is_empty() || username.len() > MAX_USERNAME_LEN {
+        return Err(format!(
+            "Username must be 1 to {} characters",
+            MAX_USERNAME_LEN
+        ));
+    }
+    if username.starts_with('.')
+        || !username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        return Err(format!(
+            "Invalid username '{}': use letters, digits, '.', '_' and '-', not starting with '.'",
+            username
+        ));
+    }
+    Ok(())
+}
+
+/// What an account may do within its namespace
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Everything, including overwriting and deleting
+    #[default]
+    Admin,
+    /// Upload new files, copy files to new paths and create directories,
+    /// list what is stored; never read contents back, replace, move or
+    /// delete anything
+    Append,
+    /// List and download only
+    ReadOnly,
+}
+
+impl Role {
+    /// Whether this role may use `operation` at all. Append-only accounts
+    /// are additionally kept from replacing existing files by the server.
+    pub fn permits(&self, operation: Operation) -> bool {
+        match self {
+            Role::Admin => true,
+            Role::Append => matches!(
+                operation,
+                Operation::Store
+                    | Operation::UploadBegin
+                    | Operation::UploadStatus
+                    | Operation::StoreChunk
+                    | Operation::StoreDelta
+                    | Operation::StoreComplete
+                    | Operation::HaveChunks
+                    | Operation::Mkdir
+                    | Operation::Copy
+                    | Operation::List
+                    | Operation::ListVersions
+                    | Operation::Stat
+            ),
+            Role::ReadOnly => matches!(
+                operation,
+                Operation::Retrieve
+                    | Operation::RetrieveChunk
+                    | Operation::ChunkHashes
+                    | Operation::Signatures
+                    | Operation::List
+                    | Operation::ListVersions
+                    | Operation::Stat
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Role::Admin => "admin",
+            Role::Append => "append",
+            Role::ReadOnly => "read-only",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "append" => Ok(Role::Append),
+            "read-only" => Ok(Role::ReadOnly),
+            _ => Err(format!(
+                "Unknown role '{}': expected admin, append or read-only",
+                s
+            )),
+        }
+    }
+}
+
+/// A user as the server sees it at login
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account {
+    pub credentials: Credentials,
+    pub role: Role,
+    /// Whose files the account works on: its own name unless it was given
+    /// access to another user's namespace
+    pub namespace: String,
+}
+
+/// One account as stored on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UserRecord {
+    /// Hex-encoded PBKDF2 salt
+    salt: String,
+    iterations: u32,
+    /// Hex-encoded keys the server checks logins with; see `auth::Credentials`
+    #[serde(default)]
+    stored_key: String,
+    #[serde(default)]
+    server_key: String,
+    /// Hex-encoded salted password, as written by earlier releases. It is
+    /// enough to log in with, so it is converted to the keys above on load.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    key: String,
+    #[serde(default)]
+    role: Role,
+    /// Another user's namespace to work on instead of its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+}
+
+impl UserRecord {
+    fn set_credentials(&mut self, credentials: &Credentials) {
+        self.salt = hex::encode(&credentials.salt);
+        self.iterations = credentials.iterations;
+        self.stored_key = hex::encode(&credentials.stored_key);
+        self.server_key = hex::encode(&credentials.server_key);
+        self.key.clear();
+    }
+
+    /// Replace a legacy salted password with the keys derived from it.
+    /// Returns whether the record changed.
+    fn upgrade(&mut self) -> bool {
+        if self.key.is_empty() {
+            return false;
+        }
+        let salt = hex::decode(&self.salt).and_then(|salt| salt.try_into().ok());
+        let key = hex::decode(&self.key).and_then(|key| key.try_into().ok());
+        match (salt, key) {
+            (Some(salt), Some(key)) => {
+                self.set_credentials(&Credentials::derive(salt, self.iterations, &key));
+                true
+            }
+            // Left as is, and ignored at login as malformed
+            _ => false,
+        }
+    }
+
+    fn account(&self, username: &str) -> Option<Account> {
+        Some(Account {
+            credentials: Credentials {
+                salt: hex::decode(&self.salt)?.try_into().ok()?,
+                iterations: self.iterations,
+                stored_key: hex::decode(&self.stored_key)?.try_into().ok()?,
+                server_key: hex::decode(&self.server_key)?.try_into().ok()?,
+            },
+            role: self.role,
+            namespace: self
+                .namespace
+                .clone()
+                .unwrap_or_else(|| username.to_string()),
+        })
+    }
+}
+
+/// Accounts allowed to log in, managed with `netbackup user`.
+/// Passwords are kept only as keys derived from salted PBKDF2.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UserDatabase {
+    #[serde(default)]
+    users: BTreeMap<String, UserRecord>,
+    /// Records converted from the legacy format while loading
+    #[serde(skip)]
+    upgraded: usize,
+}
+
+impl UserDatabase {
+    /// Load the database, or an empty one if the file does not exist yet.
+    /// Legacy records are converted in memory; `save` writes them out.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        let mut db: Self = toml::from_str(&contents)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        db.upgraded = db
+            .users
+            .values_mut()
+            .map(UserRecord::upgrade)
+            .filter(|&upgraded| upgraded)
+            .count();
+        Ok(db)
+    }
+
+    /// How many records `load` converted from the legacy format
+    pub fn upgraded(&self) -> usize {
+        self.upgraded
+    }
+
+    /// Write the database through a temp file so a crash never leaves it half-written
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents = format!(
+            "# netbackup users. Manage with `netbackup user add/remove/passwd`.\n{}",
+            toml::to_string(self)?
+        );
+
+        let tmp = path.with_extension("toml.tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Add an account. With `namespace`, it works on that user's files
+    /// instead of getting its own, e.g. a read-only login for restores.
+    pub fn add(
+        &mut self,
+        username: &str,
+        password: &str,
+        role: Role,
+        namespace: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        validate_username(username)?;
+        if let Some(namespace) = namespace {
+            validate_username(namespace)?;
+        }
+        if self.users.contains_key(username) {
+            return Err(format!("User '{}' already exists", username).into());
+        }
+        let mut record = UserRecord {
+            salt: String::new(),
+            iterations: 0,
+            stored_key: String::new(),
+            server_key: String::new(),
+            key: String::new(),
+            role,
+            namespace: namespace
+                .filter(|namespace| *namespace != username)
+                .map(str::to_string),
+        };
+        record.set_credentials(&Credentials::new(password, auth::PASSWORD_ITERATIONS));
+        self.users.insert(username.to_string(), record);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, username: &str) -> Result<(), Box<dyn Error>> {
+        self.users
+            .remove(username)
+            .map(|_| ())
+            .ok_or_else(|| format!("No such user: {}", username).into())
+    }
+
+    pub fn set_password(&mut self, username: &str, password: &str) -> Result<(), Box<dyn Error>> {
+        self.record_mut(username)?
+            .set_credentials(&Credentials::new(password, auth::PASSWORD_ITERATIONS));
+        Ok(())
+    }
+
+    pub fn set_role(&mut self, username: &str, role: Role) -> Result<(), Box<dyn Error>> {
+        self.record_mut(username)?.role = role;
+        Ok(())
+    }
+
+    fn record_mut(&mut self, username: &str) -> Result<&mut UserRecord, Box<dyn Error>> {
+        self.users
+            .get_mut(username)
+            .ok_or_else(|| format!("No such user: {}", username).into())
+    }
+
+    /// The account for `username`, or None if there is no such (valid) account
+    pub fn account(&self, username: &str) -> Option<Account> {
+        self.users.get(username)?.account(username).or_else(|| {
+            eprintln!("Ignoring malformed user record for '{}'", username);
+            None
+        })
+    }
+
+    /// Usernames, roles and borrowed namespaces, in name order
+    pub fn users(&self) -> impl Iterator<Item = (&str, Role, Option<&str>)> {
+        self.users
+            .iter()
+            .map(|(name, record)| (name.as_str(), record.role, record.namespace.as_deref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    #[test]
+    fn test_user_database_roundtrip() {
+        let dir = TestDir::new();
+        let path = users_file(&dir);
+
+        let mut db = UserDatabase::load(&path).unwrap();
+        db.add("alice", "correct horse", Role::Admin, None).unwrap();
+        db.add("bob", "battery staple", Role::Append, Some("alice"))
+            .unwrap();
+        assert!(db.add("alice", "again", Role::Admin, None).is_err());
+        assert!(db.add("../evil", "x", Role::Admin, None).is_err());
+        assert!(db.add(".hidden", "x", Role::Admin, None).is_err());
+        assert!(db.add("carol", "x", Role::Admin, Some("..")).is_err());
+        db.save(&path).unwrap();
+
+        let mut db = UserDatabase::load(&path).unwrap();
+        assert_eq!(
+            db.users().collect::<Vec<_>>(),
+            [
+                ("alice", Role::Admin, None),
+                ("bob", Role::Append, Some("alice"))
+            ]
+        );
+        assert_eq!(db.account("alice").unwrap().namespace, "alice");
+        assert_eq!(db.account("bob").unwrap().namespace, "alice");
+        let alice = db.account("alice").unwrap().credentials;
+        let key = auth::password_key("correct horse", &alice.salt, alice.iterations);
+        assert_eq!(
+            auth::Credentials::derive(alice.salt, alice.iterations, &key),
+            alice
+        );
+        // Nothing on disk is enough to log in with
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains(&hex::encode(&key)));
+        assert!(!saved.contains(&hex::encode(&auth::client_key(&key))));
+
+        db.set_password("alice", "new password").unwrap();
+        assert_ne!(db.account("alice").unwrap().credentials, alice);
+        db.set_role("bob", Role::ReadOnly).unwrap();
+        db.set_password("bob", "new").unwrap();
+        let bob = db.account("bob").unwrap();
+        assert_eq!(
+            (bob.role, bob.namespace.as_str()),
+            (Role::ReadOnly, "alice")
+        );
+        db.remove("bob").unwrap();
+        assert!(db.remove("bob").is_err());
+        assert!(db.set_password("bob", "x").is_err());
+        assert!(db.account("bob").is_none());
+    }
+
+    #[test]
+    fn test_legacy_records_upgraded() {
+        let dir = TestDir::new();
+        let path = users_file(&dir);
+        let salt = [7u8; auth::SALT_LEN];
+        let key = auth::password_key("old password", &salt, auth::MIN_ITERATIONS);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            &path,
+            format!(
+                "[users.old]\nsalt = \"{}\"\niterations = {}\nkey = \"{}\"\nrole = \"append\"\n",
+                hex::encode(&salt),
+                auth::MIN_ITERATIONS,
+                hex::encode(&key)
+            ),
+        )
+        .unwrap();
+
+        let db = UserDatabase::load(&path).unwrap();
+        assert_eq!(db.upgraded(), 1);
+        let account = db.account("old").unwrap();
+        assert_eq!(account.role, Role::Append);
+        assert_eq!(
+            account.credentials,
+            auth::Credentials::derive(salt, auth::MIN_ITERATIONS, &key)
+        );
+
+        // Once saved, the salted password is gone from disk
+        db.save(&path).unwrap();
+        assert!(!fs::read_to_string(&path)
+            .unwrap()
+            .contains(&hex::encode(&key)));
+        let db = UserDatabase::load(&path).unwrap();
+        assert_eq!(db.upgraded(), 0);
+        assert_eq!(db.account("old").unwrap(), account);
+    }
+
+    #[test]
+    fn test_roles() {
+        // Records written before roles existed are admins
+        let db: UserDatabase =
+            toml::from_str("[users.old]\nsalt = \"00\"\niterations = 1\nkey = \"00\"\n").unwrap();
+        assert_eq!(db.users().next(), Some(("old", Role::Admin, None)));
+
+        for role in [Role::Admin, Role::Append, Role::ReadOnly] {
+            assert_eq!(role.to_string().parse::<Role>(), Ok(role));
+        }
+        assert!("root".parse::<Role>().is_err());
+
+        assert!(Role::Admin.permits(Operation::Delete));
+        assert!(Role::Append.permits(Operation::UploadBegin));
+        assert!(!Role::Append.permits(Operation::Delete));
+        assert!(!Role::Append.permits(Operation::RetrieveChunk));
+        assert!(Role::ReadOnly.permits(Operation::RetrieveChunk));
+        assert!(!Role::ReadOnly.permits(Operation::StoreChunk));
+        assert!(!Role::ReadOnly.permits(Operation::Rmdir));
+        assert!(Role::Append.permits(Operation::Stat));
+        assert!(Role::Append.permits(Operation::Copy));
+        assert!(!Role::Append.permits(Operation::Rename));
+        assert!(!Role::ReadOnly.permits(Operation::Copy));
+    }
+}